@@ -0,0 +1,490 @@
+//! Admin interface to moderate comments, rendered on the server with plain html forms so that it
+//! works without any JavaScript framework.
+//!
+//! Admins log in with the password set in `admin.password` and get a session cookie. The cookie is
+//! `SameSite=Strict`, which also protects the moderation forms from cross-site requests.
+
+use actix_web::http::header;
+use actix_web::http::{Cookie, StatusCode};
+use actix_web::{Form, FutureResponse, HttpMessage, HttpRequest, HttpResponse, Path, Query};
+use cookie::SameSite;
+use futures::future;
+use futures::prelude::*;
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+
+use risso_api::admin::{self, Admin, AdminComment, CommentFilter, CommentPage};
+use risso_api::context::ApiContext;
+use risso_api::errors::ApiError;
+use risso_api::models::{CommentMode, Thread};
+use risso_api::CommentId;
+
+use crate::client_addr::remote_addr;
+use crate::errors::api_error;
+
+const SESSION_COOKIE: &str = "risso-admin";
+
+const PER_PAGE: i64 = 50;
+
+/// The admin logged in with the session cookie, if any.
+fn session(req: &HttpRequest<ApiContext>) -> Option<Admin> {
+    req.cookie(SESSION_COOKIE)
+        .and_then(|cookie| admin::check_session(req.state(), cookie.value()).ok())
+}
+
+fn check_logged_in(req: &HttpRequest<ApiContext>) -> Result<Admin, actix_web::Error> {
+    session(req).ok_or_else(|| api_error(ApiError::Forbidden(String::from("Please log in")).into()))
+}
+
+fn redirect(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther().header(header::LOCATION, location).finish()
+}
+
+//--------------------------------------------------------------------------------------------------
+// Handlers
+
+/// Query parameters of the comment list. Empty form fields are sent as empty strings.
+#[derive(Deserialize)]
+pub struct ListParams {
+    mode: Option<String>,
+    thread: Option<String>,
+    search: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    page: Option<i64>,
+}
+
+impl ListParams {
+    fn filter(&self) -> Result<CommentFilter, ApiError> {
+        fn non_empty(s: &Option<String>) -> Option<&str> {
+            s.as_ref().map(String::as_str).filter(|s| !s.is_empty())
+        }
+
+        let date = |s: &Option<String>| match non_empty(s) {
+            None => Ok(None),
+            Some(s) => chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .map(Some)
+                .map_err(|_| ApiError::BadRequest(String::from("Dates must be formatted as YYYY-MM-DD"))),
+        };
+
+        let mode = match non_empty(&self.mode) {
+            None => None,
+            Some(s) => Some(
+                s.parse::<i32>()
+                    .map_err(|_| ApiError::BadRequest(String::from("Invalid mode")))?,
+            ),
+        };
+
+        Ok(CommentFilter {
+            mode,
+            uri: non_empty(&self.thread).map(str::to_owned),
+            search: non_empty(&self.search).map(str::to_owned),
+            from: date(&self.from)?,
+            to: date(&self.to)?,
+            page: self.page.unwrap_or(0),
+            per_page: PER_PAGE,
+        })
+    }
+}
+
+/// The comment list, or the login form if the admin isn't logged in.
+pub fn index(http_req: HttpRequest<ApiContext>, params: Query<ListParams>) -> FutureResponse<HttpResponse> {
+    if !admin::is_enabled(http_req.state()) {
+        return Box::new(future::ok(
+            html_page("Risso admin", "<p>The admin interface is disabled.</p>").with_status(StatusCode::NOT_FOUND),
+        ));
+    }
+
+    if session(&http_req).is_none() {
+        return Box::new(future::ok(login_page(None)));
+    }
+
+    let params = params.into_inner();
+    let filter = match params.filter() {
+        Ok(filter) => filter,
+        Err(err) => return Box::new(future::err(api_error(err.into()))),
+    };
+
+    let query = http_req.query_string().to_owned();
+    let state = http_req.state();
+
+    Box::new(
+        admin::threads(state)
+            .join(admin::list(state, filter))
+            .map(move |(threads, page)| list_page(&params, &query, &threads, &page))
+            .map_err(api_error),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    password: String,
+}
+
+pub fn login(http_req: HttpRequest<ApiContext>, form: Form<LoginForm>) -> HttpResponse {
+    match admin::login(http_req.state(), &remote_addr(&http_req), &form.password) {
+        Ok(token) => {
+            let cookie = Cookie::build(SESSION_COOKIE, token)
+                .path("/admin")
+                .http_only(true)
+                .same_site(SameSite::Strict)
+                .finish();
+
+            HttpResponse::SeeOther()
+                .header(header::LOCATION, "/admin")
+                .cookie(cookie)
+                .finish()
+        }
+        Err(ApiError::TooManyRequests { message, retry_after }) => {
+            let mut resp = login_page(Some(&message)).with_status(StatusCode::TOO_MANY_REQUESTS);
+            resp.headers_mut().insert(header::RETRY_AFTER, retry_after.into());
+            resp
+        }
+        Err(err) => login_page(Some(&err.to_string())).with_status(StatusCode::FORBIDDEN),
+    }
+}
+
+pub fn logout() -> HttpResponse {
+    let cookie = Cookie::build(SESSION_COOKIE, "").path("/admin").finish();
+
+    HttpResponse::SeeOther()
+        .header(header::LOCATION, "/admin")
+        .del_cookie(&cookie)
+        .finish()
+}
+
+/// Activate or delete comments. The form's `action` is either `activate` or `delete` to act on
+/// the comments whose `id-<id>` checkbox is checked, or `activate:<id>` or `delete:<id>` to act on
+/// a single comment.
+pub fn moderate(
+    http_req: HttpRequest<ApiContext>,
+    form: Form<HashMap<String, String>>,
+) -> FutureResponse<HttpResponse> {
+    let logged_in = match check_logged_in(&http_req) {
+        Ok(admin) => admin,
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    let form = form.into_inner();
+    let action = form.get("action").map_or("", String::as_str);
+
+    let mut parts = action.splitn(2, ':');
+    let action = parts.next().unwrap_or("");
+    let ids: Vec<CommentId> = match parts.next() {
+        Some(id) => id.parse().into_iter().collect(),
+        None => form
+            .keys()
+            .filter(|key| key.starts_with("id-"))
+            .filter_map(|key| key["id-".len()..].parse().ok())
+            .collect(),
+    };
+
+    let location = match form.get("back") {
+        Some(query) if !query.is_empty() => format!("/admin?{}", query),
+        _ => String::from("/admin"),
+    };
+
+    let state = http_req.state();
+    let result = match action {
+        "activate" => admin::activate(state, &logged_in, ids),
+        "delete" => admin::delete(state, &logged_in, ids),
+        _ => {
+            let err = ApiError::BadRequest(format!("Unknown action '{}'", action));
+            return Box::new(future::err(api_error(err.into())));
+        }
+    };
+
+    Box::new(result.map(move |_| redirect(&location)).map_err(api_error))
+}
+
+pub fn edit_form(http_req: HttpRequest<ApiContext>, id: Path<CommentId>) -> FutureResponse<HttpResponse> {
+    if let Err(err) = check_logged_in(&http_req) {
+        return Box::new(future::err(err));
+    }
+
+    Box::new(
+        admin::get(http_req.state(), id.into_inner())
+            .map(|comment| edit_page(&comment))
+            .map_err(api_error),
+    )
+}
+
+pub fn edit(
+    http_req: HttpRequest<ApiContext>,
+    id: Path<CommentId>,
+    form: Form<risso_api::EditComment>,
+) -> FutureResponse<HttpResponse> {
+    let logged_in = match check_logged_in(&http_req) {
+        Ok(admin) => admin,
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    Box::new(
+        admin::edit(http_req.state(), &logged_in, id.into_inner(), form.into_inner())
+            .map(|_| redirect("/admin"))
+            .map_err(api_error),
+    )
+}
+
+//--------------------------------------------------------------------------------------------------
+// Html rendering
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; } \
+                     table { border-collapse: collapse; width: 100%; } \
+                     td, th { border-bottom: 1px solid #ddd; padding: 0.5em; text-align: left; vertical-align: top; } \
+                     pre { white-space: pre-wrap; margin: 0; } \
+                     .mode-2 { background: #fff8e1; } .mode-4 { color: #999; }";
+
+trait WithStatus {
+    fn with_status(self, status: StatusCode) -> Self;
+}
+
+impl WithStatus for HttpResponse {
+    fn with_status(mut self, status: StatusCode) -> Self {
+        *self.status_mut() = status;
+        self
+    }
+}
+
+fn html_page(title: &str, body: &str) -> HttpResponse {
+    let page = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>{title}</title><style>{style}</style></head>
+<body>
+{body}
+</body>
+</html>"#,
+        title = escape(title),
+        style = STYLE,
+        body = body
+    );
+
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(page)
+}
+
+fn login_page(error: Option<&str>) -> HttpResponse {
+    let error = error.map_or(String::new(), |e| format!("<p><strong>{}</strong></p>", escape(e)));
+
+    html_page(
+        "Risso admin",
+        &format!(
+            r#"<h1>Risso admin</h1>
+{error}
+<form method="post" action="/admin/login">
+<input type="password" name="password" placeholder="Password" autofocus>
+<button>Log in</button>
+</form>"#,
+            error = error
+        ),
+    )
+}
+
+fn mode_name(mode: i32) -> &'static str {
+    match mode {
+        m if m == CommentMode::Valid as i32 => "valid",
+        m if m == CommentMode::Pending as i32 => "pending",
+        m if m == CommentMode::SoftDeleted as i32 => "deleted",
+        _ => "unknown",
+    }
+}
+
+fn option(value: &str, label: &str, selected: bool) -> String {
+    format!(
+        r#"<option value="{}"{}>{}</option>"#,
+        escape(value),
+        if selected { " selected" } else { "" },
+        escape(label)
+    )
+}
+
+fn list_page(params: &ListParams, query: &str, threads: &[Thread], page: &CommentPage) -> HttpResponse {
+    let param = |p: &Option<String>| p.as_ref().map_or("", String::as_str);
+
+    let mut modes = option("", "All", param(&params.mode).is_empty());
+    for mode in &[
+        CommentMode::Valid as i32,
+        CommentMode::Pending as i32,
+        CommentMode::SoftDeleted as i32,
+    ] {
+        let value = mode.to_string();
+        modes.push_str(&option(&value, mode_name(*mode), param(&params.mode) == value));
+    }
+
+    let mut thread_options = option("", "All", param(&params.thread).is_empty());
+    for thread in threads {
+        thread_options.push_str(&option(&thread.uri, &thread.uri, param(&params.thread) == thread.uri));
+    }
+
+    let current_page = params.page.unwrap_or(0);
+    let mut pagination = String::new();
+    if current_page > 0 {
+        pagination.push_str(&format!(
+            r#"<button name="page" value="{}">Previous</button>"#,
+            current_page - 1
+        ));
+    }
+    if (current_page + 1) * PER_PAGE < page.total {
+        pagination.push_str(&format!(
+            r#"<button name="page" value="{}">Next</button>"#,
+            current_page + 1
+        ));
+    }
+
+    let rows: String = page.comments.iter().map(comment_row).collect();
+
+    html_page(
+        "Risso admin",
+        &format!(
+            r#"<form method="post" action="/admin/logout" style="float: right"><button>Log out</button></form>
+<h1>Comments</h1>
+<form method="get" action="/admin">
+Mode <select name="mode">{modes}</select>
+Thread <select name="thread">{threads}</select>
+Search <input name="search" value="{search}">
+From <input type="date" name="from" value="{from}">
+To <input type="date" name="to" value="{to}">
+<button>Filter</button>
+{total} comments {pagination}
+</form>
+<form method="post" action="/admin/moderate">
+<input type="hidden" name="back" value="{back}">
+<p>
+<button name="action" value="activate">Activate selected</button>
+<button name="action" value="delete" onclick="return confirm('Delete selected comments?')">Delete selected</button>
+</p>
+<table>
+<tr><th></th><th>Id</th><th>Thread</th><th>Author</th><th>Date</th><th>Mode</th><th>Comment</th><th></th></tr>
+{rows}
+</table>
+</form>"#,
+            modes = modes,
+            threads = thread_options,
+            search = escape(param(&params.search)),
+            from = escape(param(&params.from)),
+            to = escape(param(&params.to)),
+            total = page.total,
+            pagination = pagination,
+            back = escape(query),
+            rows = rows
+        ),
+    )
+}
+
+fn comment_row(comment: &AdminComment) -> String {
+    let mut author = escape(comment.author.as_ref().map_or("Anonymous", String::as_str));
+    if let Some(ref email) = comment.email {
+        author.push_str(&format!("<br>{}", escape(email)));
+    }
+    if let Some(ref website) = comment.website {
+        author.push_str(&format!("<br>{}", escape(website)));
+    }
+    author.push_str(&format!("<br>{}", escape(&comment.remote_addr)));
+
+    let activate = if comment.mode == CommentMode::Pending as i32 {
+        format!(
+            r#"<button name="action" value="activate:{}">Activate</button>"#,
+            comment.id
+        )
+    } else {
+        String::new()
+    };
+
+    format!(
+        r#"<tr class="mode-{mode}">
+<td><input type="checkbox" name="id-{id}"></td>
+<td>{id}</td>
+<td>{uri}</td>
+<td>{author}</td>
+<td>{date}</td>
+<td>{mode_name}</td>
+<td><pre>{text}</pre></td>
+<td>{activate}
+<a href="/admin/edit/{id}">Edit</a>
+<button name="action" value="delete:{id}" onclick="return confirm('Delete comment {id}?')">Delete</button></td>
+</tr>
+"#,
+        mode = comment.mode,
+        id = comment.id,
+        uri = escape(&comment.uri),
+        author = author,
+        date = comment.created.format("%Y-%m-%d %H:%M"),
+        mode_name = mode_name(comment.mode),
+        text = escape(&comment.text),
+        activate = activate
+    )
+}
+
+fn edit_page(comment: &AdminComment) -> HttpResponse {
+    let field = |value: &Option<String>| escape(value.as_ref().map_or("", String::as_str));
+
+    html_page(
+        &format!("Edit comment {}", comment.id),
+        &format!(
+            r#"<h1>Edit comment {id}</h1>
+<p>On {uri}, {date}</p>
+<form method="post" action="/admin/edit/{id}">
+<p><input name="author" value="{author}" placeholder="Author"></p>
+<p><input name="website" value="{website}" placeholder="Website"></p>
+<p><textarea name="text" rows="12" cols="80">{text}</textarea></p>
+<button>Save</button> <a href="/admin">Cancel</a>
+</form>"#,
+            id = comment.id,
+            uri = escape(&comment.uri),
+            date = comment.created.format("%Y-%m-%d %H:%M"),
+            author = field(&comment.author),
+            website = field(&comment.website),
+            text = escape(&comment.text)
+        ),
+    )
+}
+
+/// Escape text to be used in html content or attribute values.
+fn escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '&' => result.push_str("&amp;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_params_filter() {
+        let params = ListParams {
+            mode: Some(String::from("2")),
+            thread: Some(String::new()),
+            search: None,
+            from: Some(String::from("2018-12-01")),
+            to: None,
+            page: Some(3),
+        };
+
+        let filter = params.filter().unwrap();
+        assert_eq!(Some(2), filter.mode);
+        assert_eq!(None, filter.uri);
+        assert_eq!(Some(chrono::NaiveDate::from_ymd(2018, 12, 1)), filter.from);
+        assert_eq!(3, filter.page);
+
+        let params = ListParams {
+            from: Some(String::from("yesterday")),
+            ..params
+        };
+        assert!(params.filter().is_err());
+    }
+
+    #[test]
+    fn escape_html() {
+        assert_eq!("&lt;a href=&quot;x&quot;&gt;&amp;&#39;", escape(r#"<a href="x">&'"#));
+    }
+}
@@ -0,0 +1,101 @@
+//! JSON admin API, to script moderation tasks. Endpoints are under `/api/admin/v1`, and requests
+//! are authenticated with a bearer token listed in `admin.api_tokens`.
+//!
+//! - `GET /comments`: list comments, filtered by `mode`, `uri`, `search`, `from` and `to` dates,
+//!   with `page` and `per_page`
+//! - `GET /comments/{id}`: get a comment
+//! - `PATCH /comments/{id}`: change a comment's `mode` or `text`
+//! - `POST /comments/delete`: delete the comments in `ids`
+//! - `GET /threads`: list threads
+//! - `PATCH /threads/{id}`: change a thread's `uri` or `title`
+//!
+//! Changes are recorded in the audit log.
+
+use actix_web::http::header;
+use actix_web::{FutureResponse, HttpRequest, HttpResponse, Json, Path, Query};
+use futures::future;
+use futures::prelude::*;
+use serde_derive::{Deserialize, Serialize};
+
+use risso_api::admin::{self, Admin, CommentFilter, PatchComment, PatchThread};
+use risso_api::context::ApiContext;
+use risso_api::{CommentId, ThreadId};
+
+use crate::errors::api_error;
+
+/// Check the request's `Authorization: Bearer <token>` header.
+fn authenticate(req: &HttpRequest<ApiContext>) -> Result<Admin, actix_web::Error> {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| value.starts_with("Bearer "))
+        .map_or("", |value| value["Bearer ".len()..].trim());
+
+    admin::check_api_token(req.state(), token).map_err(|err| api_error(err.into()))
+}
+
+/// Authenticate the request, and call `f` with the admin to get the response's content.
+fn respond<T, F, R>(req: &HttpRequest<ApiContext>, f: F) -> FutureResponse<HttpResponse>
+where
+    T: serde::Serialize + 'static,
+    F: FnOnce(&ApiContext, &Admin) -> R,
+    R: Future<Item = T, Error = failure::Error> + 'static,
+{
+    match authenticate(req) {
+        Ok(admin) => Box::new(
+            f(req.state(), &admin)
+                .map(|result| HttpResponse::Ok().json(result))
+                .map_err(api_error),
+        ),
+        Err(err) => Box::new(future::err(err)),
+    }
+}
+
+pub fn list_comments(req: HttpRequest<ApiContext>, filter: Query<CommentFilter>) -> FutureResponse<HttpResponse> {
+    respond(&req, |ctx, _| admin::list(ctx, filter.into_inner()))
+}
+
+pub fn get_comment(req: HttpRequest<ApiContext>, id: Path<CommentId>) -> FutureResponse<HttpResponse> {
+    respond(&req, |ctx, _| admin::get(ctx, id.into_inner()))
+}
+
+pub fn patch_comment(
+    req: HttpRequest<ApiContext>,
+    id: Path<CommentId>,
+    body: Json<PatchComment>,
+) -> FutureResponse<HttpResponse> {
+    respond(&req, |ctx, caller| {
+        admin::patch(ctx, caller, id.into_inner(), body.into_inner())
+    })
+}
+
+#[derive(Deserialize)]
+pub struct DeleteRequest {
+    ids: Vec<CommentId>,
+}
+
+#[derive(Serialize)]
+pub struct DeleteResponse {
+    deleted: usize,
+}
+
+pub fn delete_comments(req: HttpRequest<ApiContext>, body: Json<DeleteRequest>) -> FutureResponse<HttpResponse> {
+    respond(&req, |ctx, caller| {
+        admin::delete(ctx, caller, body.into_inner().ids).map(|deleted| DeleteResponse { deleted })
+    })
+}
+
+pub fn list_threads(req: HttpRequest<ApiContext>) -> FutureResponse<HttpResponse> {
+    respond(&req, |ctx, _| admin::threads(ctx))
+}
+
+pub fn patch_thread(
+    req: HttpRequest<ApiContext>,
+    id: Path<ThreadId>,
+    body: Json<PatchThread>,
+) -> FutureResponse<HttpResponse> {
+    respond(&req, |ctx, caller| {
+        admin::patch_thread(ctx, caller, id.into_inner(), body.into_inner())
+    })
+}
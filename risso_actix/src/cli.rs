@@ -0,0 +1,260 @@
+//! Command line interface of the server.
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter};
+
+use risso_api::config::{Config, RissoConfig};
+use risso_api::context::ApiBuilder;
+use risso_api::logs::LogFormat;
+
+use crate::ActixConfig;
+
+pub fn app() -> App<'static, 'static> {
+    App::new("risso")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("A comment server for static websites, compatible with Isso")
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .short("c")
+                .value_name("FILE")
+                .global(true)
+                .help("Configuration file, merged with the built-in defaults"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .global(true)
+                .possible_values(&["trace", "debug", "info", "warn", "error"])
+                .help("Minimum level of log records [default: info]"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .global(true)
+                .possible_values(&["json", "text"])
+                .help("Format of log records [default: json]"),
+        )
+        .subcommand(SubCommand::with_name("serve").about("Start the server. This is the default command"))
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Apply pending database migrations")
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Only list the migrations and whether they are applied"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check-config").about("Check the configuration and print it, with secrets hidden"),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Import an export into an empty database")
+                .arg(
+                    Arg::with_name("FILE")
+                        .required(true)
+                        .help("Export file, or '-' for the standard input"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import-isso")
+                .about("Import the comments of an Isso database")
+                .arg(
+                    Arg::with_name("PATH")
+                        .required(true)
+                        .help("Path of Isso's SQLite database"),
+                )
+                .arg(
+                    Arg::with_name("overwrite-preferences")
+                        .long("overwrite-preferences")
+                        .help("Replace existing preferences, such as the session key, with Isso's"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export threads, comments and preferences as newline-delimited JSON")
+                .arg(
+                    Arg::with_name("FILE")
+                        .required(true)
+                        .help("Export file, or '-' for the standard output"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rerender")
+                .about("Render the html of all comments again, and the hash of their author"),
+        )
+}
+
+/// Value of a global argument, that can be given before or after the subcommand.
+pub fn global_value<'a>(matches: &'a ArgMatches, sub_matches: Option<&'a ArgMatches>, name: &str) -> Option<&'a str> {
+    sub_matches
+        .and_then(|m| m.value_of(name))
+        .or_else(|| matches.value_of(name))
+}
+
+pub fn log_level(name: Option<&str>) -> slog::Level {
+    match name {
+        Some("trace") => slog::Level::Trace,
+        Some("debug") => slog::Level::Debug,
+        Some("warn") => slog::Level::Warning,
+        Some("error") => slog::Level::Error,
+        _ => slog::Level::Info,
+    }
+}
+
+pub fn log_format(name: Option<&str>) -> LogFormat {
+    match name {
+        Some("text") => LogFormat::Text,
+        _ => LogFormat::Json,
+    }
+}
+
+/// Check all sections of the configuration, and print it.
+pub fn check_config(merged_config: &Config) -> Result<(), failure::Error> {
+    RissoConfig::from_config(merged_config)?;
+    ActixConfig::from_config(merged_config)?;
+
+    let redacted = risso_api::config::redacted(merged_config)?;
+    println!("{}", serde_json::to_string_pretty(&redacted)?);
+
+    Ok(())
+}
+
+/// Apply pending database migrations, or only list them with `dry_run`, and print a report.
+pub fn migrate(config: &RissoConfig, dry_run: bool) -> Result<(), failure::Error> {
+    let cnx = ApiBuilder::connect(config)?;
+
+    let applied = if dry_run {
+        Vec::new()
+    } else {
+        risso_api::migrations::run_pending(&cnx)?
+    };
+
+    for migration in risso_api::migrations::status(&cnx)? {
+        let state = if applied.iter().any(|m| m.version == migration.version) {
+            "applied now"
+        } else if migration.applied {
+            "applied"
+        } else {
+            "pending"
+        };
+
+        println!("{}_{}: {}", migration.version, migration.name, state);
+    }
+
+    Ok(())
+}
+
+/// Export all data to a file, see `risso_api::backup`.
+pub fn export(config: &RissoConfig, path: &str) -> Result<(), failure::Error> {
+    let cnx = ApiBuilder::connect(config)?;
+
+    let summary = if path == "-" {
+        let stdout = io::stdout();
+        risso_api::backup::export(&cnx, stdout.lock())?
+    } else {
+        let file = File::create(path).map_err(|err| failure::err_msg(format!("Cannot create {}: {}", path, err)))?;
+        risso_api::backup::export(&cnx, BufWriter::new(file))?
+    };
+
+    // Not on the standard output, that may be the export itself
+    eprint!("Exported {}", summary);
+
+    Ok(())
+}
+
+/// Import an export into an empty database, see `risso_api::backup`.
+pub fn import(config: &RissoConfig, path: &str) -> Result<(), failure::Error> {
+    let cnx = ApiBuilder::connect(config)?;
+
+    if config.database.run_migrations {
+        risso_api::migrations::run_pending(&cnx)?;
+    }
+
+    let summary = if path == "-" {
+        let stdin = io::stdin();
+        risso_api::backup::import(stdin.lock(), &cnx)?
+    } else {
+        let file = File::open(path).map_err(|err| failure::err_msg(format!("Cannot open {}: {}", path, err)))?;
+        risso_api::backup::import(BufReader::new(file), &cnx)?
+    };
+
+    print!("Imported {}", summary);
+
+    Ok(())
+}
+
+/// Render all comments again with the current markup configuration, see `risso_api::markup`.
+/// The server otherwise does it when it starts after a configuration change or an import.
+pub fn rerender(config: &RissoConfig) -> Result<(), failure::Error> {
+    let cnx = ApiBuilder::connect(config)?;
+
+    if config.database.run_migrations {
+        risso_api::migrations::run_pending(&cnx)?;
+    }
+
+    let count = risso_api::markup::rerender_all(&config.markup, &cnx)?;
+    println!("Rendered {} comments.", count);
+
+    Ok(())
+}
+
+/// Import an Isso database, and print a report of what was imported.
+#[cfg(feature = "isso")]
+pub fn import_isso(config: &RissoConfig, path: &str, overwrite_preferences: bool) -> Result<(), failure::Error> {
+    let cnx = ApiBuilder::connect(config)?;
+
+    if config.database.run_migrations {
+        risso_api::migrations::run_pending(&cnx)?;
+    }
+
+    let report = risso_api::import::isso::import(path, &cnx, overwrite_preferences)?;
+    print!("{}", report);
+
+    if !report.conflicting_preferences.is_empty() {
+        println!("Use --overwrite-preferences to replace the preferences that were kept.");
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "isso"))]
+pub fn import_isso(_config: &RissoConfig, _path: &str, _overwrite_preferences: bool) -> Result<(), failure::Error> {
+    Err(failure::err_msg("Risso was built without the 'isso' feature"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn global_arguments() {
+        let matches = app().get_matches_from(vec![
+            "risso",
+            "--log-level",
+            "debug",
+            "migrate",
+            "--config",
+            "risso.toml",
+        ]);
+        let (name, sub_matches) = matches.subcommand();
+
+        assert_eq!("migrate", name);
+        assert_eq!(Some("risso.toml"), global_value(&matches, sub_matches, "config"));
+        assert_eq!(
+            slog::Level::Debug,
+            log_level(global_value(&matches, sub_matches, "log-level"))
+        );
+        assert_eq!(
+            LogFormat::Json,
+            log_format(global_value(&matches, sub_matches, "log-format"))
+        );
+    }
+}
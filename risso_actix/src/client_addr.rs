@@ -0,0 +1,161 @@
+//! Address of the client that sent a request, used to rate limit comments, votes and admin logins.
+//!
+//! It is the address of the peer, unless the peer is one of the proxies listed in
+//! `actix.trusted_proxies`. The `Forwarded` and `X-Forwarded-For` headers are then read from the
+//! right, skipping trusted proxies. They are otherwise ignored, as any client can set them.
+
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::middleware::{Middleware, Started};
+use actix_web::HttpRequest;
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// The client address found by `ClientAddrMiddleware`, stored in the request extensions.
+struct ClientAddr(String);
+
+/// A middleware that finds the client address of requests. See `remote_addr`.
+pub struct ClientAddrMiddleware {
+    trusted_proxies: Arc<Vec<IpAddr>>,
+}
+
+impl ClientAddrMiddleware {
+    pub fn new(trusted_proxies: Arc<Vec<IpAddr>>) -> Self {
+        ClientAddrMiddleware { trusted_proxies }
+    }
+}
+
+impl<S> Middleware<S> for ClientAddrMiddleware {
+    fn start(&self, req: &HttpRequest<S>) -> actix_web::Result<Started> {
+        let peer = req.peer_addr().map(|addr| addr.ip());
+        let addr = client_addr(peer, &forwarded_hops(req.headers()), &self.trusted_proxies);
+        req.extensions_mut().insert(ClientAddr(addr));
+
+        Ok(Started::Done)
+    }
+}
+
+/// The client's address, without the port number.
+pub fn remote_addr<S>(req: &HttpRequest<S>) -> String {
+    if let Some(addr) = req.extensions().get::<ClientAddr>() {
+        return addr.0.clone();
+    }
+
+    req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default()
+}
+
+fn client_addr(peer: Option<IpAddr>, hops: &[String], trusted_proxies: &[IpAddr]) -> String {
+    let peer = match peer {
+        Some(peer) => peer,
+        None => return String::new(),
+    };
+
+    let mut addr = peer.to_string();
+
+    if trusted_proxies.contains(&peer) {
+        for hop in hops.iter().rev() {
+            addr = hop.clone();
+            match hop.parse::<IpAddr>() {
+                Ok(ip) if trusted_proxies.contains(&ip) => {}
+                _ => break,
+            }
+        }
+    }
+
+    addr
+}
+
+/// The addresses the request was forwarded for, from the client to the last proxy, as given by
+/// the `Forwarded` header or, if there is none, by `X-Forwarded-For`.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<String> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>()
+    };
+
+    let forwarded = values(header::FORWARDED)
+        .into_iter()
+        .filter_map(|element| {
+            element
+                .split(';')
+                .map(str::trim)
+                .find(|pair| pair.get(..4).map_or(false, |name| name.eq_ignore_ascii_case("for=")))
+                .map(|pair| without_port(&pair[4..]).to_owned())
+        })
+        .collect::<Vec<_>>();
+
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+
+    values(header::HeaderName::from_static("x-forwarded-for"))
+        .into_iter()
+        .map(|addr| without_port(addr).to_owned())
+        .collect()
+}
+
+/// Remove the quotes, brackets and port of a forwarded address, e.g. `"[2001:db8::1]:4711"`.
+fn without_port(addr: &str) -> &str {
+    let addr = addr.trim_matches('"');
+
+    if addr.starts_with('[') {
+        addr[1..].split(']').next().unwrap_or_default()
+    } else if addr.matches(':').count() == 1 {
+        addr.split(':').next().unwrap_or_default()
+    } else {
+        addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::HeaderValue;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    fn hops(headers: &[(&'static str, &'static str)]) -> Vec<String> {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, HeaderValue::from_static(*value));
+        }
+        forwarded_hops(&map)
+    }
+
+    #[test]
+    fn forwarded_headers() {
+        assert_eq!(
+            vec!["10.0.0.1", "2001:db8::1", "10.0.0.2"],
+            hops(&[
+                ("forwarded", r#"for=10.0.0.1:4711, For="[2001:db8::1]:4711""#),
+                ("forwarded", "proto=https;for=10.0.0.2"),
+                ("x-forwarded-for", "10.0.0.3"),
+            ])
+        );
+        assert_eq!(vec!["10.0.0.3", "::1"], hops(&[("x-forwarded-for", "10.0.0.3, ::1")]));
+        assert!(hops(&[]).is_empty());
+    }
+
+    #[test]
+    fn trusted_proxies() {
+        let proxies = vec![ip("127.0.0.1"), ip("10.0.0.254")];
+        let forwarded = vec!["1.2.3.4".to_owned(), "5.6.7.8".to_owned(), "10.0.0.254".to_owned()];
+
+        // Headers of other peers are ignored
+        assert_eq!("9.9.9.9", client_addr(Some(ip("9.9.9.9")), &forwarded, &proxies));
+
+        // The first address that isn't a trusted proxy, from the right: the client can prepend anything
+        assert_eq!("5.6.7.8", client_addr(Some(ip("127.0.0.1")), &forwarded, &proxies));
+
+        // Requests sent directly by a proxy
+        assert_eq!("127.0.0.1", client_addr(Some(ip("127.0.0.1")), &[], &proxies));
+        assert_eq!("", client_addr(None, &forwarded, &proxies));
+    }
+}
@@ -0,0 +1,32 @@
+//! Conversion of `risso_api` errors to http responses.
+
+use actix_web::error::InternalError;
+use actix_web::http::{header, StatusCode};
+use actix_web::HttpResponse;
+
+use risso_api::errors::ApiError;
+
+/// Convert an error returned by an API function to an actix-web error with the appropriate status
+/// code. Errors that aren't an `ApiError` are internal errors.
+///
+/// Rate limit errors have a `Retry-After` header with the number of seconds to wait.
+///
+pub fn api_error(err: failure::Error) -> actix_web::Error {
+    let status = match err.downcast_ref::<ApiError>() {
+        Some(ApiError::Validation(_)) | Some(ApiError::BadRequest(_)) => StatusCode::BAD_REQUEST,
+        Some(ApiError::Unauthorized(_)) => StatusCode::UNAUTHORIZED,
+        Some(ApiError::Forbidden(_)) => StatusCode::FORBIDDEN,
+        Some(ApiError::NotFound(_)) => StatusCode::NOT_FOUND,
+        Some(ApiError::TooManyRequests { .. }) => StatusCode::TOO_MANY_REQUESTS,
+        None => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    if let Some(ApiError::TooManyRequests { retry_after, .. }) = err.downcast_ref::<ApiError>() {
+        let response = HttpResponse::build(status)
+            .header(header::RETRY_AFTER, retry_after.to_string())
+            .body(err.to_string());
+        return InternalError::from_response(err.compat(), response).into();
+    }
+
+    InternalError::new(err.compat(), status).into()
+}
@@ -4,27 +4,51 @@
 
 use serde_derive::Deserialize;
 
+mod admin;
+mod admin_api;
+mod cli;
+mod client_addr;
+mod errors;
 mod metrics;
 mod request_logger;
 
 use actix_web::http::header;
+use actix_web::http::Cookie;
 use actix_web::http::Method;
 use actix_web::middleware::cors;
-use actix_web::{server, App, AsyncResponder, HttpRequest, HttpResponse, Json, Path, Query, Responder, State};
-use std::sync::Once;
-
+use actix_web::{
+    server, App, AsyncResponder, FutureResponse, HttpRequest, HttpResponse, Json, Path, Query, Responder, State,
+};
+use chrono::Duration;
+use std::net::IpAddr;
+use std::sync::{Arc, Once};
+
+use risso_api::config::RissoConfig;
 use risso_api::context::*;
 use risso_api::logs;
 use risso_api::logs::macros::*;
+use risso_api::CommentId;
 
 use futures::prelude::*;
 
+use crate::client_addr::{remote_addr, ClientAddrMiddleware};
+use crate::errors::api_error;
 use crate::request_logger::RequestLogger;
 
-fn unsubscribe(state: State<ApiContext>, path: Path<(String, String, String)>) -> impl Responder {
-    let (id, email, key) = path.into_inner();
+/// The email is percent-decoded by `risso_api::unsubscribe`: it is taken as is from the path,
+/// rather than from the `Path` extractor that may already have decoded it.
+fn unsubscribe(
+    state: State<ApiContext>,
+    http_req: HttpRequest<ApiContext>,
+    path: Path<(CommentId, String, String)>,
+) -> impl Responder {
+    let (id, _, key) = path.into_inner();
+    let email = http_req.match_info().get("email").unwrap_or_default().to_owned();
 
-    risso_api::unsubscribe(&state, id, email, key).map(Json).responder()
+    risso_api::unsubscribe(&state, id, email, key)
+        .map(|_| HttpResponse::Ok().body("You have been unsubscribed from replies to this comment."))
+        .map_err(api_error)
+        .responder()
 }
 
 pub fn view(id: Path<String>, req: HttpRequest<ApiContext>) -> impl Responder {
@@ -47,18 +71,199 @@ pub struct NewCommentParams {
 pub fn new_comment(
     _log: RequestLogger,
     state: State<ApiContext>,
+    http_req: HttpRequest<ApiContext>,
     req: Query<NewCommentParams>,
     body: Json<risso_api::NewComment>,
 ) -> impl Responder {
-    risso_api::new_comment(&state, req.into_inner().uri, body.into_inner())
+    let max_age = Duration::seconds(state.config().general.max_age);
+
+    risso_api::new_comment(&state, req.into_inner().uri, remote_addr(&http_req), body.into_inner())
+        .map(move |created| {
+            // Like Isso, also send the cookie in a custom header, for clients on a different domain
+            // that can't read the Set-Cookie header. It lives as long as the comment can be edited.
+            let cookie = Cookie::build(created.comment.id().to_string(), created.token)
+                .path("/")
+                .max_age(max_age)
+                .finish();
+
+            // Comments held for moderation are accepted, but not created yet.
+            let mut response = if created.comment.is_pending() {
+                HttpResponse::Accepted()
+            } else {
+                HttpResponse::Created()
+            };
+
+            response
+                .header("X-Set-Cookie", cookie.to_string())
+                .cookie(cookie)
+                .json(created.comment)
+        })
+        .map_err(api_error)
+        .responder()
+}
+
+/// The author's token for a comment, stored in a cookie named after the comment id.
+fn author_key(req: &HttpRequest<ApiContext>, id: CommentId) -> String {
+    req.cookie(&id.to_string())
+        .map(|cookie| cookie.value().to_owned())
+        .unwrap_or_default()
+}
+
+pub fn edit(
+    state: State<ApiContext>,
+    http_req: HttpRequest<ApiContext>,
+    id: Path<CommentId>,
+    body: Json<risso_api::EditComment>,
+) -> impl Responder {
+    let id = id.into_inner();
+    let key = author_key(&http_req, id);
+
+    risso_api::edit_comment(&state, id, key, body.into_inner())
         .map(Json)
+        .map_err(api_error)
         .responder()
 }
 
+pub fn delete(state: State<ApiContext>, http_req: HttpRequest<ApiContext>, id: Path<CommentId>) -> impl Responder {
+    let id = id.into_inner();
+    let key = author_key(&http_req, id);
+
+    risso_api::delete_comment(&state, id, key)
+        .map(Json)
+        .map_err(api_error)
+        .responder()
+}
+
+fn vote(state: &ApiContext, http_req: &HttpRequest<ApiContext>, id: CommentId, upvote: bool) -> impl Responder {
+    risso_api::vote(state, id, remote_addr(http_req), upvote)
+        .map(Json)
+        .map_err(api_error)
+        .responder()
+}
+
+pub fn like(state: State<ApiContext>, http_req: HttpRequest<ApiContext>, id: Path<CommentId>) -> impl Responder {
+    vote(&state, &http_req, id.into_inner(), true)
+}
+
+pub fn dislike(state: State<ApiContext>, http_req: HttpRequest<ApiContext>, id: Path<CommentId>) -> impl Responder {
+    vote(&state, &http_req, id.into_inner(), false)
+}
+
+/// Confirmation page for the moderation links sent by email, that can only be followed with a
+/// GET request. Actions are performed by POSTing to the same url.
+pub fn comment_action_confirm(path: Path<(CommentId, String, String)>) -> HttpResponse {
+    let (id, action, _) = path.into_inner();
+
+    let page = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><title>Comment {id}</title></head>
+<body>
+<script>
+if (confirm("{action} comment {id}: are you sure?")) {{
+    var xhr = new XMLHttpRequest();
+    xhr.open("POST", window.location.href);
+    xhr.onload = function() {{ document.body.textContent = xhr.status == 200 ? "Done." : xhr.responseText; }};
+    xhr.send(null);
+}}
+</script>
+</body>
+</html>"#,
+        id = id,
+        action = action
+    );
+
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(page)
+}
+
+pub fn comment_action(
+    state: State<ApiContext>,
+    path: Path<(CommentId, String, String)>,
+) -> FutureResponse<HttpResponse> {
+    let (id, action, key) = path.into_inner();
+
+    match action.as_str() {
+        "activate" => Box::new(
+            risso_api::activate_comment(&state, id, key)
+                .map(|_| HttpResponse::Ok().finish())
+                .map_err(api_error),
+        ),
+        "delete" => Box::new(
+            risso_api::delete_comment(&state, id, key)
+                .map(|deleted| HttpResponse::Ok().json(deleted))
+                .map_err(api_error),
+        ),
+        // Other actions are rejected by the route
+        _ => Box::new(futures::future::ok(HttpResponse::NotFound().finish())),
+    }
+}
+
 pub fn fetch(log: RequestLogger, state: State<ApiContext>, req: Query<risso_api::FetchRequest>) -> impl Responder {
     slog_info!(log, "Fetching comments");
 
-    risso_api::fetch(&state, req.into_inner()).map(Json).responder()
+    risso_api::fetch(&state, req.into_inner())
+        .map(Json)
+        .map_err(api_error)
+        .responder()
+}
+
+#[derive(Deserialize)]
+pub struct CountParams {
+    pub uri: String,
+}
+
+/// Number of comments for a single uri
+pub fn get_counts(state: State<ApiContext>, req: Query<CountParams>) -> impl Responder {
+    risso_api::counts(&state, vec![req.into_inner().uri])
+        .map(|counts| Json(counts[0]))
+        .map_err(api_error)
+        .responder()
+}
+
+/// Number of comments for a list of uris
+pub fn post_counts(state: State<ApiContext>, uris: Json<Vec<String>>) -> impl Responder {
+    risso_api::counts(&state, uris.into_inner())
+        .map(Json)
+        .map_err(api_error)
+        .responder()
+}
+
+pub fn preview(state: State<ApiContext>, req: Json<risso_api::PreviewRequest>) -> impl Responder {
+    risso_api::preview(&state, req.into_inner())
+        .map(Json)
+        .map_err(api_error)
+        .responder()
+}
+
+#[derive(Deserialize)]
+pub struct FeedParams {
+    pub uri: String,
+}
+
+pub fn feed(state: State<ApiContext>, req: Query<FeedParams>) -> impl Responder {
+    risso_api::feed(&state, req.into_inner().uri)
+        .map(|xml| {
+            HttpResponse::Ok()
+                .content_type("application/atom+xml; charset=utf-8")
+                .body(xml)
+        })
+        .map_err(api_error)
+        .responder()
+}
+
+/// Identicon for a comment hash. Avatars never change for a given hash, and can be cached forever.
+pub fn avatar(state: State<ApiContext>, path: Path<(String, String)>) -> impl Responder {
+    let (hash, format) = path.into_inner();
+
+    risso_api::avatar(&state, &hash, &format)
+        .map(|avatar| {
+            HttpResponse::Ok()
+                .content_type(avatar.content_type)
+                .header(header::CACHE_CONTROL, "public, max-age=31536000, immutable")
+                .body(avatar.data)
+        })
+        .map_err(api_error)
+        .responder()
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -67,19 +272,64 @@ pub fn fetch(log: RequestLogger, state: State<ApiContext>, req: Query<risso_api:
 pub struct ActixConfig {
     listen_addr: String,
     allowed_origins: Vec<String>,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+impl ActixConfig {
+    pub fn from_config(config: &risso_api::config::Config) -> Result<Self, failure::Error> {
+        config
+            .get::<ActixConfig>("actix")
+            .map_err(|err| failure::err_msg(format!("Invalid configuration in section [actix]: {}", err)))
+    }
 }
 
 pub fn main() -> Result<(), failure::Error> {
-    let (_guard, _log) = logs::setup_slog();
+    let matches = cli::app().get_matches();
+    let (command, sub_matches) = matches.subcommand();
+    let arg = |name: &str| cli::global_value(&matches, sub_matches, name);
 
-    info!("Starting...");
+    let (_guard, _log) = logs::setup_slog(cli::log_level(arg("log-level")), cli::log_format(arg("log-format")));
+
+    let merged_config = risso_api::config::load(arg("config"))?;
+
+    match command {
+        "" | "serve" => serve(&merged_config),
+        "migrate" => {
+            let dry_run = sub_matches.map_or(false, |m| m.is_present("dry-run"));
+            cli::migrate(&RissoConfig::from_config(&merged_config)?, dry_run)
+        }
+        "check-config" => cli::check_config(&merged_config),
+        "import-isso" => {
+            let path = sub_matches.and_then(|m| m.value_of("PATH")).unwrap_or_default();
+            let overwrite_preferences = sub_matches.map_or(false, |m| m.is_present("overwrite-preferences"));
+            cli::import_isso(&RissoConfig::from_config(&merged_config)?, path, overwrite_preferences)
+        }
+        "import" | "export" => {
+            let config = RissoConfig::from_config(&merged_config)?;
+            let path = sub_matches.and_then(|m| m.value_of("FILE")).unwrap_or_default();
+
+            if command == "import" {
+                cli::import(&config, path)
+            } else {
+                cli::export(&config, path)
+            }
+        }
+        "rerender" => cli::rerender(&RissoConfig::from_config(&merged_config)?),
+        _ => unreachable!(),
+    }
+}
 
-    let config = risso_api::CONFIG.get::<ActixConfig>("actix")?;
+fn serve(merged_config: &risso_api::config::Config) -> Result<(), failure::Error> {
+    let config = RissoConfig::from_config(merged_config)?;
+    let actix_config = ActixConfig::from_config(merged_config)?;
 
-    let listen_addr = config.listen_addr;
-    let allowed_origins = config.allowed_origins;
+    info!("Starting...");
+
+    let listen_addr = actix_config.listen_addr;
+    let allowed_origins = actix_config.allowed_origins;
+    let trusted_proxies = Arc::new(actix_config.trusted_proxies);
 
-    let api_builder = ApiBuilder::new()?;
+    let api_builder = ApiBuilder::new(config)?;
     let api = api_builder.build();
 
     let metrics_builder = metrics::MiddlewareBuilder::builder()?;
@@ -91,26 +341,45 @@ pub fn main() -> Result<(), failure::Error> {
             .route("/count", Method::GET, get_counts)
             .route("/counts", Method::POST, post_counts)
             .route("/feed", Method::GET, feed)
+            .route("/avatar/{hash:[0-9a-f]+}.{format:(svg|png)}", Method::GET, avatar)
             .route("/id/{id}", Method::GET, view)
+            .route("/id/{id}", Method::PUT, edit)
+            .route("/id/{id}", Method::DELETE, delete)
             .route("/id/{id}/unsubscribe/{email}/{key}", Method::GET, unsubscribe)
             .route(
-                "/id/{id}/{action:(edit|delete|activate)}/{key}",
+                "/id/{id}/{action:(delete|activate)}/{key}",
                 Method::GET,
-                comment_action,
+                comment_action_confirm,
             )
             .route(
-                "/id/{id}/{action:(edit|delete|activate)}/{key}",
+                "/id/{id}/{action:(delete|activate)}/{key}",
                 Method::POST,
                 comment_action,
             )
             .route("/id/{id}/like", Method::POST, like)
             .route("/id/{id}/dislike", Method::POST, dislike)
             .route("/preview", Method::POST, preview)
-            .route("/admin", Method::GET, admin)
+            .route("/admin", Method::GET, admin::index)
+            .route("/admin/login", Method::POST, admin::login)
+            .route("/admin/logout", Method::POST, admin::logout)
+            .route("/admin/moderate", Method::POST, admin::moderate)
+            .route("/admin/edit/{id}", Method::GET, admin::edit_form)
+            .route("/admin/edit/{id}", Method::POST, admin::edit)
+            .route("/api/admin/v1/comments", Method::GET, admin_api::list_comments)
+            .route(
+                "/api/admin/v1/comments/delete",
+                Method::POST,
+                admin_api::delete_comments,
+            )
+            .route("/api/admin/v1/comments/{id}", Method::GET, admin_api::get_comment)
+            .route("/api/admin/v1/comments/{id}", Method::PATCH, admin_api::patch_comment)
+            .route("/api/admin/v1/threads", Method::GET, admin_api::list_threads)
+            .route("/api/admin/v1/threads/{id}", Method::PATCH, admin_api::patch_thread)
             .route("/metrics", Method::GET, metrics::handler)
             .middleware(metrics_builder.build())
             .middleware(build_cors(&allowed_origins))
             .middleware(actix_web_requestid::RequestIDHeader)
+            .middleware(ClientAddrMiddleware::new(trusted_proxies.clone()))
     });
 
     srv.bind(listen_addr)?.run();
@@ -133,46 +402,8 @@ fn build_cors(origins: &[String]) -> cors::Cors {
     }
 
     cors.allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT, header::CONTENT_TYPE])
+        .expose_headers(vec!["X-Set-Cookie"])
         .max_age(3600);
 
     cors.finish()
 }
-
-//--------------------------------------------------------------------------------------------------
-// Stubs
-
-fn todo() -> HttpResponse {
-    HttpResponse::NotImplemented().body("Not implemented yet!")
-}
-
-fn get_counts(_state: State<ApiContext>) -> HttpResponse {
-    todo()
-}
-
-fn post_counts(_state: State<ApiContext>) -> HttpResponse {
-    todo()
-}
-
-fn feed(_state: State<ApiContext>) -> HttpResponse {
-    todo()
-}
-
-fn comment_action(_state: State<ApiContext>) -> HttpResponse {
-    todo()
-}
-
-fn like(_state: State<ApiContext>) -> HttpResponse {
-    todo()
-}
-
-fn dislike(_state: State<ApiContext>) -> HttpResponse {
-    todo()
-}
-
-fn preview(_state: State<ApiContext>) -> HttpResponse {
-    todo()
-}
-
-fn admin(_state: State<ApiContext>) -> HttpResponse {
-    todo()
-}
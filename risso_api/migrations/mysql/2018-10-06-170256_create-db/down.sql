@@ -0,0 +1,4 @@
+
+DROP TABLE comments;
+DROP TABLE preferences;
+DROP TABLE threads;
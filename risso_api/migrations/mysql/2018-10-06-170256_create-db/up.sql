@@ -0,0 +1,32 @@
+
+-- Same schema as Isso's SQLite database, with the NOT NULL columns of src/schema.rs.
+-- Dates are stored as fractional seconds since the Epoch.
+-- Comments can be up to 65535 characters, hence MEDIUMTEXT.
+
+CREATE TABLE IF NOT EXISTS threads (
+    id INTEGER AUTO_INCREMENT PRIMARY KEY,
+    uri VARCHAR(256) NOT NULL UNIQUE,
+    title VARCHAR(256) NOT NULL
+) CHARACTER SET utf8mb4;
+
+CREATE TABLE IF NOT EXISTS comments (
+    tid INTEGER NOT NULL REFERENCES threads(id),
+    id INTEGER AUTO_INCREMENT PRIMARY KEY,
+    parent INTEGER,
+    created DOUBLE NOT NULL,
+    modified DOUBLE,
+    mode INTEGER NOT NULL,
+    remote_addr VARCHAR(64) NOT NULL,
+    text MEDIUMTEXT NOT NULL,
+    author VARCHAR(1024),
+    email VARCHAR(254),
+    website VARCHAR(254),
+    likes INTEGER NOT NULL DEFAULT 0,
+    dislikes INTEGER NOT NULL DEFAULT 0,
+    voters BLOB NOT NULL
+) CHARACTER SET utf8mb4;
+
+CREATE TABLE IF NOT EXISTS preferences (
+    `key` VARCHAR(191) PRIMARY KEY,
+    value TEXT NOT NULL
+) CHARACTER SET utf8mb4;
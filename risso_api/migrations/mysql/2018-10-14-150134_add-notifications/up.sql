@@ -0,0 +1,3 @@
+
+-- Add a flag to notify posters of replies to their comment
+ALTER TABLE comments ADD COLUMN notification BOOLEAN NOT NULL DEFAULT FALSE;
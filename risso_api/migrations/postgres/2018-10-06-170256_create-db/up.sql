@@ -0,0 +1,31 @@
+
+-- Same schema as Isso's SQLite database, with the NOT NULL columns of src/schema.rs.
+-- Dates are stored as fractional seconds since the Epoch.
+
+CREATE TABLE IF NOT EXISTS threads (
+    id SERIAL PRIMARY KEY,
+    uri VARCHAR(256) NOT NULL UNIQUE,
+    title VARCHAR(256) NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS comments (
+    tid INTEGER NOT NULL REFERENCES threads(id),
+    id SERIAL PRIMARY KEY,
+    parent INTEGER,
+    created DOUBLE PRECISION NOT NULL,
+    modified DOUBLE PRECISION,
+    mode INTEGER NOT NULL,
+    remote_addr VARCHAR NOT NULL,
+    text TEXT NOT NULL,
+    author VARCHAR,
+    email VARCHAR,
+    website VARCHAR,
+    likes INTEGER NOT NULL DEFAULT 0,
+    dislikes INTEGER NOT NULL DEFAULT 0,
+    voters BYTEA NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS preferences (
+    key VARCHAR PRIMARY KEY,
+    value VARCHAR NOT NULL
+);
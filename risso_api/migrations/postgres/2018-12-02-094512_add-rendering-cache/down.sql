@@ -0,0 +1,3 @@
+ALTER TABLE comments DROP COLUMN html_fingerprint;
+ALTER TABLE comments DROP COLUMN html;
+ALTER TABLE comments DROP COLUMN hash;
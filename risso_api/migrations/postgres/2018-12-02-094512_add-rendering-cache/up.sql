@@ -0,0 +1,5 @@
+-- Cache of the html rendering of comments, and of the hash identifying their author
+ALTER TABLE comments ADD COLUMN hash TEXT;
+ALTER TABLE comments ADD COLUMN html TEXT;
+-- Fingerprint of the markup configuration used to render `html`
+ALTER TABLE comments ADD COLUMN html_fingerprint TEXT;
@@ -0,0 +1,25 @@
+-- SQLite before 3.35 can't drop columns: rebuild the table without it
+CREATE TABLE comments_down (
+    tid REFERENCES threads(id),
+    id INTEGER PRIMARY KEY,
+    parent INTEGER,
+    created FLOAT NOT NULL,
+    modified FLOAT,
+    mode INTEGER,
+    remote_addr VARCHAR,
+    text VARCHAR,
+    author VARCHAR,
+    email VARCHAR,
+    website VARCHAR,
+    likes INTEGER DEFAULT 0,
+    dislikes INTEGER DEFAULT 0,
+    voters BLOB NOT NULL
+);
+
+INSERT INTO comments_down
+    SELECT tid, id, parent, created, modified, mode, remote_addr, text, author, email, website,
+        likes, dislikes, voters
+    FROM comments;
+
+DROP TABLE comments;
+ALTER TABLE comments_down RENAME TO comments;
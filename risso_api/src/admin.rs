@@ -0,0 +1,518 @@
+//! Moderation functions used by the admin interface.
+//!
+//! Unlike the public API, these functions act on any comment without requiring a comment key.
+//! Functions that modify data require an `Admin`, which is obtained by authenticating with
+//! `check_session` (admin interface) or `check_api_token` (scripts), and are recorded in the audit log.
+
+use chrono::prelude::*;
+use futures::future::Future;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use validator::Validate;
+
+use crate::context::ApiContext;
+use crate::errors::ApiError;
+use crate::logs::macros::*;
+use crate::store::CommentStore;
+use crate::{audit, dieselext, guard, markup, models, tokens};
+use crate::{BoxFuture, CommentId, EditComment, ThreadId};
+
+/// Subject of admin session tokens, and name of the admin logged in with the password.
+const ADMIN_SUBJECT: &str = "admin";
+
+/// An authenticated admin. Its name identifies the admin in the audit log.
+#[derive(Clone, Debug)]
+pub struct Admin {
+    name: String,
+}
+
+impl Admin {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Is the admin interface enabled? It requires `admin.enabled` and a password.
+pub fn is_enabled(ctx: &ApiContext) -> bool {
+    let config = &ctx.config().admin;
+    config.enabled && !config.password.is_empty()
+}
+
+/// Check the admin password and return a session token. Addresses that failed too many times
+/// recently are rejected, as set by `admin.login_ratelimit`.
+pub fn login(ctx: &ApiContext, remote_addr: &str, password: &str) -> Result<String, ApiError> {
+    if !is_enabled(ctx) {
+        return Err(ApiError::Forbidden(String::from("The admin interface is disabled")));
+    }
+
+    guard::check_login(ctx.config(), ctx.login_limiter(), remote_addr)?;
+
+    if !tokens::secret_matches(ctx.session_key(), &ctx.config().admin.password, password) {
+        warn!("Failed admin login attempt from {}", remote_addr);
+        guard::failed_login(ctx.login_limiter(), remote_addr);
+        return Err(ApiError::Forbidden(String::from("Wrong password")));
+    }
+
+    Ok(tokens::sign(ctx.session_key(), tokens::ADMIN, ADMIN_SUBJECT))
+}
+
+/// Check that `token` is a session token returned by `login` that hasn't expired yet.
+pub fn check_session(ctx: &ApiContext, token: &str) -> Result<Admin, ApiError> {
+    let max_age = Some(ctx.config().admin.session_max_age);
+
+    if is_enabled(ctx) && tokens::verify(ctx.session_key(), tokens::ADMIN, ADMIN_SUBJECT, token, max_age) {
+        Ok(Admin {
+            name: String::from(ADMIN_SUBJECT),
+        })
+    } else {
+        Err(ApiError::Forbidden(String::from("Invalid or expired admin session")))
+    }
+}
+
+/// Check a bearer token of the admin API against those listed in `admin.api_tokens`. The admin is
+/// named after the token's key in that table.
+pub fn check_api_token(ctx: &ApiContext, token: &str) -> Result<Admin, ApiError> {
+    ctx.config()
+        .admin
+        .api_tokens
+        .iter()
+        .find(|(_, value)| !value.is_empty() && tokens::secret_matches(ctx.session_key(), value, token))
+        .map(|(name, _)| Admin {
+            name: format!("token:{}", name),
+        })
+        .ok_or_else(|| ApiError::Unauthorized(String::from("Invalid API token")))
+}
+
+//--------------------------------------------------------------------------------------------------
+// Listing comments
+
+/// A comment with all its information, along with the thread it belongs to.
+#[derive(Serialize)]
+pub struct AdminComment {
+    pub id: CommentId,
+    pub thread_id: ThreadId,
+    pub uri: String,
+    pub title: String,
+    pub parent: Option<CommentId>,
+    pub mode: i32,
+    pub created: DateTime<Utc>,
+    pub modified: Option<DateTime<Utc>>,
+    pub author: Option<String>,
+    pub email: Option<String>,
+    pub website: Option<String>,
+    pub remote_addr: String,
+    pub text: String,
+    pub likes: i32,
+    pub dislikes: i32,
+}
+
+impl AdminComment {
+    fn new(comment: models::Comment, thread: models::Thread) -> Self {
+        AdminComment {
+            id: comment.id,
+            thread_id: thread.id,
+            uri: thread.uri,
+            title: thread.title,
+            parent: comment.parent,
+            mode: comment.mode,
+            created: comment.created.0,
+            modified: comment.modified.map(|d| d.0),
+            author: comment.author,
+            email: comment.email,
+            website: comment.website,
+            remote_addr: comment.remote_addr,
+            text: comment.text,
+            likes: comment.likes,
+            dislikes: comment.dislikes,
+        }
+    }
+}
+
+/// Filters to select comments. Dates are inclusive bounds of the comments' creation day, in UTC.
+#[derive(Clone, Debug, Deserialize, Validate)]
+#[serde(default)]
+pub struct CommentFilter {
+    /// Mode bitmask, as in `models::CommentMode`. All comments are selected if not set.
+    pub mode: Option<i32>,
+    /// Uri of the thread.
+    #[validate(length(max = "1024"))]
+    pub uri: Option<String>,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    /// Text searched in the comments' text, author and email.
+    #[validate(length(max = "1024"))]
+    pub search: Option<String>,
+    /// Page number, starting at 0.
+    #[validate(range(min = "0", max = "1000000"))]
+    pub page: i64,
+    #[validate(range(min = "1", max = "500"))]
+    pub per_page: i64,
+}
+
+impl Default for CommentFilter {
+    fn default() -> Self {
+        CommentFilter {
+            mode: None,
+            uri: None,
+            from: None,
+            to: None,
+            search: None,
+            page: 0,
+            per_page: 50,
+        }
+    }
+}
+
+/// A page of comments, and the total number of comments matching the filter.
+#[derive(Serialize)]
+pub struct CommentPage {
+    pub comments: Vec<AdminComment>,
+    pub total: i64,
+}
+
+/// List comments across all threads, most recent first.
+pub fn list(ctx: &ApiContext, filter: CommentFilter) -> BoxFuture<CommentPage> {
+    if let Some(err) = crate::validate(&filter) {
+        return err;
+    }
+
+    let day_start = |date: NaiveDate| dieselext::FloatDateTime(Utc.from_utc_date(&date).and_hms(0, 0, 0)).to_f64();
+
+    let after = filter.from.map(day_start);
+    let before = filter.to.map(|date| day_start(date.succ()));
+
+    ctx.spawn_store(move |store| {
+        let (comments, total) = store.list(
+            filter.mode,
+            filter.uri.as_ref().map(String::as_str),
+            filter.search.as_ref().map(String::as_str),
+            after,
+            before,
+            filter.per_page,
+            filter.page * filter.per_page,
+        )?;
+
+        Ok(CommentPage {
+            comments: comments
+                .into_iter()
+                .map(|(comment, thread)| AdminComment::new(comment, thread))
+                .collect(),
+            total,
+        })
+    })
+    .boxed()
+}
+
+/// List all threads, sorted by uri.
+pub fn threads(ctx: &ApiContext) -> BoxFuture<Vec<models::Thread>> {
+    ctx.spawn_store(|store| store.threads()).boxed()
+}
+
+/// Get a comment by its id.
+pub fn get(ctx: &ApiContext, id: CommentId) -> BoxFuture<AdminComment> {
+    ctx.spawn_store(move |store| {
+        let comment = store.comment(id)?.ok_or_else(|| crate::not_found(id))?;
+        admin_comment(store, comment)
+    })
+    .boxed()
+}
+
+fn admin_comment(store: &dyn CommentStore, comment: models::Comment) -> Result<AdminComment, failure::Error> {
+    let thread = store
+        .thread(comment.thread_id)?
+        .ok_or_else(|| ApiError::NotFound(format!("Thread {} not found", comment.thread_id)))?;
+
+    Ok(AdminComment::new(comment, thread))
+}
+
+//--------------------------------------------------------------------------------------------------
+// Moderation actions
+
+/// Activate pending comments. Returns the number of comments that were activated.
+pub fn activate(ctx: &ApiContext, admin: &Admin, ids: Vec<CommentId>) -> BoxFuture<usize> {
+    let notify_ctx = ctx.clone();
+    let admin = admin.clone();
+
+    ctx.spawn_store(move |store| {
+        let mut notifications = Vec::new();
+        let mut record = audit::Record::new(&notify_ctx.config().admin, admin.name(), "activate");
+
+        let result = store.atomically(|| {
+            let mut count = 0;
+            for &id in &ids {
+                if crate::activate(&notify_ctx, store, id, &mut notifications)? {
+                    count += 1;
+                }
+            }
+
+            record.begin(json!({ "ids": ids }))?;
+            Ok(count)
+        });
+        record.end(&result);
+
+        let count = result?;
+        crate::send_notifications(&notify_ctx, notifications);
+        Ok(count)
+    })
+    .boxed()
+}
+
+/// Delete comments. Comments that have replies are soft-deleted. Returns the number of comments
+/// that were deleted.
+pub fn delete(ctx: &ApiContext, admin: &Admin, ids: Vec<CommentId>) -> BoxFuture<usize> {
+    let config = ctx.config().clone();
+    let admin = admin.clone();
+
+    ctx.spawn_store(move |store| {
+        let mut record = audit::Record::new(&config.admin, admin.name(), "delete");
+
+        let result = store.atomically(|| {
+            let mut count = 0;
+            for &id in &ids {
+                if store.comment(id)?.is_some() {
+                    store.delete_comment(id)?;
+                    count += 1;
+                }
+            }
+
+            record.begin(json!({ "ids": ids }))?;
+            Ok(count)
+        });
+        record.end(&result);
+        result
+    })
+    .boxed()
+}
+
+/// Edit the text, author and website of a comment.
+pub fn edit(ctx: &ApiContext, admin: &Admin, id: CommentId, req: EditComment) -> BoxFuture<AdminComment> {
+    let req = req.normalize();
+    if let Some(err) = crate::validate(&req) {
+        return err;
+    }
+
+    let config = ctx.config().clone();
+    let admin = admin.clone();
+
+    ctx.spawn_store(move |store| {
+        let mut record = audit::Record::new(&config.admin, admin.name(), "edit");
+
+        let result = store.atomically(|| {
+            let author = req.author.as_ref().map(String::as_str);
+            let website = req.website.as_ref().map(String::as_str);
+
+            let mut comment = store
+                .update_comment(id, &req.text, author, website)?
+                .ok_or_else(|| crate::not_found(id))?;
+            markup::refresh(&config.markup, store, std::slice::from_mut(&mut comment))?;
+
+            record.begin(json!({ "id": id, "author": author, "website": website, "text": req.text }))?;
+            admin_comment(store, comment)
+        });
+        record.end(&result);
+        result
+    })
+    .boxed()
+}
+
+/// Changes to a comment. Only valid and pending comments can change mode: deleting comments is
+/// done with `delete`.
+#[derive(Deserialize, Validate)]
+pub struct PatchComment {
+    mode: Option<i32>,
+    #[validate(length(min = "3", max = "65535"))]
+    text: Option<String>,
+}
+
+/// Change the mode or the text of a comment.
+pub fn patch(ctx: &ApiContext, admin: &Admin, id: CommentId, req: PatchComment) -> BoxFuture<AdminComment> {
+    use crate::models::CommentMode;
+
+    if let Some(err) = crate::validate(&req) {
+        return err;
+    }
+
+    let notify_ctx = ctx.clone();
+    let admin = admin.clone();
+
+    ctx.spawn_store(move |store| {
+        let mut notifications = Vec::new();
+        let mut record = audit::Record::new(&notify_ctx.config().admin, admin.name(), "patch");
+
+        let result = store.atomically(|| {
+            let comment = store.comment(id)?.ok_or_else(|| crate::not_found(id))?;
+
+            if let Some(ref text) = req.text {
+                let author = comment.author.as_ref().map(String::as_str);
+                let website = comment.website.as_ref().map(String::as_str);
+                store.update_comment(id, text, author, website)?;
+            }
+
+            match req.mode {
+                None => {}
+                Some(mode) if mode == comment.mode => {}
+                Some(_) if comment.mode == CommentMode::SoftDeleted as i32 => {
+                    return Err(ApiError::BadRequest(format!("Comment {} is deleted", id)).into());
+                }
+                Some(mode) if mode == CommentMode::Valid as i32 => {
+                    crate::activate(&notify_ctx, store, id, &mut notifications)?;
+                }
+                Some(mode) if mode == CommentMode::Pending as i32 => {
+                    store.deactivate(id)?;
+                }
+                Some(mode) => {
+                    return Err(ApiError::BadRequest(format!("Invalid mode {}", mode)).into());
+                }
+            }
+
+            record.begin(json!({ "id": id, "mode": req.mode, "text": req.text }))?;
+
+            let mut comment = store.comment(id)?.ok_or_else(|| crate::not_found(id))?;
+            markup::refresh(&notify_ctx.config().markup, store, std::slice::from_mut(&mut comment))?;
+            admin_comment(store, comment)
+        });
+        record.end(&result);
+
+        let comment = result?;
+        crate::send_notifications(&notify_ctx, notifications);
+        Ok(comment)
+    })
+    .boxed()
+}
+
+//--------------------------------------------------------------------------------------------------
+// Threads
+
+/// Changes to a thread.
+#[derive(Deserialize, Validate)]
+pub struct PatchThread {
+    #[validate(length(min = "1", max = "1024"))]
+    uri: Option<String>,
+    #[validate(length(max = "256"))]
+    title: Option<String>,
+}
+
+/// Change the uri or the title of a thread, e.g. when a page of the site has moved. Uris must be
+/// unique.
+pub fn patch_thread(ctx: &ApiContext, admin: &Admin, id: ThreadId, req: PatchThread) -> BoxFuture<models::Thread> {
+    if let Some(err) = crate::validate(&req) {
+        return err;
+    }
+
+    let config = ctx.config().clone();
+    let admin = admin.clone();
+
+    ctx.spawn_store(move |store| {
+        let mut record = audit::Record::new(&config.admin, admin.name(), "patch_thread");
+
+        let result = store.atomically(|| {
+            let thread = store
+                .thread(id)?
+                .ok_or_else(|| ApiError::NotFound(format!("Thread {} not found", id)))?;
+
+            if let Some(ref uri) = req.uri {
+                match store.thread_by_uri(uri)? {
+                    Some(ref other) if other.id != id => {
+                        return Err(
+                            ApiError::BadRequest(format!("Thread {} already uses uri {}", other.id, uri)).into(),
+                        );
+                    }
+                    _ => {}
+                }
+            }
+
+            let uri = req.uri.as_ref().unwrap_or(&thread.uri);
+            let title = req.title.as_ref().unwrap_or(&thread.title);
+            let thread = store.update_thread(id, uri, title)?;
+
+            record.begin(json!({ "id": id, "uri": req.uri, "title": req.title }))?;
+            Ok(thread)
+        });
+        record.end(&result);
+        result
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::RissoConfig;
+    use crate::models::NewThreadRow;
+    use crate::store::fixtures::{comment_row, memory_api};
+
+    #[test]
+    fn api_tokens() {
+        let mut config = RissoConfig::default();
+        config
+            .admin
+            .api_tokens
+            .insert(String::from("scripts"), String::from("secret"));
+        config.admin.api_tokens.insert(String::from("disabled"), String::new());
+
+        let (_builder, ctx) = memory_api(config);
+
+        assert_eq!("token:scripts", check_api_token(&ctx, "secret").unwrap().name());
+
+        // Empty tokens never match
+        assert!(check_api_token(&ctx, "").is_err());
+        assert!(check_api_token(&ctx, "some token").is_err());
+    }
+
+    #[test]
+    fn edits_are_rendered() {
+        let mut config = RissoConfig::default();
+        config
+            .admin
+            .api_tokens
+            .insert(String::from("scripts"), String::from("secret"));
+        let (builder, ctx) = memory_api(config);
+        let admin = check_api_token(&ctx, "secret").unwrap();
+
+        let id = builder
+            .storage
+            .run(|store| {
+                let thread = store.insert_thread(&NewThreadRow { uri: "/", title: "" })?;
+                Ok(store.insert_comment(&comment_row(thread.id, "Hello"))?.id)
+            })
+            .unwrap();
+        let html = || builder.storage.run(|store| store.comment(id)).unwrap().unwrap().html;
+
+        let req = EditComment {
+            author: None,
+            text: String::from("*Edited*"),
+            website: None,
+        };
+        edit(&ctx, &admin, id, req).wait().unwrap();
+        assert_eq!(Some("<p><em>Edited</em></p>\n".to_owned()), html());
+
+        let req = PatchComment {
+            mode: None,
+            text: Some(String::from("*Patched*")),
+        };
+        patch(&ctx, &admin, id, req).wait().unwrap();
+        assert_eq!(Some("<p><em>Patched</em></p>\n".to_owned()), html());
+    }
+
+    #[test]
+    fn failed_logins_are_limited() {
+        let mut config = RissoConfig::default();
+        config.admin.enabled = true;
+        config.admin.password = String::from("secret");
+        config.admin.login_ratelimit = 2;
+
+        let (_builder, ctx) = memory_api(config);
+
+        assert!(login(&ctx, "10.0.0.1", "secret").is_ok());
+        assert!(login(&ctx, "10.0.0.1", "guess 1").is_err());
+        assert!(login(&ctx, "10.0.0.1", "guess 2").is_err());
+
+        // Even the right password is rejected until failures are old enough
+        match login(&ctx, "10.0.0.1", "secret") {
+            Err(ApiError::TooManyRequests { .. }) => {}
+            _ => panic!("Expected a TooManyRequests error"),
+        }
+        assert!(login(&ctx, "10.0.0.2", "secret").is_ok());
+    }
+}
@@ -0,0 +1,144 @@
+//! Audit log of the changes made through the admin functions.
+//!
+//! Each entry is a JSON object on its own line, appended to the file set in `admin.audit_log`, so
+//! that it can be easily processed with tools like `jq`. No audit log is written if it is empty.
+//!
+//! A change is first recorded as `pending` within its transaction, so that changes that can't be
+//! recorded are rolled back. Once the transaction is over, an entry with the same `id` records
+//! whether it was `committed` or `rolled_back`.
+
+use chrono::prelude::*;
+use serde_derive::Serialize;
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+
+use crate::config::AdminConfig;
+use crate::logs::macros::*;
+
+lazy_static! {
+    /// Serializes writes, so that concurrent entries aren't interleaved.
+    static ref LOCK: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    time: DateTime<Utc>,
+    id: &'a str,
+    status: &'a str,
+    actor: &'a str,
+    action: &'a str,
+    #[serde(skip_serializing_if = "serde_json::Value::is_null")]
+    details: serde_json::Value,
+}
+
+/// A change made by `actor`, recorded before and after its transaction.
+pub struct Record<'a> {
+    config: &'a AdminConfig,
+    actor: &'a str,
+    action: &'a str,
+    id: Option<String>,
+}
+
+impl<'a> Record<'a> {
+    pub fn new(config: &'a AdminConfig, actor: &'a str, action: &'a str) -> Self {
+        Record {
+            config,
+            actor,
+            action,
+            id: None,
+        }
+    }
+
+    /// Record the change as pending. It must be called within the transaction: an error is
+    /// returned if the entry could not be written, so that the change can be rolled back.
+    pub fn begin(&mut self, details: serde_json::Value) -> Result<(), failure::Error> {
+        info!("Admin action by {}: {} {}", self.actor, self.action, details);
+
+        let id = format!("{:016x}", rand::random::<u64>());
+        write(self.config, &self.entry(&id, "pending", details))?;
+        self.id = Some(id);
+        Ok(())
+    }
+
+    /// Record the outcome of the transaction, if the change was recorded as pending. The change
+    /// is over, so errors are only logged.
+    pub fn end<T>(&self, result: &Result<T, failure::Error>) {
+        if let Some(ref id) = self.id {
+            let status = if result.is_ok() { "committed" } else { "rolled_back" };
+
+            if let Err(err) = write(self.config, &self.entry(id, status, serde_json::Value::Null)) {
+                error!("Failed to record the end of admin action {}: {}", id, err);
+            }
+        }
+    }
+
+    fn entry<'b>(&'b self, id: &'b str, status: &'b str, details: serde_json::Value) -> Entry<'b> {
+        Entry {
+            time: Utc::now(),
+            id,
+            status,
+            actor: self.actor,
+            action: self.action,
+            details,
+        }
+    }
+}
+
+fn write(config: &AdminConfig, entry: &Entry) -> Result<(), failure::Error> {
+    if config.audit_log.is_empty() {
+        return Ok(());
+    }
+
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+
+    let _lock = LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&config.audit_log)?
+        .write_all(line.as_bytes())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::RissoConfig;
+    use serde_json::json;
+    use std::fs;
+
+    #[test]
+    fn pending_and_outcome() {
+        let path = std::env::temp_dir().join(format!("risso-audit-{}.log", rand::random::<u32>()));
+        let mut config = RissoConfig::default().admin;
+        config.audit_log = path.to_string_lossy().into_owned();
+
+        let mut committed = Record::new(&config, "admin", "delete");
+        committed.begin(json!({ "ids": [1] })).unwrap();
+        committed.end(&Ok(()));
+
+        let mut rolled_back = Record::new(&config, "admin", "edit");
+        rolled_back.begin(json!({ "id": 2 })).unwrap();
+        rolled_back.end::<()>(&Err(failure::err_msg("conflict")));
+
+        // Nothing is written for changes that failed before being recorded
+        Record::new(&config, "admin", "patch").end::<()>(&Err(failure::err_msg("not found")));
+
+        let log = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let entries: Vec<serde_json::Value> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let statuses: Vec<&str> = entries.iter().map(|entry| entry["status"].as_str().unwrap()).collect();
+        assert_eq!(vec!["pending", "committed", "pending", "rolled_back"], statuses);
+
+        assert_eq!(entries[0]["id"], entries[1]["id"]);
+        assert_eq!(json!({ "ids": [1] }), entries[0]["details"]);
+        assert!(entries[1].get("details").is_none());
+    }
+}
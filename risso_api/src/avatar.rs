@@ -0,0 +1,198 @@
+//! Identicons, generated locally from the `hash` of comments so that no data about commenters is
+//! sent to a third party such as Gravatar.
+//!
+//! An identicon is a 5x5 grid of cells, symmetric around its vertical axis, whose pattern and color
+//! are derived from the hash. The same hash always gives the same image.
+
+use png::HasParameters;
+
+/// Number of cells on each side of the grid.
+const GRID: usize = 5;
+/// Size of a cell, in pixels.
+const CELL: usize = 12;
+/// Space around the grid, in pixels.
+const MARGIN: usize = 10;
+/// Width and height of identicons, in pixels.
+pub const SIZE: usize = GRID * CELL + 2 * MARGIN;
+
+const BACKGROUND: (u8, u8, u8) = (0xf0, 0xf0, 0xf0);
+
+/// Is `hash` something that can be turned into an identicon? Comment hashes are hex-encoded sha1
+/// digests, but any lowercase hex string with up to 64 characters is accepted.
+pub fn is_valid_hash(hash: &str) -> bool {
+    let is_lower_hex = |b| match b {
+        b'0'..=b'9' | b'a'..=b'f' => true,
+        _ => false,
+    };
+
+    !hash.is_empty() && hash.len() <= 64 && hash.bytes().all(is_lower_hex)
+}
+
+struct Identicon {
+    color: (u8, u8, u8),
+    cells: [[bool; GRID]; GRID],
+}
+
+impl Identicon {
+    fn new(hash: &str) -> Identicon {
+        // Hash again, to have evenly distributed bits whatever the input
+        let mut digest = sha1::Sha1::new();
+        digest.update(hash.as_bytes());
+        let bytes = digest.digest().bytes();
+
+        let hue = f64::from(u16::from(bytes[0]) << 8 | u16::from(bytes[1])) * 360.0 / 65536.0;
+        let color = hsl_to_rgb(hue, 0.5, 0.55);
+
+        // Only the left columns and the middle one are drawn from the hash, and mirrored on the right
+        let mut cells = [[false; GRID]; GRID];
+        for (row, row_cells) in cells.iter_mut().enumerate() {
+            for col in 0..(GRID + 1) / 2 {
+                let bit = row * GRID + col;
+                let set = (bytes[2 + bit / 8] >> (bit % 8)) & 1 == 1;
+                row_cells[col] = set;
+                row_cells[GRID - 1 - col] = set;
+            }
+        }
+
+        Identicon { color, cells }
+    }
+
+    /// Is the pixel at (`x`, `y`) in a set cell?
+    fn is_set(&self, x: usize, y: usize) -> bool {
+        if x < MARGIN || y < MARGIN || x >= SIZE - MARGIN || y >= SIZE - MARGIN {
+            return false;
+        }
+        self.cells[(y - MARGIN) / CELL][(x - MARGIN) / CELL]
+    }
+}
+
+/// Convert a color from HSL (hue in degrees, saturation and lightness between 0 and 1) to RGB.
+fn hsl_to_rgb(hue: f64, saturation: f64, lightness: f64) -> (u8, u8, u8) {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let h = hue / 60.0;
+    let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+
+    let (r, g, b) = match h as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+
+    let m = lightness - chroma / 2.0;
+    let channel = |value: f64| ((value + m) * 255.0).round() as u8;
+    (channel(r), channel(g), channel(b))
+}
+
+/// The identicon for `hash`, as an SVG document.
+pub fn svg(hash: &str) -> String {
+    let identicon = Identicon::new(hash);
+    let (r, g, b) = identicon.color;
+
+    let mut svg = format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{size}" height="{size}" viewBox="0 0 {size} {size}"><rect width="{size}" height="{size}" fill="#{:02x}{:02x}{:02x}"/><g fill="#{:02x}{:02x}{:02x}">"##,
+        BACKGROUND.0,
+        BACKGROUND.1,
+        BACKGROUND.2,
+        r,
+        g,
+        b,
+        size = SIZE
+    );
+
+    for (row, cells) in identicon.cells.iter().enumerate() {
+        for (col, set) in cells.iter().enumerate() {
+            if *set {
+                svg.push_str(&format!(
+                    r#"<rect x="{}" y="{}" width="{cell}" height="{cell}"/>"#,
+                    MARGIN + col * CELL,
+                    MARGIN + row * CELL,
+                    cell = CELL
+                ));
+            }
+        }
+    }
+
+    svg.push_str("</g></svg>");
+    svg
+}
+
+/// The identicon for `hash`, as a PNG image.
+pub fn png(hash: &str) -> Result<Vec<u8>, failure::Error> {
+    let identicon = Identicon::new(hash);
+
+    let mut pixels = Vec::with_capacity(SIZE * SIZE * 3);
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            let (r, g, b) = if identicon.is_set(x, y) {
+                identicon.color
+            } else {
+                BACKGROUND
+            };
+            pixels.extend_from_slice(&[r, g, b]);
+        }
+    }
+
+    let mut result = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut result, SIZE as u32, SIZE as u32);
+        encoder.set(png::ColorType::RGB).set(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&pixels)?;
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_hashes() {
+        assert!(is_valid_hash("4a8b0e5ac1b1b7c1ee5b2e5e1f0fce1f6d1d1e5b"));
+        assert!(!is_valid_hash("ABCDEF0123"));
+        assert!(!is_valid_hash(""));
+        assert!(!is_valid_hash("../etc/passwd"));
+        assert!(!is_valid_hash(&"a".repeat(65)));
+    }
+
+    #[test]
+    fn identicons() {
+        let first = Identicon::new("4a8b0e5ac1b1b7c1ee5b2e5e1f0fce1f6d1d1e5b");
+        let second = Identicon::new("4a8b0e5ac1b1b7c1ee5b2e5e1f0fce1f6d1d1e5c");
+
+        assert_ne!((first.color, first.cells), (second.color, second.cells));
+
+        for cells in first.cells.iter() {
+            for (col, set) in cells.iter().enumerate() {
+                assert_eq!(*set, cells[GRID - 1 - col]);
+            }
+        }
+
+        // Deterministic
+        assert_eq!(svg("abc"), svg("abc"));
+        assert_eq!(png("abc").unwrap(), png("abc").unwrap());
+        assert_ne!(svg("abc"), svg("abd"));
+    }
+
+    #[test]
+    fn formats() {
+        let svg = svg("abc");
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"80\""));
+        assert!(svg.ends_with("</g></svg>"));
+
+        let png = png("abc").unwrap();
+        assert_eq!(b"\x89PNG\r\n\x1a\n", &png[0..8]);
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!((255, 0, 0), hsl_to_rgb(0.0, 1.0, 0.5));
+        assert_eq!((0, 255, 0), hsl_to_rgb(120.0, 1.0, 0.5));
+        assert_eq!((0, 0, 255), hsl_to_rgb(240.0, 1.0, 0.5));
+        assert_eq!((128, 128, 128), hsl_to_rgb(42.0, 0.0, 0.5));
+    }
+}
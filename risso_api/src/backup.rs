@@ -0,0 +1,266 @@
+//! Portable export and import of all data, e.g. for backups or to move to another database backend.
+//!
+//! The format is newline-delimited JSON: a header with the format version, followed by one record
+//! per line for each preference, thread and comment. Records are the serialized `models` structs,
+//! where dates are RFC 3339 strings with nanoseconds, so that they are kept exactly.
+
+use std::fmt;
+use std::io::{BufRead, Write};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::markup;
+use crate::models::{Comment, Preference, Thread};
+use crate::store::CommentStore;
+
+const FORMAT: &str = "risso";
+
+/// Version of the format, to be incremented on incompatible changes.
+pub const VERSION: u32 = 1;
+
+/// Number of comments read at once when exporting.
+const PAGE_SIZE: i64 = 500;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Record {
+    Header { format: String, version: u32 },
+    Preference { key: String, value: String },
+    Thread(Thread),
+    Comment(Comment),
+}
+
+/// Number of records that were exported or imported.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub preferences: usize,
+    pub threads: usize,
+    pub comments: usize,
+    /// Preferences that already existed in the target store with another value, and were kept.
+    pub kept_preferences: Vec<String>,
+}
+
+/// Write all data of `store` to `writer`. Threads are sorted by uri and comments by id.
+pub fn export<W: Write>(store: &dyn CommentStore, mut writer: W) -> Result<Summary, failure::Error> {
+    // In a transaction, to have a consistent view of the data
+    store.atomically(|| {
+        let mut summary = Summary::default();
+
+        let header = Record::Header {
+            format: FORMAT.to_owned(),
+            version: VERSION,
+        };
+        write_record(&mut writer, &header)?;
+
+        // The rendering cache isn't exported: neither is its fingerprint
+        for (key, value) in store.preferences()? {
+            if key == Preference::MARKUP_FINGERPRINT {
+                continue;
+            }
+            write_record(&mut writer, &Record::Preference { key, value })?;
+            summary.preferences += 1;
+        }
+
+        for thread in store.threads()? {
+            write_record(&mut writer, &Record::Thread(thread))?;
+            summary.threads += 1;
+        }
+
+        // Comments are listed most recent first: read pages from the end, and reverse them
+        let (_, total) = store.list(None, None, None, None, None, 0, 0)?;
+        let mut written = 0;
+
+        while written < total {
+            let limit = PAGE_SIZE.min(total - written);
+            let (page, _) = store.list(None, None, None, None, None, limit, total - written - limit)?;
+
+            for (comment, _) in page.into_iter().rev() {
+                write_record(&mut writer, &Record::Comment(comment))?;
+                summary.comments += 1;
+            }
+
+            written += limit;
+        }
+
+        writer.flush()?;
+        Ok(summary)
+    })
+}
+
+fn write_record<W: Write>(writer: &mut W, record: &Record) -> Result<(), failure::Error> {
+    serde_json::to_writer(&mut *writer, record)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Read an export into `store`, in a single transaction. Ids are kept, so the store must not have
+/// any threads or comments.
+pub fn import<R: BufRead>(reader: R, store: &dyn CommentStore) -> Result<Summary, failure::Error> {
+    store.atomically(|| {
+        if store.max_ids()? != (None, None) {
+            return Err(failure::err_msg("Data can only be imported into an empty database"));
+        }
+
+        let mut summary = Summary::default();
+        let mut has_header = false;
+
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let record: Record = serde_json::from_str(&line)
+                .map_err(|err| failure::err_msg(format!("Invalid record at line {}: {}", number + 1, err)))?;
+
+            match record {
+                Record::Header { format, version } => {
+                    if format != FORMAT || version != VERSION {
+                        return Err(failure::err_msg(format!(
+                            "Unsupported format '{}' version {}",
+                            format, version
+                        )));
+                    }
+                    has_header = true;
+                }
+                _ if !has_header => return Err(failure::err_msg("Not a Risso export: the header is missing")),
+                Record::Preference { key, value } => match store.preference(&key)? {
+                    None => {
+                        store.insert_preference(&key, &value)?;
+                        summary.preferences += 1;
+                    }
+                    Some(ref existing) if *existing == value => {}
+                    Some(_) => summary.kept_preferences.push(key),
+                },
+                Record::Thread(thread) => {
+                    store.restore_thread(&thread)?;
+                    summary.threads += 1;
+                }
+                Record::Comment(comment) => {
+                    store.restore_comment(&comment)?;
+                    summary.comments += 1;
+                }
+            }
+        }
+
+        if !has_header {
+            return Err(failure::err_msg("Not a Risso export: the file is empty"));
+        }
+
+        markup::invalidate(store)?;
+        Ok(summary)
+    })
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} threads, {} comments and {} preferences.",
+            self.threads, self.comments, self.preferences
+        )?;
+
+        if !self.kept_preferences.is_empty() {
+            writeln!(
+                f,
+                "Existing preferences that were kept: {}",
+                self.kept_preferences.join(", ")
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{NewCommentRow, NewThreadRow};
+    use crate::store::fixtures::comment_row;
+    use crate::store::{MemoryStore, Storage};
+
+    fn populate(store: &dyn CommentStore) -> Result<(), failure::Error> {
+        store.insert_preference("session-key", "secret")?;
+
+        for uri in vec!["/b", "/a"] {
+            let thread = store.insert_thread(&NewThreadRow { uri, title: "Title" })?;
+
+            let row = |parent: Option<i32>, created: f64| NewCommentRow {
+                parent,
+                created,
+                author: Some("Jane"),
+                website: Some("https://example.com"),
+                notification: true,
+                voters: &[1, 2, 3],
+                ..comment_row(thread.id, "Hello \"world\"\nand all")
+            };
+
+            let parent = store.insert_comment(&row(None, 1_500_000_000.123_456))?;
+            store.insert_comment(&row(Some(parent.id), 1_500_000_001.5))?;
+            store.vote(parent.id, true, &parent.voters, &[4, 5, 6])?;
+            store.update_comment(parent.id, "Edited", None, None)?;
+        }
+
+        Ok(())
+    }
+
+    fn export_all(storage: &dyn Storage) -> Vec<u8> {
+        let mut out = Vec::new();
+        storage.run(|store| export(store, &mut out)).unwrap();
+        out
+    }
+
+    #[test]
+    fn round_trip() {
+        let source: &dyn Storage = &MemoryStore::new();
+        source.run(populate).unwrap();
+        let exported = export_all(source);
+
+        let target: &dyn Storage = &MemoryStore::new();
+        let summary = target.run(|store| import(exported.as_slice(), store)).unwrap();
+        assert_eq!((1, 2, 4), (summary.preferences, summary.threads, summary.comments));
+
+        assert_eq!(
+            String::from_utf8(exported).unwrap(),
+            String::from_utf8(export_all(target)).unwrap()
+        );
+
+        target
+            .run(|store| {
+                let comment = store.comment(1)?.unwrap();
+                assert_eq!(1_500_000_000.123_456, comment.created.to_f64());
+                assert_eq!((1, "Edited"), (comment.likes, comment.text.as_str()));
+                assert!(comment.modified.is_some());
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    fn import_error(input: &[u8], storage: &dyn Storage) -> String {
+        storage.run(|store| import(input, store)).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn invalid_imports() {
+        let source: &dyn Storage = &MemoryStore::new();
+        source.run(populate).unwrap();
+        let exported = export_all(source);
+
+        // Not empty
+        assert_eq!(
+            "Data can only be imported into an empty database",
+            import_error(&exported, source)
+        );
+
+        let target: &dyn Storage = &MemoryStore::new();
+        assert_eq!(
+            "Not a Risso export: the header is missing",
+            import_error(br#"{"type":"preference","key":"a","value":"b"}"#, target)
+        );
+        assert_eq!(
+            "Unsupported format 'risso' version 2",
+            import_error(br#"{"type":"header","format":"risso","version":2}"#, target)
+        );
+        assert!(import_error(b"not json", target).starts_with("Invalid record at line 1"));
+    }
+}
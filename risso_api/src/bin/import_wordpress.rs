@@ -0,0 +1,34 @@
+//! Import a WordPress WXR export into the database of the configuration, and print a report.
+//!
+//! Usage: `import_wordpress <export.xml> [<config.toml>]`
+
+use std::fs::File;
+use std::io::BufReader;
+
+use risso_api::config::RissoConfig;
+use risso_api::context::ApiBuilder;
+
+fn main() -> Result<(), failure::Error> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let path = match args.first() {
+        Some(path) => path,
+        None => {
+            eprintln!("Usage: import_wordpress <export.xml> [<config.toml>]");
+            std::process::exit(1);
+        }
+    };
+
+    let config = RissoConfig::load(args.get(1).map(String::as_str))?;
+    let cnx = ApiBuilder::connect(&config)?;
+
+    if config.database.run_migrations {
+        risso_api::migrations::run_pending(&cnx)?;
+    }
+
+    let file = File::open(path).map_err(|err| failure::err_msg(format!("Cannot open {}: {}", path, err)))?;
+    let report = risso_api::import::wordpress::import(BufReader::new(file), &cnx)?;
+    print!("{}", report);
+
+    Ok(())
+}
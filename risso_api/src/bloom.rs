@@ -0,0 +1,116 @@
+//! A bloom filter of voter addresses, stored in the `comments.voters` column.
+//!
+//! It follows [Isso's implementation][1] bit for bit so that databases can be shared or migrated:
+//! a 256 bytes array, 11 probes per key, each probe being 11 consecutive bits of the key's
+//! SHA-256 hash (read as a big-endian integer, starting from the least significant bits).
+//!
+//! [1]: https://github.com/posativ/isso/blob/f2333d716d661a5ab1d0102b3f5890080267755a/isso/utils/__init__.py#L35
+
+use sha2::{Digest, Sha256};
+
+/// Size of the filter's bit array, in bytes.
+const SIZE: usize = 256;
+
+/// Number of probes per key.
+const K: usize = 11;
+
+/// Number of bits in the filter. Must be a power of 2 (probes are computed with a bit mask).
+const M: usize = SIZE * 8;
+
+pub struct Bloomfilter {
+    array: Vec<u8>,
+}
+
+impl Bloomfilter {
+    /// Create an empty filter.
+    pub fn new() -> Self {
+        Bloomfilter { array: vec![0; SIZE] }
+    }
+
+    /// Load a filter from its bit array.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut array = bytes.to_vec();
+        array.resize(SIZE, 0);
+        Bloomfilter { array }
+    }
+
+    /// Does the filter contain `key`? May return false positives, but never false negatives.
+    pub fn contains(&self, key: &str) -> bool {
+        probes(key).all(|i| self.array[i / 8] & (1 << (i % 8)) != 0)
+    }
+
+    /// Add a key to the filter.
+    pub fn add(&mut self, key: &str) {
+        for i in probes(key) {
+            self.array[i / 8] |= 1 << (i % 8);
+        }
+    }
+
+    /// The filter's bit array, as stored in the database.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.array
+    }
+}
+
+impl Default for Bloomfilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Bit positions for a key.
+fn probes(key: &str) -> impl Iterator<Item = usize> {
+    let hash = Sha256::digest(key.as_bytes());
+
+    // Bit `n` of the hash considered as a big-endian 256 bits integer
+    let bit = move |n: usize| ((hash[hash.len() - 1 - n / 8] >> (n % 8)) & 1) as usize;
+
+    (0..K).map(move |probe| (0..K).fold(0, |acc, b| acc | bit(probe * K + b) << b) & (M - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn isso_compatibility() {
+        // Computed with Isso's `Bloomfilter(iterable=["127.0.0.1"]).array`
+        let expected: Vec<(usize, u8)> = vec![
+            (1, 64),
+            (26, 16),
+            (32, 16),
+            (41, 2),
+            (52, 1),
+            (112, 1),
+            (116, 16),
+            (126, 32),
+            (147, 2),
+            (205, 64),
+            (208, 64),
+        ];
+
+        let mut bf = Bloomfilter::new();
+        bf.add("127.0.0.1");
+
+        let actual: Vec<(usize, u8)> = bf
+            .as_bytes()
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| **b != 0)
+            .map(|(i, b)| (i, *b))
+            .collect();
+
+        assert_eq!(SIZE, bf.as_bytes().len());
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn add_and_contains() {
+        let mut bf = Bloomfilter::new();
+        bf.add("127.0.0.1");
+
+        let bf = Bloomfilter::from_bytes(bf.as_bytes());
+        assert!(bf.contains("127.0.0.1"));
+        assert!(!bf.contains("127.0.0.2"));
+    }
+}
@@ -3,71 +3,168 @@
 // Republish diesel's manager so that server impls don't have to add it to their deps.
 pub use diesel::r2d2::ConnectionManager;
 use diesel::r2d2::Pool;
-use serde_derive::Deserialize;
 
 use futures::future::Future;
 
-use crate::logs::macros::*;
+use std::sync::Arc;
 
-#[derive(Deserialize)]
-struct ContextConfig {
-    db_path: String,
-    min_connections: u32,
-    max_connections: u32,
-}
+use crate::config::RissoConfig;
+use crate::guard::{PostingLocks, RateLimiter};
+use crate::logs::macros::*;
+use crate::models::Preference;
+use crate::store::{CommentStore, DieselStore, Storage};
 
-/// Single location where choose the actual database backend we're using.
-/// TODO: add compile-time feature to choose between `SQLite`, `PG` and `MySQL`
+/// Single location where choose the actual database backend we're using, with the `sqlite`
+/// (default), `postgres` or `mysql` cargo features.
 ///
+#[cfg(feature = "sqlite")]
 pub type Connection = diesel::sqlite::SqliteConnection;
+#[cfg(feature = "sqlite")]
 pub type DB = diesel::sqlite::Sqlite;
 
+#[cfg(feature = "postgres")]
+pub type Connection = diesel::pg::PgConnection;
+#[cfg(feature = "postgres")]
+pub type DB = diesel::pg::Pg;
+
+#[cfg(feature = "mysql")]
+pub type Connection = diesel::mysql::MysqlConnection;
+#[cfg(feature = "mysql")]
+pub type DB = diesel::mysql::Mysql;
+
+#[cfg(not(any(feature = "sqlite", feature = "postgres", feature = "mysql")))]
+compile_error!(
+    "One of the 'sqlite', 'postgres' or 'mysql' features must be enabled, e.g. \
+     `--no-default-features --features postgres`"
+);
+
+#[cfg(any(
+    all(feature = "sqlite", feature = "postgres"),
+    all(feature = "sqlite", feature = "mysql"),
+    all(feature = "postgres", feature = "mysql")
+))]
+compile_error!(
+    "Only one of the 'sqlite', 'postgres' or 'mysql' features can be enabled. 'sqlite' is the default: use \
+     `--no-default-features --features postgres` or `--no-default-features --features mysql`"
+);
+
 /// Base type from which `ApiContext` objects can be built. It must be held by the main thread, as it
 /// contains the main thread pool object from which individual `Executors` can be obtained.
 /// Dropping `ApiContextBootstrap` drops the pool.
 ///
 pub struct ApiBuilder {
-    pub cnx_pool: Pool<ConnectionManager<Connection>>,
+    pub storage: Arc<dyn Storage>,
     pub thread_pool: tokio_threadpool::ThreadPool,
     pub registry: prometheus::Registry,
+    config: Arc<RissoConfig>,
+    session_key: Arc<Vec<u8>>,
+    vote_limiter: Arc<RateLimiter>,
+    login_limiter: Arc<RateLimiter>,
+    posting_locks: Arc<PostingLocks>,
 }
 
 impl ApiBuilder {
+    /// Create a builder using the configured database.
     #[allow(clippy::new_ret_no_self)]
-    pub fn new() -> Result<Self, failure::Error> {
-        let config = crate::CONFIG.get::<ContextConfig>("database")?;
+    pub fn new(config: RissoConfig) -> Result<Self, failure::Error> {
+        let db_config = &config.database;
 
         info!(
             "Using database at {} with max {} connections.",
-            config.db_path, config.max_connections
+            db_config.db_path, db_config.max_connections
         );
 
-        let cnx_manager = ConnectionManager::<Connection>::new(config.db_path);
+        let cnx_manager = ConnectionManager::<Connection>::new(db_config.db_path.as_str());
 
         let cnx_pool = Pool::builder()
-            .max_size(config.max_connections)
-            .min_idle(Some(config.min_connections))
+            .max_size(db_config.max_connections)
+            .min_idle(Some(db_config.min_connections))
             .build(cnx_manager)?;
 
-        let thread_pool = tokio_threadpool::Builder::new()
+        if db_config.run_migrations {
+            for migration in crate::migrations::run_pending(&*cnx_pool.get()?)? {
+                info!("Applied migration {}_{}", migration.version, migration.name);
+            }
+        }
+
+        let pool_size = db_config.max_connections as usize;
+        Self::create(config, Arc::new(DieselStore::new(cnx_pool)), Some(pool_size))
+    }
+
+    /// Create a builder using an alternate storage, e.g. a `MemoryStore` for tests. The database
+    /// section of the configuration is ignored.
+    pub fn with_storage(config: RissoConfig, storage: Arc<dyn Storage>) -> Result<Self, failure::Error> {
+        Self::create(config, storage, None)
+    }
+
+    /// Create a builder whose thread pool has `pool_size` threads, or one per CPU if `None`.
+    fn create(
+        config: RissoConfig,
+        storage: Arc<dyn Storage>,
+        pool_size: Option<usize>,
+    ) -> Result<Self, failure::Error> {
+        let mut thread_pool = tokio_threadpool::Builder::new();
+        thread_pool
             .name_prefix("risso-api")
-            .keep_alive(Some(std::time::Duration::from_secs(30)))
-            .pool_size(config.max_connections as usize)
-            .build();
+            .keep_alive(Some(std::time::Duration::from_secs(30)));
+
+        if let Some(pool_size) = pool_size {
+            thread_pool.pool_size(pool_size);
+        }
+
+        let thread_pool = thread_pool.build();
 
         let registry = prometheus::Registry::new();
 
+        let session_key = Arc::new(storage.run(Self::session_key)?.into_bytes());
+
+        if let Some(count) = storage.run(|store| crate::markup::rerender_if_changed(&config.markup, store))? {
+            info!("Rendered {} comments with the current markup configuration.", count);
+        }
+
         Ok(Self {
-            cnx_pool,
+            storage,
             thread_pool,
             registry,
+            config: Arc::new(config),
+            session_key,
+            vote_limiter: Arc::new(RateLimiter::new()),
+            login_limiter: Arc::new(RateLimiter::new()),
+            posting_locks: Arc::new(PostingLocks::new()),
         })
     }
 
+    /// Open a single connection to the configured database, e.g. to manage it outside of a server.
+    pub fn connect(config: &RissoConfig) -> Result<Connection, failure::Error> {
+        Ok(<Connection as diesel::Connection>::establish(&config.database.db_path)?)
+    }
+
+    /// Get the secret used to sign tokens, creating it if it doesn't exist yet. Like in Isso, it is
+    /// stored in the database so that tokens survive server restarts.
+    fn session_key(store: &dyn CommentStore) -> Result<String, failure::Error> {
+        use rand::Rng;
+
+        if let Some(key) = store.preference(Preference::SESSION_KEY)? {
+            return Ok(key);
+        }
+
+        info!("Creating a new session key.");
+
+        let key = hex::encode(rand::thread_rng().gen::<[u8; 24]>());
+        store.insert_preference(Preference::SESSION_KEY, &key)?;
+
+        Ok(key)
+    }
+
     pub fn build(&self) -> ApiContext {
         ApiContext {
-            cnx_pool: self.cnx_pool.clone(),
+            storage: self.storage.clone(),
             executor: self.thread_pool.sender().clone(),
+            config: self.config.clone(),
+            session_key: self.session_key.clone(),
+            vote_limiter: self.vote_limiter.clone(),
+            login_limiter: self.login_limiter.clone(),
+            posting_locks: self.posting_locks.clone(),
         }
     }
 }
@@ -75,29 +172,72 @@ impl ApiBuilder {
 #[allow(clippy::stutter)]
 #[derive(Clone)]
 pub struct ApiContext {
-    cnx_pool: Pool<ConnectionManager<Connection>>,
+    storage: Arc<dyn Storage>,
     executor: tokio_threadpool::Sender,
+    config: Arc<RissoConfig>,
+    session_key: Arc<Vec<u8>>,
+    vote_limiter: Arc<RateLimiter>,
+    login_limiter: Arc<RateLimiter>,
+    posting_locks: Arc<PostingLocks>,
 }
 
 impl ApiContext {
+    /// The configuration. It is an `Arc` so that it can be moved to blocking operations.
+    pub fn config(&self) -> &Arc<RissoConfig> {
+        &self.config
+    }
+
+    /// The secret used to sign tokens.
+    pub fn session_key(&self) -> &[u8] {
+        &self.session_key
+    }
+
+    /// Votes of each address, shared by all contexts built from the same `ApiBuilder`.
+    pub(crate) fn vote_limiter(&self) -> &RateLimiter {
+        &self.vote_limiter
+    }
+
+    /// Failed admin logins of each address, shared like the votes.
+    pub(crate) fn login_limiter(&self) -> &RateLimiter {
+        &self.login_limiter
+    }
+
+    /// Addresses that are posting a comment, shared like the votes.
+    pub(crate) fn posting_locks(&self) -> &PostingLocks {
+        &self.posting_locks
+    }
+
     // https://github.com/diesel-rs/diesel/issues/399#issuecomment-360535059
 
-    /// Run a blocking operation on the database on the context's thread pool and return a future
-    pub fn spawn_db<F, T, E>(&self, f: F) -> impl Future<Item = T, Error = failure::Error>
+    /// Run a blocking operation on the store on the context's thread pool and return a future
+    pub fn spawn_store<F, T>(&self, f: F) -> impl Future<Item = T, Error = failure::Error>
     where
         T: Send + 'static,
-        E: std::error::Error + Send + Sync + 'static,
-        F: FnOnce(&Connection) -> Result<T, E> + Send + 'static,
+        F: FnOnce(&dyn CommentStore) -> Result<T, failure::Error> + Send + 'static,
     {
         use futures::sync::oneshot;
 
-        let pool = self.cnx_pool.clone();
-        oneshot::spawn_fn(
-            move || {
-                let cnx = pool.get().map_err(failure::Error::from)?;
-                f(&cnx).map_err(failure::Error::from)
-            },
-            &self.executor,
-        )
+        let storage = self.storage.clone();
+        oneshot::spawn_fn(move || storage.run(f), &self.executor)
+    }
+
+    /// Run a blocking operation on the context's thread pool without waiting for its result.
+    /// Errors are logged, as there is no one to report them to.
+    pub fn spawn_detached<F>(&self, f: F)
+    where
+        F: FnOnce() -> Result<(), failure::Error> + Send + 'static,
+    {
+        use futures::future::Executor;
+
+        let task = futures::lazy(move || {
+            if let Err(err) = f() {
+                error!("Background task failed: {}", err);
+            }
+            Ok(())
+        });
+
+        if self.executor.execute(task).is_err() {
+            error!("Could not run background task: thread pool is shut down");
+        }
     }
 }
@@ -15,10 +15,54 @@ use std;
 use std::io::Write;
 
 // See https://github.com/diesel-rs/diesel/issues/1781
+// Plain standard SQL, that all supported backends return as a BIGINT.
 pub fn count_star() -> SqlLiteral<BigInt> {
     diesel::dsl::sql::<diesel::sql_types::BigInt>("count(*)")
 }
 
+// Id of the last inserted row, for backends that don't support `INSERT ... RETURNING`.
+#[cfg(feature = "sqlite")]
+no_arg_sql_function!(last_insert_rowid, diesel::sql_types::Integer);
+
+#[cfg(feature = "mysql")]
+no_arg_sql_function!(last_insert_id, diesel::sql_types::Unsigned<diesel::sql_types::BigInt>);
+
+/// Id of the last row inserted on a connection. PostgreSQL uses `INSERT ... RETURNING` instead.
+#[cfg(feature = "sqlite")]
+pub fn inserted_id(cnx: &crate::context::Connection) -> diesel::QueryResult<i32> {
+    use diesel::RunQueryDsl;
+    diesel::select(last_insert_rowid).get_result(cnx)
+}
+
+/// Id of the last row inserted on a connection. PostgreSQL uses `INSERT ... RETURNING` instead.
+#[cfg(feature = "mysql")]
+pub fn inserted_id(cnx: &crate::context::Connection) -> diesel::QueryResult<i32> {
+    use diesel::RunQueryDsl;
+    diesel::select(last_insert_id)
+        .get_result::<u64>(cnx)
+        .map(|id| id as i32)
+}
+
+/// Move the id sequence of a table past its highest id, after rows were inserted with explicit ids.
+/// Only PostgreSQL needs it, other backends do it by themselves.
+#[cfg(feature = "postgres")]
+pub fn sync_sequence(cnx: &crate::context::Connection, table: &str) -> diesel::QueryResult<()> {
+    use diesel::RunQueryDsl;
+    diesel::sql_query(format!(
+        "SELECT setval(pg_get_serial_sequence('{0}', 'id'), (SELECT MAX(id) FROM {0}))",
+        table
+    ))
+    .execute(cnx)
+    .map(|_| ())
+}
+
+/// Move the id sequence of a table past its highest id, after rows were inserted with explicit ids.
+/// Only PostgreSQL needs it, other backends do it by themselves.
+#[cfg(not(feature = "postgres"))]
+pub fn sync_sequence(_cnx: &crate::context::Connection, _table: &str) -> diesel::QueryResult<()> {
+    Ok(())
+}
+
 /// A wrapper around Chrono's `DataTime<Utc>` to read the ISSO database, that encodes dates using
 /// a double containing fractional seconds since the Epoch (similar to what JavaScript does)
 
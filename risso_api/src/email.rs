@@ -0,0 +1,210 @@
+//! Emails sent by Risso, and their delivery through SMTP.
+
+use lettre::smtp::authentication::Credentials;
+use lettre::*;
+use lettre_email::{Email, EmailBuilder};
+use native_tls::TlsConnector;
+
+use crate::config::{SmtpConfig, SmtpSecurity};
+use crate::models::{Comment, CommentMode, Thread};
+
+/// Links included in admin emails to moderate a comment.
+pub struct ModerationLinks {
+    pub activate: String,
+    pub delete: String,
+}
+
+/// Notify the admin of a new comment.
+pub fn send_new_comment_email(
+    config: &SmtpConfig,
+    thread: &Thread,
+    comment: &Comment,
+    links: &ModerationLinks,
+) -> Result<(), failure::Error> {
+    let email = EmailBuilder::new()
+        .from(config.from.clone())
+        .to(config.to.clone())
+        .subject(format!("New comment posted on {}", thread_title(thread)))
+        .text(new_comment_body(thread, comment, links))
+        .build()?;
+
+    send(config, &email)
+}
+
+fn new_comment_body(thread: &Thread, comment: &Comment, links: &ModerationLinks) -> String {
+    let mut body = String::new();
+
+    body.push_str(comment.author.as_ref().map_or("Anonymous", String::as_str));
+    if let Some(ref email) = comment.email {
+        body.push_str(&format!(" <{}>", email));
+    }
+    if let Some(ref website) = comment.website {
+        body.push_str(&format!(" ({})", website));
+    }
+    body.push_str(&format!(" wrote on {}:\n\n", thread_title(thread)));
+
+    body.push_str(&comment.text);
+    body.push_str("\n\n---\n");
+
+    body.push_str(&format!("Thread: {}\n", thread.uri));
+    body.push_str(&format!("IP address: {}\n", comment.remote_addr));
+
+    if comment.mode == CommentMode::Pending as i32 {
+        body.push_str(&format!("Activate comment: {}\n", links.activate));
+    }
+    body.push_str(&format!("Delete comment: {}\n", links.delete));
+
+    body
+}
+
+/// Notify `recipient` of a new reply in a conversation they subscribed to.
+pub fn send_reply_notification(
+    config: &SmtpConfig,
+    thread: &Thread,
+    reply: &Comment,
+    recipient: &str,
+    unsubscribe_link: &str,
+) -> Result<(), failure::Error> {
+    let email = EmailBuilder::new()
+        .from(config.from.clone())
+        .to(recipient.to_owned())
+        .subject(format!("Re: New comment posted on {}", thread_title(thread)))
+        .text(reply_notification_body(thread, reply, unsubscribe_link))
+        .build()?;
+
+    send(config, &email)
+}
+
+fn reply_notification_body(thread: &Thread, reply: &Comment, unsubscribe_link: &str) -> String {
+    format!(
+        "{author} wrote on {title}:\n\n{text}\n\n---\nUnsubscribe from this conversation: {link}\n",
+        author = reply.author.as_ref().map_or("Anonymous", String::as_str),
+        title = thread_title(thread),
+        text = reply.text,
+        link = unsubscribe_link,
+    )
+}
+
+/// A thread's title, or its uri for threads created without a title.
+fn thread_title(thread: &Thread) -> &str {
+    if thread.title.is_empty() {
+        &thread.uri
+    } else {
+        &thread.title
+    }
+}
+
+/// Send an email through the configured SMTP server.
+pub fn send(config: &SmtpConfig, email: &Email) -> Result<(), failure::Error> {
+    let mut mailer = transport(config)?;
+
+    mailer.send(email).map(|_| ()).map_err(|err| err.into())
+}
+
+/// Create a SMTP transport for a configuration.
+fn transport(config: &SmtpConfig) -> Result<SmtpTransport, failure::Error> {
+    let tls_parameters = || -> Result<ClientTlsParameters, failure::Error> {
+        // The TLS domain must match the server's certificate
+        Ok(ClientTlsParameters::new(
+            config.host.clone(),
+            TlsConnector::builder()?.build()?,
+        ))
+    };
+
+    let security = match config.security {
+        SmtpSecurity::None => ClientSecurity::None,
+        SmtpSecurity::Starttls => ClientSecurity::Required(tls_parameters()?),
+        SmtpSecurity::Tls => ClientSecurity::Wrapper(tls_parameters()?),
+    };
+
+    let mut builder = SmtpTransport::builder((config.host.as_str(), config.port), security)?;
+
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dieselext::FloatDateTime;
+    use chrono::prelude::*;
+
+    fn comment(mode: CommentMode) -> Comment {
+        Comment {
+            thread_id: 1,
+            id: 42,
+            parent: None,
+            created: FloatDateTime(Utc::now()),
+            modified: None,
+            mode: mode as i32,
+            remote_addr: String::from("127.0.0.1"),
+            text: String::from("Nice post!"),
+            author: Some(String::from("Jane")),
+            email: Some(String::from("jane@example.com")),
+            website: None,
+            likes: 0,
+            dislikes: 0,
+            notification: false,
+            voters: Vec::new(),
+            hash: None,
+            html: None,
+            html_fingerprint: None,
+        }
+    }
+
+    fn thread() -> Thread {
+        Thread {
+            id: 1,
+            uri: String::from("/blog/post"),
+            title: String::from("My post"),
+        }
+    }
+
+    fn links() -> ModerationLinks {
+        ModerationLinks {
+            activate: String::from("http://risso/id/42/activate/key"),
+            delete: String::from("http://risso/id/42/delete/key"),
+        }
+    }
+
+    #[test]
+    fn new_comment_email_body() {
+        let body = new_comment_body(&thread(), &comment(CommentMode::Pending), &links());
+
+        assert!(body.starts_with("Jane <jane@example.com> wrote on My post:\n\nNice post!\n"));
+        assert!(body.contains("Thread: /blog/post\n"));
+        assert!(body.contains("Activate comment: http://risso/id/42/activate/key\n"));
+        assert!(body.contains("Delete comment: http://risso/id/42/delete/key\n"));
+
+        // Valid comments don't need to be activated
+        let body = new_comment_body(&thread(), &comment(CommentMode::Valid), &links());
+        assert!(!body.contains("Activate comment"));
+    }
+
+    #[test]
+    #[ignore] // Requires a SMTP sink, e.g. `python3 -m smtpd -n -c DebuggingServer localhost:1025`
+    fn send_to_local_smtp_sink() {
+        let config = SmtpConfig {
+            username: None,
+            password: None,
+            host: String::from("localhost"),
+            port: 1025,
+            security: SmtpSecurity::None,
+            to: String::from("admin@example.com"),
+            from: String::from("risso@example.com"),
+        };
+
+        let email = EmailBuilder::new()
+            .from(config.from.clone())
+            .to(config.to.clone())
+            .subject("Risso test")
+            .text(new_comment_body(&thread(), &comment(CommentMode::Pending), &links()))
+            .build()
+            .unwrap();
+
+        transport(&config).unwrap().send(&email).unwrap();
+    }
+}
@@ -0,0 +1,41 @@
+//! Errors returned by the API functions that are meaningful to the caller, i.e. that are caused
+//! by the request rather than by an internal failure.
+//!
+//! They travel inside a `failure::Error` like any other error: front-ends can use
+//! `err.downcast_ref::<ApiError>()` to find them and choose an appropriate response status. Any
+//! other error should be considered as an internal server error.
+
+use failure::Fail;
+
+#[derive(Debug, Fail)]
+pub enum ApiError {
+    /// The request failed validation.
+    #[fail(display = "Invalid request: {}", _0)]
+    Validation(#[cause] validator::ValidationErrors),
+
+    /// The request is syntactically valid, but inconsistent with the data it relates to.
+    #[fail(display = "Bad request: {}", _0)]
+    BadRequest(String),
+
+    /// The request requires authentication, e.g. a missing or unknown API token.
+    #[fail(display = "Unauthorized: {}", _0)]
+    Unauthorized(String),
+
+    /// The request isn't allowed, e.g. because of an invalid or expired token.
+    #[fail(display = "Forbidden: {}", _0)]
+    Forbidden(String),
+
+    /// The requested object doesn't exist.
+    #[fail(display = "Not found: {}", _0)]
+    NotFound(String),
+
+    /// A rate limit was exceeded. The request can be sent again after `retry_after` seconds.
+    #[fail(display = "Too many requests: {}", message)]
+    TooManyRequests { message: String, retry_after: u64 },
+}
+
+impl From<validator::ValidationErrors> for ApiError {
+    fn from(err: validator::ValidationErrors) -> Self {
+        ApiError::Validation(err)
+    }
+}
@@ -0,0 +1,150 @@
+//! Atom feed of the comments on a thread, modelled after [Isso's feed][1].
+//!
+//! [1]: https://github.com/posativ/isso/blob/master/isso/views/comments.py
+
+use chrono::prelude::*;
+use chrono::SecondsFormat;
+
+use crate::models::Comment;
+
+/// Build an Atom 1.0 document for the comments on `uri`.
+///
+/// `base` is the url of the site the thread belongs to, used to build links to comments, and
+/// `render` returns the html of a comment.
+pub fn atom_feed<F>(base: &str, uri: &str, comments: &[Comment], render: F) -> String
+where
+    F: Fn(&Comment) -> String,
+{
+    let base = base.trim_end_matches('/');
+    let hostname = hostname(base);
+
+    let entry_id = |thread_id: i32, id: i32| format!("tag:{},2018:/isso/{}/{}", hostname, thread_id, id);
+    let entry_link = |id: i32| format!("{}{}#isso-{}", base, uri, id);
+
+    // Comments are sorted by descending id: the first one is the most recent
+    let updated = comments
+        .first()
+        .map_or_else(Utc::now, |comment| *comment.modified.unwrap_or(comment.created));
+
+    let mut xml = String::new();
+    xml.push_str(r#"<?xml version="1.0" encoding="utf-8"?>"#);
+    xml.push_str(r#"<feed xmlns="http://www.w3.org/2005/Atom" xmlns:thr="http://purl.org/syndication/thread/1.0">"#);
+
+    push_element(&mut xml, "id", &format!("tag:{},2018:/isso/thread{}", hostname, uri));
+    push_element(&mut xml, "title", &format!("Comments for {}{}", hostname, uri));
+    push_element(&mut xml, "updated", &format_date(updated));
+
+    for comment in comments {
+        xml.push_str("<entry>");
+
+        push_element(&mut xml, "id", &entry_id(comment.thread_id, comment.id));
+        push_element(&mut xml, "title", &format!("Comment #{}", comment.id));
+        push_element(
+            &mut xml,
+            "updated",
+            &format_date(*comment.modified.unwrap_or(comment.created)),
+        );
+
+        xml.push_str("<author>");
+        push_element(
+            &mut xml,
+            "name",
+            comment.author.as_ref().map_or("Anonymous", String::as_str),
+        );
+        xml.push_str("</author>");
+
+        xml.push_str(&format!(r#"<link href="{}"/>"#, escape(&entry_link(comment.id))));
+
+        xml.push_str(r#"<content type="html">"#);
+        xml.push_str(&escape(&render(comment)));
+        xml.push_str("</content>");
+
+        if let Some(parent) = comment.parent {
+            xml.push_str(&format!(
+                r#"<thr:in-reply-to ref="{}" href="{}"/>"#,
+                escape(&entry_id(comment.thread_id, parent)),
+                escape(&entry_link(parent))
+            ));
+        }
+
+        xml.push_str("</entry>");
+    }
+
+    xml.push_str("</feed>");
+    xml
+}
+
+fn push_element(xml: &mut String, name: &str, text: &str) {
+    xml.push_str(&format!("<{}>{}</{}>", name, escape(text), name));
+}
+
+fn format_date(date: DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// The host name in a url, or the url itself if it has no scheme.
+fn hostname(url: &str) -> &str {
+    let without_scheme = url.splitn(2, "://").nth(1).unwrap_or(url);
+    without_scheme.split('/').next().unwrap_or(without_scheme)
+}
+
+/// Escape text to be used in XML content or attribute values.
+fn escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '&' => result.push_str("&amp;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&apos;"),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dieselext::FloatDateTime;
+
+    fn comment(id: i32, parent: Option<i32>, text: &str) -> Comment {
+        Comment {
+            thread_id: 1,
+            id,
+            parent,
+            created: FloatDateTime(Utc.ymd(2018, 12, 23).and_hms(10, 0, id as u32)),
+            modified: None,
+            mode: 1,
+            remote_addr: String::from("127.0.0.1"),
+            text: String::from(text),
+            author: Some(String::from("Jane & John")),
+            email: None,
+            website: None,
+            likes: 0,
+            dislikes: 0,
+            notification: false,
+            voters: Vec::new(),
+            hash: None,
+            html: None,
+            html_fingerprint: None,
+        }
+    }
+
+    #[test]
+    fn build_feed() {
+        let comments = vec![comment(2, Some(1), "<b>Reply</b>"), comment(1, None, "First")];
+        let feed = atom_feed("https://example.com/", "/blog/post", &comments, |comment| {
+            comment.text.clone()
+        });
+
+        assert!(feed.contains("<id>tag:example.com,2018:/isso/thread/blog/post</id>"));
+        assert!(feed.contains("<updated>2018-12-23T10:00:02Z</updated>"));
+        assert!(feed.contains("<id>tag:example.com,2018:/isso/1/2</id>"));
+        assert!(feed.contains("<name>Jane &amp; John</name>"));
+        assert!(feed.contains(r#"<link href="https://example.com/blog/post#isso-2"/>"#));
+        assert!(feed.contains(r#"<content type="html">&lt;b&gt;Reply&lt;/b&gt;</content>"#));
+        assert!(feed.contains(r#"<thr:in-reply-to ref="tag:example.com,2018:/isso/1/1""#));
+    }
+}
@@ -0,0 +1,374 @@
+//! Limits on what a single address can post, modelled after [Isso's guard][1]:
+//! - a number of comments per minute,
+//! - a number of top-level comments per thread,
+//! - no replies to one's own comment while it can still be edited, a common way to bump it,
+//! - a number of votes per minute. Votes aren't stored individually, so they are counted in memory
+//!   by each server process.
+//!
+//! Failed logins to the admin interface are also counted in memory, to slow down password guessing.
+//!
+//! Comments are counted in the database, so the checks and the insertion of a comment must not
+//! interleave with another comment from the same address: `PostingLocks` serializes them within a
+//! server process.
+//!
+//! A limit of 0 disables the corresponding check.
+//!
+//! [1]: https://github.com/posativ/isso/blob/master/isso/ext/guard.py
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::RissoConfig;
+use crate::errors::ApiError;
+use crate::models::Comment;
+use crate::store::CommentStore;
+
+/// Period of rate limits, in seconds.
+const PERIOD: u64 = 60;
+
+/// Number of addresses above which the vote limiter forgets the ones that didn't vote recently.
+const PURGE_THRESHOLD: usize = 10_000;
+
+/// Check that `remote_addr` can post a comment on the thread `thread_id`, replying to `parent` if
+/// any. `now` is the creation date of the new comment. The caller must hold the lock of
+/// `remote_addr` from `PostingLocks` until the comment is inserted.
+pub fn check_comment(
+    config: &RissoConfig,
+    store: &dyn CommentStore,
+    remote_addr: &str,
+    thread_id: i32,
+    parent: Option<&Comment>,
+    now: f64,
+) -> Result<(), failure::Error> {
+    let guard = &config.guard;
+    if !guard.enabled {
+        return Ok(());
+    }
+
+    if guard.ratelimit > 0 {
+        let limit = guard.ratelimit as usize;
+        let recent = store.comments_by_addr(remote_addr, now - PERIOD as f64, None)?;
+
+        if recent.len() >= limit {
+            // Comments are sorted oldest first: wait until enough of them are out of the period
+            let expires = recent[recent.len() - limit].created.to_f64() + PERIOD as f64;
+            return Err(too_many_requests(format!("No more than {} comments per minute", limit), expires - now).into());
+        }
+    }
+
+    if guard.direct_reply > 0 && parent.is_none() {
+        let direct = store
+            .comments_by_addr(remote_addr, 0.0, Some(thread_id))?
+            .iter()
+            .filter(|comment| comment.parent.is_none())
+            .count();
+
+        if direct >= guard.direct_reply as usize {
+            return Err(ApiError::Forbidden(format!(
+                "No more than {} top-level comments on a thread",
+                guard.direct_reply
+            ))
+            .into());
+        }
+    }
+
+    if let Some(parent) = parent {
+        let editable = now - parent.created.to_f64() < config.general.max_age as f64;
+
+        if !guard.reply_to_self && editable && parent.remote_addr == remote_addr {
+            return Err(ApiError::Forbidden(String::from(
+                "Cannot reply to your own comment while it can still be edited",
+            ))
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that `remote_addr` can vote, and count the vote.
+pub fn check_vote(config: &RissoConfig, limiter: &RateLimiter, remote_addr: &str) -> Result<(), ApiError> {
+    if config.guard.enabled {
+        limiter.check(remote_addr, config.guard.vote_ratelimit, "votes", Instant::now())
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that `remote_addr` can try to log in, i.e. that it didn't fail too many times recently.
+/// Failed logins are limited even if the guard is disabled, as it only applies to posting.
+pub fn check_login(config: &RissoConfig, limiter: &RateLimiter, remote_addr: &str) -> Result<(), ApiError> {
+    limiter.peek(
+        remote_addr,
+        config.admin.login_ratelimit,
+        "failed logins",
+        Instant::now(),
+    )
+}
+
+/// Count a failed login from `remote_addr`.
+pub fn failed_login(limiter: &RateLimiter, remote_addr: &str) {
+    limiter.record(remote_addr, Instant::now());
+}
+
+fn too_many_requests(message: String, retry_after: f64) -> ApiError {
+    ApiError::TooManyRequests {
+        message,
+        retry_after: retry_after.ceil().max(1.0) as u64,
+    }
+}
+
+/// Addresses that are posting a comment.
+#[derive(Default)]
+pub struct PostingLocks {
+    posting: Mutex<HashSet<String>>,
+    released: Condvar,
+}
+
+impl PostingLocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wait until no other comment is being posted from `remote_addr`. It is released when the
+    /// returned guard is dropped.
+    pub fn lock(&self, remote_addr: &str) -> PostingLock<'_> {
+        let mut posting = self.posting.lock().unwrap_or_else(|err| err.into_inner());
+        while posting.contains(remote_addr) {
+            posting = self.released.wait(posting).unwrap_or_else(|err| err.into_inner());
+        }
+        posting.insert(remote_addr.to_owned());
+
+        PostingLock {
+            locks: self,
+            remote_addr: remote_addr.to_owned(),
+        }
+    }
+}
+
+/// See `PostingLocks::lock`.
+pub struct PostingLock<'a> {
+    locks: &'a PostingLocks,
+    remote_addr: String,
+}
+
+impl<'a> Drop for PostingLock<'a> {
+    fn drop(&mut self) {
+        let mut posting = self.locks.posting.lock().unwrap_or_else(|err| err.into_inner());
+        posting.remove(&self.remote_addr);
+        self.locks.released.notify_all();
+    }
+}
+
+/// Number of events of each address during the last period, kept in memory.
+#[derive(Default)]
+pub struct RateLimiter {
+    events: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record an event for `remote_addr` at `now`, unless it already had `limit` events during the
+    /// period. `what` names the events in the error message.
+    fn check(&self, remote_addr: &str, limit: u32, what: &str, now: Instant) -> Result<(), ApiError> {
+        if limit == 0 {
+            return Ok(());
+        }
+
+        let mut events = self.events.lock().unwrap_or_else(|err| err.into_inner());
+        let times = recent_events(&mut events, remote_addr, now);
+
+        check_limit(times, limit, what, now)?;
+        times.push_back(now);
+        Ok(())
+    }
+
+    /// Check that `remote_addr` had less than `limit` events during the period, without recording one.
+    fn peek(&self, remote_addr: &str, limit: u32, what: &str, now: Instant) -> Result<(), ApiError> {
+        if limit == 0 {
+            return Ok(());
+        }
+
+        let mut events = self.events.lock().unwrap_or_else(|err| err.into_inner());
+        check_limit(recent_events(&mut events, remote_addr, now), limit, what, now)
+    }
+
+    /// Record an event for `remote_addr` at `now`, whatever the limit.
+    fn record(&self, remote_addr: &str, now: Instant) {
+        let mut events = self.events.lock().unwrap_or_else(|err| err.into_inner());
+        recent_events(&mut events, remote_addr, now).push_back(now);
+    }
+}
+
+/// The events of `remote_addr` during the period before `now`, older ones being removed.
+fn recent_events<'a>(
+    events: &'a mut HashMap<String, VecDeque<Instant>>,
+    remote_addr: &str,
+    now: Instant,
+) -> &'a mut VecDeque<Instant> {
+    let is_recent = |time: &Instant| now.duration_since(*time) < Duration::from_secs(PERIOD);
+
+    if events.len() >= PURGE_THRESHOLD {
+        events.retain(|_, times| times.back().map_or(false, is_recent));
+    }
+
+    let times = events.entry(remote_addr.to_owned()).or_insert_with(VecDeque::new);
+    while times.front().map_or(false, |time| !is_recent(time)) {
+        times.pop_front();
+    }
+
+    times
+}
+
+fn check_limit(times: &VecDeque<Instant>, limit: u32, what: &str, now: Instant) -> Result<(), ApiError> {
+    let limit = limit as usize;
+    if times.len() < limit {
+        return Ok(());
+    }
+
+    let wait = Duration::from_secs(PERIOD) - now.duration_since(times[times.len() - limit]);
+    Err(too_many_requests(
+        format!("No more than {} {} per minute", limit, what),
+        wait.as_secs() as f64 + f64::from(wait.subsec_nanos()) / 1e9,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{NewCommentRow, NewThreadRow};
+    use crate::store::fixtures::comment_row;
+    use crate::store::{MemoryStore, Storage};
+
+    fn retry_after(result: Result<(), failure::Error>) -> u64 {
+        match result.map_err(|err| err.downcast::<ApiError>()) {
+            Err(Ok(ApiError::TooManyRequests { retry_after, .. })) => retry_after,
+            _ => panic!("Expected a TooManyRequests error"),
+        }
+    }
+
+    fn is_forbidden(result: Result<(), failure::Error>) -> bool {
+        match result.map_err(|err| err.downcast::<ApiError>()) {
+            Err(Ok(ApiError::Forbidden(_))) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn comments() {
+        let mut config = RissoConfig::default();
+        config.guard.ratelimit = 2;
+        config.guard.direct_reply = 3;
+        config.general.max_age = 900;
+
+        let storage: &dyn Storage = &MemoryStore::new();
+        storage
+            .run(|store| {
+                let thread = store.insert_thread(&NewThreadRow { uri: "/", title: "" })?;
+                let post = |remote_addr: &str, parent: Option<i32>, created: f64| {
+                    store.insert_comment(&NewCommentRow {
+                        parent,
+                        created,
+                        remote_addr,
+                        ..comment_row(thread.id, "Hello")
+                    })
+                };
+                let check = |remote_addr: &str, parent: Option<&Comment>, now: f64| {
+                    check_comment(&config, store, remote_addr, thread.id, parent, now)
+                };
+
+                let first = post("10.0.0.1", None, 1000.0)?;
+                assert!(check("10.0.0.1", None, 1010.0).is_ok());
+                post("10.0.0.1", None, 1020.0)?;
+
+                // Rate limit, until the first comment is more than a minute old
+                assert_eq!(50, retry_after(check("10.0.0.1", None, 1010.5)));
+                assert!(check("10.0.0.2", None, 1010.5).is_ok());
+
+                // Direct replies
+                post("10.0.0.1", None, 1100.0)?;
+                assert!(is_forbidden(check("10.0.0.1", None, 1200.0)));
+
+                // Replies to one's own comment, while it can be edited
+                assert!(is_forbidden(check("10.0.0.1", Some(&first), 1200.0)));
+                assert!(check("10.0.0.2", Some(&first), 1200.0).is_ok());
+                assert!(check("10.0.0.1", Some(&first), 2000.0).is_ok());
+
+                let mut disabled = config.clone();
+                disabled.guard.enabled = false;
+                assert!(check_comment(&disabled, store, "10.0.0.1", thread.id, None, 1010.5).is_ok());
+
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn votes() {
+        let limiter = RateLimiter::new();
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        assert!(limiter.check("10.0.0.1", 2, "votes", at(0)).is_ok());
+        assert!(limiter.check("10.0.0.1", 2, "votes", at(10)).is_ok());
+        assert!(limiter.check("10.0.0.2", 2, "votes", at(10)).is_ok());
+
+        match limiter.check("10.0.0.1", 2, "votes", at(20)) {
+            Err(ApiError::TooManyRequests { retry_after, .. }) => assert_eq!(40, retry_after),
+            _ => panic!("Expected a TooManyRequests error"),
+        }
+
+        assert!(limiter.check("10.0.0.1", 2, "votes", at(60)).is_ok());
+        assert!(limiter.check("10.0.0.1", 0, "votes", at(60)).is_ok());
+    }
+
+    #[test]
+    fn failed_logins() {
+        let limiter = RateLimiter::new();
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        // Only failures are counted
+        for _ in 0..3 {
+            assert!(limiter.peek("10.0.0.1", 2, "failed logins", at(0)).is_ok());
+        }
+
+        limiter.record("10.0.0.1", at(0));
+        limiter.record("10.0.0.1", at(5));
+        assert!(limiter.peek("10.0.0.1", 2, "failed logins", at(10)).is_err());
+        assert!(limiter.peek("10.0.0.2", 2, "failed logins", at(10)).is_ok());
+        assert!(limiter.peek("10.0.0.1", 2, "failed logins", at(61)).is_ok());
+    }
+
+    #[test]
+    fn posting_locks() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let locks = Arc::new(PostingLocks::new());
+        let posted = Arc::new(AtomicBool::new(false));
+
+        let lock = locks.lock("10.0.0.1");
+        // Other addresses aren't blocked
+        drop(locks.lock("10.0.0.2"));
+
+        let other = {
+            let (locks, posted) = (locks.clone(), posted.clone());
+            std::thread::spawn(move || {
+                let _lock = locks.lock("10.0.0.1");
+                posted.store(true, Ordering::SeqCst);
+            })
+        };
+
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!posted.load(Ordering::SeqCst));
+
+        drop(lock);
+        other.join().unwrap();
+        assert!(posted.load(Ordering::SeqCst));
+    }
+}
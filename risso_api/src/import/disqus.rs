@@ -0,0 +1,484 @@
+//! Import of a Disqus XML export.
+//!
+//! Threads are created from the path of their link, and posts become comments. Like in Isso, replies
+//! to a reply are attached to the top-level comment. Deleted posts are only imported if they are a
+//! top-level post with replies, as soft-deleted comments without their text and author. Spam is put
+//! in the moderation queue.
+//!
+//! Imports can be run again, e.g. with a more recent export: a post is considered already imported
+//! if its thread has a comment created at the same second by the same author.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io::BufRead;
+
+use chrono::{DateTime, Utc};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+use crate::bloom;
+use crate::dieselext::FloatDateTime;
+use crate::markup;
+use crate::models::{CommentMode, NewCommentRow, NewThreadRow};
+use crate::store::CommentStore;
+
+use super::{existing_comment, link_path, mode_name, non_empty, top_level};
+
+#[derive(Debug, Default)]
+struct DisqusThread {
+    id: String,
+    link: String,
+    title: String,
+}
+
+#[derive(Debug, Default)]
+struct DisqusPost {
+    id: String,
+    thread: String,
+    parent: Option<String>,
+    message: String,
+    created_at: String,
+    is_deleted: bool,
+    is_spam: bool,
+    author_name: String,
+    author_email: String,
+    ip_address: String,
+}
+
+#[derive(Debug, Default)]
+struct Export {
+    threads: Vec<DisqusThread>,
+    posts: Vec<DisqusPost>,
+}
+
+/// What was imported, and what needs to be checked. Posts are identified by their Disqus id.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub threads: usize,
+    pub comments: usize,
+    /// Posts that were imported by a previous run.
+    pub existing: usize,
+    /// Number of imported comments for each mode.
+    pub modes: BTreeMap<i32, usize>,
+    /// Posts whose parent cannot be found. They are imported as top-level comments.
+    pub orphans: Vec<String>,
+    /// Posts that were not imported, and why.
+    pub skipped: Vec<(String, String)>,
+}
+
+/// Import a Disqus export into `store`, in a single transaction.
+pub fn import<R: BufRead>(reader: R, store: &dyn CommentStore) -> Result<Report, failure::Error> {
+    let export = parse(reader)?;
+    store.atomically(|| copy(store, &export))
+}
+
+fn parse<R: BufRead>(reader: R) -> Result<Export, failure::Error> {
+    let mut reader = Reader::from_reader(reader);
+    reader.trim_text(true);
+
+    let mut export = Export::default();
+    let mut path = Vec::new();
+    let mut buf = Vec::new();
+
+    loop {
+        let event = reader.read_event(&mut buf).map_err(|err| {
+            failure::err_msg(format!("Invalid XML at position {}: {}", reader.buffer_position(), err))
+        })?;
+
+        match event {
+            Event::Start(ref e) => {
+                start_element(&mut export, &path, e);
+                path.push(String::from_utf8_lossy(e.name()).into_owned());
+            }
+            Event::Empty(ref e) => start_element(&mut export, &path, e),
+            Event::End(_) => {
+                path.pop();
+            }
+            Event::Text(ref e) => text(&mut export, &path, &e.unescape_and_decode(&reader)?),
+            Event::CData(ref e) => text(&mut export, &path, &reader.decode(e)),
+            Event::Eof => break,
+            _ => (),
+        }
+
+        buf.clear();
+    }
+
+    Ok(export)
+}
+
+/// The `dsq:id` attribute that identifies threads and posts.
+fn dsq_id(e: &BytesStart) -> String {
+    e.attributes()
+        .filter_map(Result::ok)
+        .find(|attr| attr.key == b"dsq:id")
+        .map(|attr| String::from_utf8_lossy(&attr.value).into_owned())
+        .unwrap_or_default()
+}
+
+fn start_element(export: &mut Export, path: &[String], e: &BytesStart) {
+    let path = path.iter().map(String::as_str).collect::<Vec<_>>();
+
+    match (path.as_slice(), e.name()) {
+        (["disqus"], b"thread") => export.threads.push(DisqusThread {
+            id: dsq_id(e),
+            ..DisqusThread::default()
+        }),
+        (["disqus"], b"post") => export.posts.push(DisqusPost {
+            id: dsq_id(e),
+            ..DisqusPost::default()
+        }),
+        (["disqus", "post"], b"thread") => {
+            if let Some(post) = export.posts.last_mut() {
+                post.thread = dsq_id(e);
+            }
+        }
+        (["disqus", "post"], b"parent") => {
+            if let Some(post) = export.posts.last_mut() {
+                post.parent = Some(dsq_id(e));
+            }
+        }
+        _ => (),
+    }
+}
+
+fn text(export: &mut Export, path: &[String], text: &str) {
+    let path = path.iter().map(String::as_str).collect::<Vec<_>>();
+
+    if path.len() > 1 && path[1] == "thread" {
+        if let Some(thread) = export.threads.last_mut() {
+            match &path[2..] {
+                ["link"] => thread.link.push_str(text),
+                ["title"] => thread.title.push_str(text),
+                _ => (),
+            }
+        }
+    } else if path.len() > 1 && path[1] == "post" {
+        if let Some(post) = export.posts.last_mut() {
+            match &path[2..] {
+                ["message"] => post.message.push_str(text),
+                ["createdAt"] => post.created_at.push_str(text),
+                ["isDeleted"] => post.is_deleted = text == "true",
+                ["isSpam"] => post.is_spam = text == "true",
+                ["author", "name"] => post.author_name.push_str(text),
+                ["author", "email"] => post.author_email.push_str(text),
+                ["ipAddress"] => post.ip_address.push_str(text),
+                _ => (),
+            }
+        }
+    }
+}
+
+fn copy(store: &dyn CommentStore, export: &Export) -> Result<Report, failure::Error> {
+    let mut report = Report::default();
+
+    let threads = export
+        .threads
+        .iter()
+        .map(|t| (t.id.as_str(), t))
+        .collect::<HashMap<_, _>>();
+    let parents = export
+        .posts
+        .iter()
+        .map(|p| (p.id.as_str(), p.parent.as_ref().map(String::as_str)))
+        .collect::<HashMap<_, _>>();
+
+    // Top-level posts that have replies. Deleted replies are not imported.
+    let with_replies = export
+        .posts
+        .iter()
+        .filter(|p| !p.is_deleted)
+        .map(|p| (top_level(&parents, &p.id), p.id.as_str()))
+        .filter(|(root_id, id)| root_id != id)
+        .map(|(root_id, _)| root_id)
+        .collect::<HashSet<_>>();
+
+    // Import top-level posts first, so that replies can find their parent
+    let mut ordered = export
+        .posts
+        .iter()
+        .map(|p| (top_level(&parents, &p.id), p))
+        .collect::<Vec<_>>();
+    ordered.sort_by_key(|(root_id, post)| *root_id != post.id.as_str());
+
+    // Disqus post id -> comment id
+    let mut comment_ids = HashMap::new();
+
+    for (root_id, post) in ordered {
+        let skip = |reason: &str| (post.id.clone(), reason.to_owned());
+
+        // Like deleted comments, deleted posts are only kept as the parent of other comments
+        if post.is_deleted && (root_id != post.id.as_str() || !with_replies.contains(root_id)) {
+            report.skipped.push(skip("deleted"));
+            continue;
+        }
+
+        let thread = match threads.get(post.thread.as_str()) {
+            Some(thread) if !thread.link.is_empty() => thread,
+            _ => {
+                report.skipped.push(skip("unknown thread"));
+                continue;
+            }
+        };
+
+        let created = match DateTime::parse_from_rfc3339(&post.created_at) {
+            Ok(date) => FloatDateTime(date.with_timezone(&Utc)).to_f64(),
+            Err(_) => {
+                report.skipped.push(skip("invalid date"));
+                continue;
+            }
+        };
+
+        let uri = link_path(&thread.link);
+        let (text, author) = if post.is_deleted {
+            ("", None)
+        } else {
+            (post.message.as_str(), non_empty(&post.author_name))
+        };
+        let email = non_empty(&post.author_email);
+
+        if let Some(id) = existing_comment(store, uri, created, author, email)? {
+            comment_ids.insert(post.id.as_str(), id);
+            report.existing += 1;
+            continue;
+        }
+
+        let parent = if root_id == post.id.as_str() {
+            None
+        } else {
+            comment_ids.get(root_id).cloned()
+        };
+
+        let parent_missing = post
+            .parent
+            .as_ref()
+            .map_or(false, |parent| !parents.contains_key(parent.as_str()));
+
+        if parent_missing || (root_id != post.id.as_str() && parent.is_none()) {
+            report.orphans.push(post.id.clone());
+        }
+
+        let thread_id = match store.thread_by_uri(uri)? {
+            Some(thread) => thread.id,
+            None => {
+                report.threads += 1;
+                store
+                    .insert_thread(&NewThreadRow {
+                        uri,
+                        title: &thread.title,
+                    })?
+                    .id
+            }
+        };
+
+        let mode = if post.is_deleted {
+            CommentMode::SoftDeleted as i32
+        } else if post.is_spam {
+            CommentMode::Pending as i32
+        } else {
+            CommentMode::Valid as i32
+        };
+
+        let mut voters = bloom::Bloomfilter::new();
+        voters.add(&post.ip_address);
+
+        let comment = store.insert_comment(&NewCommentRow {
+            thread_id,
+            parent,
+            created,
+            mode,
+            remote_addr: &post.ip_address,
+            text,
+            author,
+            email,
+            website: None,
+            notification: false,
+            voters: voters.as_bytes(),
+        })?;
+
+        comment_ids.insert(post.id.as_str(), comment.id);
+        *report.modes.entry(mode).or_insert(0) += 1;
+        report.comments += 1;
+    }
+
+    markup::invalidate(store)?;
+    Ok(report)
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Imported {} threads and {} comments. {} comments were already imported.",
+            self.threads, self.comments, self.existing
+        )?;
+
+        let modes = self
+            .modes
+            .iter()
+            .map(|(mode, count)| format!("{}: {}", mode_name(*mode), count))
+            .collect::<Vec<_>>();
+        writeln!(f, "Comments per mode: {}", modes.join(", "))?;
+
+        if !self.orphans.is_empty() {
+            writeln!(
+                f,
+                "Posts with a missing parent, imported as top-level: {}",
+                self.orphans.join(", ")
+            )?;
+        }
+        for (id, reason) in &self.skipped {
+            writeln!(f, "Skipped post {}: {}", id, reason)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RissoConfig;
+    use crate::store::fixtures::memory_api;
+    use crate::store::{MemoryStore, Storage};
+
+    const EXPORT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<disqus xmlns="http://disqus.com" xmlns:dsq="http://disqus.com/disqus-internals">
+  <category dsq:id="1"><forum>blog</forum><title>General</title></category>
+  <thread dsq:id="10">
+    <link>https://example.com/blog/hello/?utm_source=feed</link>
+    <title>Hello &amp; welcome</title>
+    <author><name>Admin</name></author>
+  </thread>
+  <post dsq:id="102">
+    <message><![CDATA[<p>Reply to a reply</p>]]></message>
+    <createdAt>2018-01-01T12:02:00Z</createdAt>
+    <isDeleted>false</isDeleted>
+    <isSpam>false</isSpam>
+    <author><email>john@example.com</email><name>John</name></author>
+    <ipAddress>10.0.0.2</ipAddress>
+    <thread dsq:id="10" />
+    <parent dsq:id="101" />
+  </post>
+  <post dsq:id="100">
+    <message><![CDATA[<p>First</p>]]></message>
+    <createdAt>2018-01-01T12:00:00Z</createdAt>
+    <isDeleted>false</isDeleted>
+    <isSpam>false</isSpam>
+    <author><email>jane@example.com</email><name>Jane</name></author>
+    <ipAddress>10.0.0.1</ipAddress>
+    <thread dsq:id="10" />
+  </post>
+  <post dsq:id="101">
+    <message><![CDATA[<p>Reply</p>]]></message>
+    <createdAt>2018-01-01T12:01:00Z</createdAt>
+    <isDeleted>true</isDeleted>
+    <isSpam>false</isSpam>
+    <author><name>Anonymous</name></author>
+    <ipAddress>10.0.0.3</ipAddress>
+    <thread dsq:id="10" />
+    <parent dsq:id="100" />
+  </post>
+  <post dsq:id="103">
+    <message>Buy now</message>
+    <createdAt>2018-01-02T00:00:00Z</createdAt>
+    <isDeleted>false</isDeleted>
+    <isSpam>true</isSpam>
+    <author><name>Spammer</name></author>
+    <ipAddress>10.0.0.4</ipAddress>
+    <thread dsq:id="10" />
+    <parent dsq:id="99" />
+  </post>
+  <post dsq:id="104">
+    <message>Lost</message>
+    <createdAt>2018-01-02T00:00:00Z</createdAt>
+    <thread dsq:id="11" />
+  </post>
+  <post dsq:id="105">
+    <message>Deleted secret</message>
+    <createdAt>2018-01-03T00:00:00Z</createdAt>
+    <isDeleted>true</isDeleted>
+    <isSpam>false</isSpam>
+    <author><email>ghost@example.com</email><name>Ghost</name></author>
+    <ipAddress>10.0.0.5</ipAddress>
+    <thread dsq:id="10" />
+  </post>
+  <post dsq:id="106">
+    <message>Answer</message>
+    <createdAt>2018-01-03T01:00:00Z</createdAt>
+    <isDeleted>false</isDeleted>
+    <isSpam>false</isSpam>
+    <author><name>John</name></author>
+    <ipAddress>10.0.0.2</ipAddress>
+    <thread dsq:id="10" />
+    <parent dsq:id="105" />
+  </post>
+</disqus>"#;
+
+    #[test]
+    fn import_twice() {
+        let storage: &dyn Storage = &MemoryStore::new();
+
+        storage
+            .run(|store| {
+                let report = import(EXPORT.as_bytes(), store)?;
+
+                assert_eq!((1, 5, 0), (report.threads, report.comments, report.existing));
+                assert_eq!(vec!["103".to_owned()], report.orphans);
+                assert_eq!(
+                    vec![
+                        ("104".to_owned(), "unknown thread".to_owned()),
+                        ("101".to_owned(), "deleted".to_owned())
+                    ],
+                    report.skipped
+                );
+
+                let thread = store.thread_by_uri("/blog/hello/")?.unwrap();
+                assert_eq!("Hello & welcome", thread.title);
+
+                let (comments, _) = store.list(None, None, None, None, None, 10, 0)?;
+                let comment = |text: &str| {
+                    comments
+                        .iter()
+                        .map(|(c, _)| c)
+                        .find(|c| c.text.contains(text))
+                        .unwrap()
+                        .clone()
+                };
+
+                let first = comment("First");
+                assert_eq!(Some("jane@example.com".to_owned()), first.email);
+                assert_eq!("10.0.0.1", first.remote_addr);
+                assert_eq!(CommentMode::Valid as i32, first.mode);
+
+                // Nested replies are attached to the top-level comment, even if their parent was deleted
+                assert_eq!(Some(first.id), comment("Reply to a reply").parent);
+                assert_eq!(CommentMode::Pending as i32, comment("Buy now").mode);
+
+                // Deleted posts with replies are kept without their text and author
+                let deleted = store.comment(comment("Answer").parent.unwrap())?.unwrap();
+                assert_eq!(CommentMode::SoftDeleted as i32, deleted.mode);
+                assert_eq!(("", None), (deleted.text.as_str(), deleted.author));
+
+                // Nothing is imported twice
+                let report = import(EXPORT.as_bytes(), store)?;
+                assert_eq!((0, 0, 5), (report.threads, report.comments, report.existing));
+                assert_eq!(5, store.list(None, None, None, None, None, 10, 0)?.1);
+
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn deleted_posts_are_not_exposed() {
+        let (builder, ctx) = memory_api(RissoConfig::default());
+
+        builder.storage.run(|store| import(EXPORT.as_bytes(), store)).unwrap();
+
+        let json = crate::import::fetch_json(&ctx, "/blog/hello/");
+        assert!(json.contains("Answer"));
+        for hidden in &["<p>Reply</p>", "Anonymous", "Deleted secret", "Ghost"] {
+            assert!(!json.contains(hidden), "{} is exposed", hidden);
+        }
+    }
+}
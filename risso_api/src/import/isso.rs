@@ -0,0 +1,468 @@
+//! Import of an Isso SQLite database.
+//!
+//! Threads, comments with their votes, and preferences are copied as is. When the target store is
+//! empty, ids are kept so that links to comments remain valid. Otherwise threads are merged by uri,
+//! and new ids are allocated after the highest existing ones.
+//!
+//! Preferences that already exist are kept, unless they are explicitly overwritten. This is the
+//! case of the session key, that Risso creates when it first starts. Importing Isso's key doesn't
+//! make the links of emails sent by Isso valid, as Risso signs its tokens differently, and
+//! overwriting Risso's key invalidates the links of emails it already sent.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use crate::dieselext::FloatDateTime;
+use crate::markup;
+use crate::models::{Comment, CommentMode, Thread};
+use crate::store::CommentStore;
+
+use super::mode_name;
+
+/// Isso's schema, where most columns are nullable.
+mod schema {
+    table! {
+        preferences (key) {
+            key -> Text,
+            value -> Nullable<Text>,
+        }
+    }
+
+    table! {
+        threads (id) {
+            id -> Integer,
+            uri -> Nullable<Text>,
+            title -> Nullable<Text>,
+        }
+    }
+
+    table! {
+        comments (id) {
+            tid -> Nullable<Integer>,
+            id -> Integer,
+            parent -> Nullable<Integer>,
+            created -> Double,
+            modified -> Nullable<Double>,
+            mode -> Nullable<Integer>,
+            remote_addr -> Nullable<Text>,
+            text -> Nullable<Text>,
+            author -> Nullable<Text>,
+            email -> Nullable<Text>,
+            website -> Nullable<Text>,
+            likes -> Nullable<Integer>,
+            dislikes -> Nullable<Integer>,
+            voters -> Binary,
+            notification -> Nullable<Bool>,
+        }
+    }
+}
+
+#[derive(Queryable)]
+struct IssoThread {
+    id: i32,
+    uri: Option<String>,
+    title: Option<String>,
+}
+
+/// A comment row. Dates are read as floats, to report those that cannot be converted.
+#[derive(Queryable)]
+struct IssoComment {
+    tid: Option<i32>,
+    id: i32,
+    parent: Option<i32>,
+    created: f64,
+    modified: Option<f64>,
+    mode: Option<i32>,
+    remote_addr: Option<String>,
+    text: Option<String>,
+    author: Option<String>,
+    email: Option<String>,
+    website: Option<String>,
+    likes: Option<i32>,
+    dislikes: Option<i32>,
+    voters: Vec<u8>,
+    notification: Option<bool>,
+}
+
+/// What was imported, and what needs to be checked. Comments are identified by their id in Isso.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Ids were remapped, as the target store wasn't empty.
+    pub remapped: bool,
+    pub threads: usize,
+    /// Threads that already existed in the target store with the same uri.
+    pub merged_threads: usize,
+    pub comments: usize,
+    /// Number of imported comments for each mode.
+    pub modes: BTreeMap<i32, usize>,
+    pub preferences: usize,
+    /// Preferences that already exist in the target store with a different value, and were kept.
+    pub conflicting_preferences: Vec<String>,
+    /// Preferences that already exist in the target store with a different value, and were replaced.
+    pub overwritten_preferences: Vec<String>,
+    /// Threads without a uri, that were skipped.
+    pub skipped_threads: Vec<i32>,
+    /// Comments whose thread doesn't exist, that were skipped.
+    pub missing_threads: Vec<i32>,
+    /// Comments whose parent doesn't exist. They are imported as top-level comments.
+    pub orphans: Vec<i32>,
+    /// Comments with a date that cannot be converted. They are skipped if it's the creation date,
+    /// and lose their modification date otherwise.
+    pub invalid_dates: Vec<i32>,
+}
+
+/// Import the Isso database at `path` into `store`, in a single transaction. Existing preferences
+/// are replaced only if `overwrite_preferences` is true.
+pub fn import(path: &str, store: &dyn CommentStore, overwrite_preferences: bool) -> Result<Report, failure::Error> {
+    let isso = SqliteConnection::establish(path)
+        .map_err(|err| failure::err_msg(format!("Cannot open Isso database {}: {}", path, err)))?;
+
+    import_from(&isso, store, overwrite_preferences)
+}
+
+/// Import an Isso database into `store`, in a single transaction.
+pub fn import_from(
+    isso: &SqliteConnection,
+    store: &dyn CommentStore,
+    overwrite_preferences: bool,
+) -> Result<Report, failure::Error> {
+    let preferences = schema::preferences::table.load::<(String, Option<String>)>(isso)?;
+    let threads = schema::threads::table
+        .order(schema::threads::id.asc())
+        .load::<IssoThread>(isso)?;
+    let comments = schema::comments::table
+        .order(schema::comments::id.asc())
+        .load::<IssoComment>(isso)?;
+
+    store.atomically(|| copy(store, overwrite_preferences, preferences, threads, comments))
+}
+
+fn copy(
+    store: &dyn CommentStore,
+    overwrite_preferences: bool,
+    preferences: Vec<(String, Option<String>)>,
+    threads: Vec<IssoThread>,
+    comments: Vec<IssoComment>,
+) -> Result<Report, failure::Error> {
+    let mut report = Report::default();
+
+    for (key, value) in preferences {
+        let value = value.unwrap_or_default();
+        match store.preference(&key)? {
+            None => {
+                store.insert_preference(&key, &value)?;
+                report.preferences += 1;
+            }
+            Some(ref existing) if *existing == value => {}
+            Some(_) if overwrite_preferences => {
+                store.update_preference(&key, &value)?;
+                report.overwritten_preferences.push(key);
+            }
+            Some(_) => report.conflicting_preferences.push(key),
+        }
+    }
+
+    let (max_thread_id, max_comment_id) = store.max_ids()?;
+    report.remapped = max_thread_id.is_some() || max_comment_id.is_some();
+
+    let mut next_thread_id = max_thread_id.unwrap_or(0);
+    let mut next_comment_id = max_comment_id.unwrap_or(0);
+
+    // Isso thread id -> store thread id
+    let mut thread_ids = HashMap::new();
+
+    for thread in threads {
+        let uri = match thread.uri {
+            Some(uri) => uri,
+            None => {
+                report.skipped_threads.push(thread.id);
+                continue;
+            }
+        };
+
+        if let Some(existing) = store.thread_by_uri(&uri)? {
+            thread_ids.insert(thread.id, existing.id);
+            report.merged_threads += 1;
+            continue;
+        }
+
+        let id = if report.remapped {
+            next_thread_id += 1;
+            next_thread_id
+        } else {
+            thread.id
+        };
+
+        store.restore_thread(&Thread {
+            id,
+            uri,
+            title: thread.title.unwrap_or_default(),
+        })?;
+
+        thread_ids.insert(thread.id, id);
+        report.threads += 1;
+    }
+
+    // Allocate all ids first, as parents are needed to insert replies
+    let mut comment_ids = HashMap::new();
+    let mut importable = Vec::new();
+
+    for comment in comments {
+        let thread_id = match comment.tid.and_then(|tid| thread_ids.get(&tid)) {
+            Some(thread_id) => *thread_id,
+            None => {
+                report.missing_threads.push(comment.id);
+                continue;
+            }
+        };
+
+        let created = match FloatDateTime::from_f64(comment.created) {
+            Some(created) => created,
+            None => {
+                report.invalid_dates.push(comment.id);
+                continue;
+            }
+        };
+
+        let id = if report.remapped {
+            next_comment_id += 1;
+            next_comment_id
+        } else {
+            comment.id
+        };
+
+        comment_ids.insert(comment.id, id);
+        importable.push((comment, thread_id, id, created));
+    }
+
+    for (comment, thread_id, id, created) in importable {
+        let parent = match comment.parent {
+            None => None,
+            Some(parent) => match comment_ids.get(&parent) {
+                Some(parent_id) => Some(*parent_id),
+                None => {
+                    report.orphans.push(comment.id);
+                    None
+                }
+            },
+        };
+
+        let modified = match comment.modified {
+            None => None,
+            Some(date) => {
+                let modified = FloatDateTime::from_f64(date);
+                if modified.is_none() {
+                    report.invalid_dates.push(comment.id);
+                }
+                modified
+            }
+        };
+
+        let mode = comment.mode.unwrap_or(CommentMode::Valid as i32);
+
+        store.restore_comment(&Comment {
+            thread_id,
+            id,
+            parent,
+            created,
+            modified,
+            mode,
+            remote_addr: comment.remote_addr.unwrap_or_default(),
+            text: comment.text.unwrap_or_default(),
+            author: comment.author,
+            email: comment.email,
+            website: comment.website,
+            likes: comment.likes.unwrap_or(0),
+            dislikes: comment.dislikes.unwrap_or(0),
+            notification: comment.notification.unwrap_or(false),
+            voters: comment.voters,
+            hash: None,
+            html: None,
+            html_fingerprint: None,
+        })?;
+
+        *report.modes.entry(mode).or_insert(0) += 1;
+        report.comments += 1;
+    }
+
+    report.invalid_dates.sort();
+    markup::invalidate(store)?;
+    Ok(report)
+}
+
+fn write_ids<T: fmt::Display>(f: &mut fmt::Formatter, title: &str, ids: &[T]) -> fmt::Result {
+    if !ids.is_empty() {
+        let ids = ids.iter().map(T::to_string).collect::<Vec<_>>();
+        writeln!(f, "{} ({}): {}", title, ids.len(), ids.join(", "))?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Imported {} threads, {} comments and {} preferences.",
+            self.threads, self.comments, self.preferences
+        )?;
+
+        if self.remapped {
+            writeln!(f, "The target database wasn't empty: ids were remapped.")?;
+        }
+        if self.merged_threads > 0 {
+            writeln!(f, "Comments were added to {} existing threads.", self.merged_threads)?;
+        }
+
+        let modes = self
+            .modes
+            .iter()
+            .map(|(mode, count)| format!("{}: {}", mode_name(*mode), count))
+            .collect::<Vec<_>>();
+        writeln!(f, "Comments per mode: {}", modes.join(", "))?;
+
+        write_ids(f, "Existing preferences that were kept", &self.conflicting_preferences)?;
+        write_ids(
+            f,
+            "Existing preferences that were overwritten",
+            &self.overwritten_preferences,
+        )?;
+        write_ids(f, "Threads without a uri, skipped", &self.skipped_threads)?;
+        write_ids(f, "Comments on a missing thread, skipped", &self.missing_threads)?;
+        write_ids(
+            f,
+            "Comments with a missing parent, imported as top-level",
+            &self.orphans,
+        )?;
+        write_ids(f, "Comments with an invalid date", &self.invalid_dates)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::connection::SimpleConnection;
+
+    use crate::models::{NewCommentRow, NewThreadRow};
+    use crate::store::{MemoryStore, Storage};
+
+    /// An Isso database with a reply, an orphan and a comment with an invalid date.
+    fn isso_db() -> SqliteConnection {
+        let cnx = SqliteConnection::establish(":memory:").unwrap();
+        cnx.batch_execute(
+            r#"
+            CREATE TABLE preferences (key VARCHAR PRIMARY KEY, value VARCHAR);
+            CREATE TABLE threads (id INTEGER PRIMARY KEY, uri VARCHAR(256) UNIQUE, title VARCHAR(256));
+            CREATE TABLE comments (
+                tid REFERENCES threads(id), id INTEGER PRIMARY KEY, parent INTEGER, created FLOAT NOT NULL,
+                modified FLOAT, mode INTEGER, remote_addr VARCHAR, text VARCHAR, author VARCHAR, email VARCHAR,
+                website VARCHAR, likes INTEGER DEFAULT 0, dislikes INTEGER DEFAULT 0, voters BLOB NOT NULL,
+                notification INTEGER DEFAULT 0);
+
+            INSERT INTO preferences VALUES ('session-key', 'isso-key');
+            INSERT INTO threads VALUES (1, '/existing', NULL), (2, '/new', 'New');
+            INSERT INTO comments (tid, id, parent, created, mode, remote_addr, text, likes, voters) VALUES
+                (1, 1, NULL, 1500000000.5, 1, '127.0.0.1', 'First', 3, x'00'),
+                (1, 2, 1, 1500000001.0, 2, '127.0.0.1', 'Reply', 0, x'00'),
+                (2, 5, 4, 1500000002.0, 4, '127.0.0.1', 'Orphan', 0, x'00'),
+                (2, 6, NULL, 1e20, 1, '127.0.0.1', 'Invalid date', 0, x'00');
+            "#,
+        )
+        .unwrap();
+        cnx
+    }
+
+    #[test]
+    fn import_into_empty_store() {
+        let storage: &dyn Storage = &MemoryStore::new();
+
+        storage
+            .run(|store| {
+                let report = import_from(&isso_db(), store, false)?;
+
+                assert!(!report.remapped);
+                assert_eq!((2, 3), (report.threads, report.comments));
+                assert_eq!(vec![5], report.orphans);
+                assert_eq!(vec![6], report.invalid_dates);
+
+                // Ids and votes are kept
+                assert_eq!(Some("isso-key".to_owned()), store.preference("session-key")?);
+                let reply = store.comment(2)?.unwrap();
+                assert_eq!((1, Some(1)), (reply.thread_id, reply.parent));
+                assert_eq!(3, store.comment(1)?.unwrap().likes);
+                assert_eq!("", store.thread(1)?.unwrap().title);
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn merge_into_existing_store() {
+        let storage: &dyn Storage = &MemoryStore::new();
+
+        storage
+            .run(|store| {
+                store.insert_preference("session-key", "risso-key")?;
+                let existing = store.insert_thread(&NewThreadRow {
+                    uri: "/existing",
+                    title: "Existing",
+                })?;
+                store.insert_comment(&NewCommentRow {
+                    thread_id: existing.id,
+                    parent: None,
+                    created: 1_400_000_000.0,
+                    mode: 1,
+                    remote_addr: "127.0.0.1",
+                    text: "Existing",
+                    author: None,
+                    email: None,
+                    website: None,
+                    notification: false,
+                    voters: &[0],
+                })?;
+
+                let report = import_from(&isso_db(), store, false)?;
+
+                assert!(report.remapped);
+                assert_eq!((1, 1), (report.threads, report.merged_threads));
+                assert_eq!(vec!["session-key".to_owned()], report.conflicting_preferences);
+
+                let mut modes = BTreeMap::new();
+                modes.insert(1, 1);
+                modes.insert(2, 1);
+                modes.insert(4, 1);
+                assert_eq!(modes, report.modes);
+
+                // Comments are added to the existing thread, with new ids
+                let comments = store.fetch("/existing", Some(7), 0.0, None, None, true, None)?;
+                assert_eq!(vec![1, 2], comments.iter().map(|c| c.id).collect::<Vec<_>>());
+                let reply = store.comment(3)?.unwrap();
+                assert_eq!((existing.id, Some(2)), (reply.thread_id, reply.parent));
+                assert_eq!(Some("risso-key".to_owned()), store.preference("session-key")?);
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn overwrite_preferences() {
+        let storage: &dyn Storage = &MemoryStore::new();
+
+        storage
+            .run(|store| {
+                store.insert_preference("session-key", "risso-key")?;
+
+                let report = import_from(&isso_db(), store, true)?;
+
+                assert!(report.conflicting_preferences.is_empty());
+                assert_eq!(vec!["session-key".to_owned()], report.overwritten_preferences);
+                assert_eq!(Some("isso-key".to_owned()), store.preference("session-key")?);
+                Ok(())
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+//! Import of comments from other comment systems.
+
+use std::collections::HashMap;
+
+use crate::store::CommentStore;
+
+pub mod disqus;
+
+#[cfg(feature = "isso")]
+pub mod isso;
+pub mod wordpress;
+
+/// Name of a comment mode in import reports.
+fn mode_name(mode: i32) -> String {
+    match mode {
+        1 => "valid".to_owned(),
+        2 => "pending".to_owned(),
+        4 => "deleted".to_owned(),
+        _ => format!("mode {}", mode),
+    }
+}
+
+/// The path of a link, that is used as the uri of its thread.
+fn link_path(link: &str) -> &str {
+    let path = match link.find("://") {
+        Some(pos) => link[pos + 3..].find('/').map_or("/", |slash| &link[pos + 3 + slash..]),
+        None => link,
+    };
+
+    path.split(|c| c == '?' || c == '#').next().unwrap_or(path)
+}
+
+fn non_empty(s: &str) -> Option<&str> {
+    if s.is_empty() {
+        None
+    } else {
+        Some(s)
+    }
+}
+
+/// The top-level ancestor of a record, given the parent of each record. Like in Isso, replies are
+/// attached to it as there is only one level of replies.
+fn top_level<'a>(parents: &HashMap<&'a str, Option<&'a str>>, id: &'a str) -> &'a str {
+    let mut current = id;
+
+    // Bounded, in case of loops in a corrupted export
+    for _ in 0..parents.len() {
+        match parents
+            .get(current)
+            .and_then(|parent| *parent)
+            .filter(|parent| parents.contains_key(parent))
+        {
+            Some(parent) => current = parent,
+            None => break,
+        }
+    }
+
+    current
+}
+
+/// The id of a comment imported by a previous run: on the same thread, created at the same second
+/// by the same author.
+fn existing_comment(
+    store: &dyn CommentStore,
+    uri: &str,
+    created: f64,
+    author: Option<&str>,
+    email: Option<&str>,
+) -> Result<Option<i32>, failure::Error> {
+    let (comments, _) = store.list(None, Some(uri), None, Some(created), Some(created + 1.0), 100, 0)?;
+
+    Ok(comments
+        .into_iter()
+        .map(|(comment, _)| comment)
+        .find(|comment| {
+            comment.author.as_ref().map(String::as_str) == author && comment.email.as_ref().map(String::as_str) == email
+        })
+        .map(|comment| comment.id))
+}
+
+/// The response of `fetch` for `uri` as json, i.e. what visitors see of imported comments.
+#[cfg(test)]
+fn fetch_json(ctx: &crate::context::ApiContext, uri: &str) -> String {
+    use futures::Future;
+
+    let req = serde_json::from_value(serde_json::json!({ "uri": uri })).unwrap();
+    serde_json::to_string(&crate::fetch(ctx, req).wait().unwrap()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_paths() {
+        assert_eq!(
+            "/blog/hello/",
+            link_path("https://example.com/blog/hello/?utm_source=feed")
+        );
+        assert_eq!("/", link_path("http://example.com"));
+        assert_eq!("/about", link_path("/about#comments"));
+    }
+
+    #[test]
+    fn top_level_ancestors() {
+        let mut parents = HashMap::new();
+        parents.insert("1", None);
+        parents.insert("2", Some("1"));
+        parents.insert("3", Some("2"));
+        parents.insert("4", Some("404"));
+        parents.insert("5", Some("6"));
+        parents.insert("6", Some("5"));
+
+        assert_eq!("1", top_level(&parents, "3"));
+        assert_eq!("4", top_level(&parents, "4"));
+        // Loops end somewhere
+        top_level(&parents, "5");
+    }
+}
@@ -0,0 +1,525 @@
+//! Import of the comments of a WordPress eXtended RSS (WXR) export.
+//!
+//! Each `<item>` with comments becomes a thread, with the path of its permalink as uri. Approved
+//! comments are valid and held comments are pending. Trashed top-level comments with replies are
+//! soft-deleted, without their text, author and website, and other trashed comments are skipped.
+//! Spam, pingbacks and trackbacks are skipped. Like the Disqus import, the import can be run again.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io::BufRead;
+
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+use crate::bloom;
+use crate::dieselext::FloatDateTime;
+use crate::markup;
+use crate::models::{CommentMode, NewCommentRow, NewThreadRow};
+use crate::store::CommentStore;
+
+use super::{existing_comment, link_path, mode_name, non_empty, top_level};
+
+#[derive(Debug, Default)]
+struct WxrItem {
+    id: String,
+    title: String,
+    link: String,
+    comments: Vec<WxrComment>,
+}
+
+#[derive(Debug, Default)]
+struct WxrComment {
+    id: String,
+    author: String,
+    author_email: String,
+    author_url: String,
+    author_ip: String,
+    date: String,
+    date_gmt: String,
+    content: String,
+    approved: String,
+    comment_type: String,
+    parent: String,
+}
+
+/// What was imported, and what needs to be checked.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub threads: usize,
+    pub comments: usize,
+    /// Comments that were imported by a previous run.
+    pub existing: usize,
+    /// Number of imported comments for each mode.
+    pub modes: BTreeMap<i32, usize>,
+    /// Ids of comments whose parent cannot be found. They are imported as top-level comments.
+    pub orphans: Vec<String>,
+    /// Records that were not imported, e.g. "comment 12", and why.
+    pub skipped: Vec<(String, String)>,
+}
+
+/// Import the comments of a WXR file into `store`, in a single transaction.
+pub fn import<R: BufRead>(reader: R, store: &dyn CommentStore) -> Result<Report, failure::Error> {
+    let items = parse(reader)?;
+    store.atomically(|| copy(store, &items))
+}
+
+fn parse<R: BufRead>(reader: R) -> Result<Vec<WxrItem>, failure::Error> {
+    let mut reader = Reader::from_reader(reader);
+    reader.trim_text(true);
+
+    let mut items = Vec::new();
+    let mut path = Vec::new();
+    let mut buf = Vec::new();
+
+    loop {
+        let event = reader.read_event(&mut buf).map_err(|err| {
+            failure::err_msg(format!("Invalid XML at position {}: {}", reader.buffer_position(), err))
+        })?;
+
+        match event {
+            Event::Start(ref e) => {
+                let name = String::from_utf8_lossy(e.name()).into_owned();
+                start_element(&mut items, &path, &name);
+                path.push(name);
+            }
+            Event::End(_) => {
+                path.pop();
+            }
+            Event::Text(ref e) => text(&mut items, &path, &e.unescape_and_decode(&reader)?),
+            Event::CData(ref e) => text(&mut items, &path, &reader.decode(e)),
+            Event::Eof => break,
+            _ => (),
+        }
+
+        buf.clear();
+    }
+
+    Ok(items)
+}
+
+fn start_element(items: &mut Vec<WxrItem>, path: &[String], name: &str) {
+    let path = path.iter().map(String::as_str).collect::<Vec<_>>();
+
+    match (path.as_slice(), name) {
+        (["rss", "channel"], "item") => items.push(WxrItem::default()),
+        (["rss", "channel", "item"], "wp:comment") => {
+            if let Some(item) = items.last_mut() {
+                item.comments.push(WxrComment::default());
+            }
+        }
+        _ => (),
+    }
+}
+
+fn text(items: &mut Vec<WxrItem>, path: &[String], text: &str) {
+    let path = path.iter().map(String::as_str).collect::<Vec<_>>();
+
+    if !path.starts_with(&["rss", "channel", "item"]) {
+        return;
+    }
+
+    let item = match items.last_mut() {
+        Some(item) => item,
+        None => return,
+    };
+
+    match &path[3..] {
+        ["title"] => item.title.push_str(text),
+        ["link"] => item.link.push_str(text),
+        ["wp:post_id"] => item.id.push_str(text),
+        ["wp:comment", field] => {
+            if let Some(comment) = item.comments.last_mut() {
+                let value = match *field {
+                    "wp:comment_id" => &mut comment.id,
+                    "wp:comment_author" => &mut comment.author,
+                    "wp:comment_author_email" => &mut comment.author_email,
+                    "wp:comment_author_url" => &mut comment.author_url,
+                    "wp:comment_author_IP" => &mut comment.author_ip,
+                    "wp:comment_date" => &mut comment.date,
+                    "wp:comment_date_gmt" => &mut comment.date_gmt,
+                    "wp:comment_content" => &mut comment.content,
+                    "wp:comment_approved" => &mut comment.approved,
+                    "wp:comment_type" => &mut comment.comment_type,
+                    "wp:comment_parent" => &mut comment.parent,
+                    _ => return,
+                };
+                value.push_str(text);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// The creation date of a comment. The GMT date is not set on some old exports, the local date is
+/// then used as is.
+fn comment_date(comment: &WxrComment) -> Option<f64> {
+    [&comment.date_gmt, &comment.date]
+        .iter()
+        .filter_map(|date| NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S").ok())
+        .next()
+        .map(|date| FloatDateTime(Utc.from_utc_datetime(&date)).to_f64())
+}
+
+fn copy(store: &dyn CommentStore, items: &[WxrItem]) -> Result<Report, failure::Error> {
+    let mut report = Report::default();
+
+    for item in items.iter().filter(|item| !item.comments.is_empty()) {
+        if item.link.is_empty() {
+            report
+                .skipped
+                .push((format!("item {}", item.id), "no permalink".to_owned()));
+            continue;
+        }
+
+        copy_item(store, item, &mut report)?;
+    }
+
+    markup::invalidate(store)?;
+    Ok(report)
+}
+
+fn copy_item(store: &dyn CommentStore, item: &WxrItem, report: &mut Report) -> Result<(), failure::Error> {
+    let uri = link_path(&item.link);
+
+    // "0" is used for top-level comments
+    let parents = item
+        .comments
+        .iter()
+        .map(|c| {
+            (
+                c.id.as_str(),
+                Some(c.parent.as_str()).filter(|p| *p != "0" && !p.is_empty()),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+
+    // Top-level comments that have replies, other than those that are not imported
+    let with_replies = item
+        .comments
+        .iter()
+        .filter(|c| ["1", "approve", "0", "hold"].contains(&c.approved.as_str()))
+        .filter(|c| c.comment_type != "pingback" && c.comment_type != "trackback")
+        .map(|c| (top_level(&parents, &c.id), c.id.as_str()))
+        .filter(|(root_id, id)| root_id != id)
+        .map(|(root_id, _)| root_id)
+        .collect::<HashSet<_>>();
+
+    // Import top-level comments first, so that replies can find their parent
+    let mut ordered = item
+        .comments
+        .iter()
+        .map(|c| (top_level(&parents, &c.id), c))
+        .collect::<Vec<_>>();
+    ordered.sort_by_key(|(root_id, comment)| *root_id != comment.id.as_str());
+
+    // WordPress comment id -> comment id
+    let mut comment_ids = HashMap::new();
+
+    for (root_id, comment) in ordered {
+        let skip = |reason: &str| (format!("comment {}", comment.id), reason.to_owned());
+
+        let mode = match comment.approved.as_str() {
+            "1" | "approve" => CommentMode::Valid as i32,
+            "0" | "hold" => CommentMode::Pending as i32,
+            // Like deleted comments, trashed ones are only kept as the parent of other comments
+            "trash" if root_id == comment.id.as_str() && with_replies.contains(root_id) => {
+                CommentMode::SoftDeleted as i32
+            }
+            "trash" => {
+                report.skipped.push(skip("trash"));
+                continue;
+            }
+            "spam" => {
+                report.skipped.push(skip("spam"));
+                continue;
+            }
+            other => {
+                report
+                    .skipped
+                    .push(skip(&format!("unknown approval state '{}'", other)));
+                continue;
+            }
+        };
+
+        if comment.comment_type == "pingback" || comment.comment_type == "trackback" {
+            report.skipped.push(skip(&comment.comment_type));
+            continue;
+        }
+
+        let created = match comment_date(comment) {
+            Some(created) => created,
+            None => {
+                report.skipped.push(skip("invalid date"));
+                continue;
+            }
+        };
+
+        let deleted = mode == CommentMode::SoftDeleted as i32;
+        let (text, author, website) = if deleted {
+            ("", None, None)
+        } else {
+            (
+                comment.content.as_str(),
+                non_empty(&comment.author),
+                non_empty(&comment.author_url).filter(|url| *url != "http://"),
+            )
+        };
+        let email = non_empty(&comment.author_email);
+
+        if let Some(id) = existing_comment(store, uri, created, author, email)? {
+            comment_ids.insert(comment.id.as_str(), id);
+            report.existing += 1;
+            continue;
+        }
+
+        let parent = if root_id == comment.id.as_str() {
+            None
+        } else {
+            comment_ids.get(root_id).cloned()
+        };
+
+        let parent_missing = parents
+            .get(comment.id.as_str())
+            .and_then(|parent| *parent)
+            .map_or(false, |parent| !parents.contains_key(parent));
+
+        if parent_missing || (root_id != comment.id.as_str() && parent.is_none()) {
+            report.orphans.push(comment.id.clone());
+        }
+
+        let thread_id = match store.thread_by_uri(uri)? {
+            Some(thread) => thread.id,
+            None => {
+                report.threads += 1;
+                store
+                    .insert_thread(&NewThreadRow {
+                        uri,
+                        title: &item.title,
+                    })?
+                    .id
+            }
+        };
+
+        let mut voters = bloom::Bloomfilter::new();
+        voters.add(&comment.author_ip);
+
+        let inserted = store.insert_comment(&NewCommentRow {
+            thread_id,
+            parent,
+            created,
+            mode,
+            remote_addr: &comment.author_ip,
+            text,
+            author,
+            email,
+            website,
+            notification: false,
+            voters: voters.as_bytes(),
+        })?;
+
+        comment_ids.insert(comment.id.as_str(), inserted.id);
+        *report.modes.entry(mode).or_insert(0) += 1;
+        report.comments += 1;
+    }
+
+    Ok(())
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Imported {} threads and {} comments. {} comments were already imported.",
+            self.threads, self.comments, self.existing
+        )?;
+
+        let modes = self
+            .modes
+            .iter()
+            .map(|(mode, count)| format!("{}: {}", mode_name(*mode), count))
+            .collect::<Vec<_>>();
+        writeln!(f, "Comments per mode: {}", modes.join(", "))?;
+
+        if !self.orphans.is_empty() {
+            writeln!(
+                f,
+                "Comments with a missing parent, imported as top-level: {}",
+                self.orphans.join(", ")
+            )?;
+        }
+        for (record, reason) in &self.skipped {
+            writeln!(f, "Skipped {}: {}", record, reason)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RissoConfig;
+    use crate::store::fixtures::memory_api;
+    use crate::store::{MemoryStore, Storage};
+
+    const EXPORT: &str = r#"<?xml version="1.0" encoding="UTF-8" ?>
+<rss version="2.0" xmlns:content="http://purl.org/rss/1.0/modules/content/"
+    xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:wp="http://wordpress.org/export/1.2/">
+<channel>
+  <title>Blog</title>
+  <link>https://example.com</link>
+  <wp:wxr_version>1.2</wp:wxr_version>
+  <item>
+    <title>Hello world!</title>
+    <link>https://example.com/2018/01/hello-world/</link>
+    <wp:post_id>1</wp:post_id>
+    <wp:comment>
+      <wp:comment_id>2</wp:comment_id>
+      <wp:comment_author><![CDATA[Jane]]></wp:comment_author>
+      <wp:comment_author_email><![CDATA[jane@example.com]]></wp:comment_author_email>
+      <wp:comment_author_url>https://jane.example.com</wp:comment_author_url>
+      <wp:comment_author_IP><![CDATA[10.0.0.2]]></wp:comment_author_IP>
+      <wp:comment_date><![CDATA[2018-01-01 11:00:00]]></wp:comment_date>
+      <wp:comment_date_gmt><![CDATA[2018-01-01 10:00:00]]></wp:comment_date_gmt>
+      <wp:comment_content><![CDATA[Nested reply]]></wp:comment_content>
+      <wp:comment_approved><![CDATA[1]]></wp:comment_approved>
+      <wp:comment_type><![CDATA[]]></wp:comment_type>
+      <wp:comment_parent>1</wp:comment_parent>
+    </wp:comment>
+    <wp:comment>
+      <wp:comment_id>1</wp:comment_id>
+      <wp:comment_author><![CDATA[John]]></wp:comment_author>
+      <wp:comment_author_IP><![CDATA[10.0.0.1]]></wp:comment_author_IP>
+      <wp:comment_date><![CDATA[2018-01-01 09:00:00]]></wp:comment_date>
+      <wp:comment_date_gmt><![CDATA[0000-00-00 00:00:00]]></wp:comment_date_gmt>
+      <wp:comment_content><![CDATA[First]]></wp:comment_content>
+      <wp:comment_approved><![CDATA[0]]></wp:comment_approved>
+      <wp:comment_parent>0</wp:comment_parent>
+    </wp:comment>
+    <wp:comment>
+      <wp:comment_id>3</wp:comment_id>
+      <wp:comment_author><![CDATA[Spammer]]></wp:comment_author>
+      <wp:comment_date_gmt><![CDATA[2018-01-02 00:00:00]]></wp:comment_date_gmt>
+      <wp:comment_content><![CDATA[Buy now]]></wp:comment_content>
+      <wp:comment_approved><![CDATA[spam]]></wp:comment_approved>
+      <wp:comment_parent>0</wp:comment_parent>
+    </wp:comment>
+    <wp:comment>
+      <wp:comment_id>4</wp:comment_id>
+      <wp:comment_date_gmt><![CDATA[2018-01-03 00:00:00]]></wp:comment_date_gmt>
+      <wp:comment_content><![CDATA[Orphan]]></wp:comment_content>
+      <wp:comment_approved><![CDATA[1]]></wp:comment_approved>
+      <wp:comment_parent>42</wp:comment_parent>
+    </wp:comment>
+    <wp:comment>
+      <wp:comment_id>5</wp:comment_id>
+      <wp:comment_author><![CDATA[Ghost]]></wp:comment_author>
+      <wp:comment_author_email><![CDATA[ghost@example.com]]></wp:comment_author_email>
+      <wp:comment_author_url>https://ghost.example.com</wp:comment_author_url>
+      <wp:comment_date_gmt><![CDATA[2018-01-04 00:00:00]]></wp:comment_date_gmt>
+      <wp:comment_content><![CDATA[Trashed secret]]></wp:comment_content>
+      <wp:comment_approved><![CDATA[trash]]></wp:comment_approved>
+      <wp:comment_parent>0</wp:comment_parent>
+    </wp:comment>
+    <wp:comment>
+      <wp:comment_id>6</wp:comment_id>
+      <wp:comment_author><![CDATA[Jane]]></wp:comment_author>
+      <wp:comment_date_gmt><![CDATA[2018-01-05 00:00:00]]></wp:comment_date_gmt>
+      <wp:comment_content><![CDATA[Answer]]></wp:comment_content>
+      <wp:comment_approved><![CDATA[1]]></wp:comment_approved>
+      <wp:comment_parent>5</wp:comment_parent>
+    </wp:comment>
+    <wp:comment>
+      <wp:comment_id>7</wp:comment_id>
+      <wp:comment_date_gmt><![CDATA[2018-01-06 00:00:00]]></wp:comment_date_gmt>
+      <wp:comment_content><![CDATA[Removed]]></wp:comment_content>
+      <wp:comment_approved><![CDATA[trash]]></wp:comment_approved>
+      <wp:comment_parent>0</wp:comment_parent>
+    </wp:comment>
+  </item>
+  <item>
+    <title>About</title>
+    <link>https://example.com/about/</link>
+    <wp:post_id>5</wp:post_id>
+  </item>
+</channel>
+</rss>"#;
+
+    #[test]
+    fn import_twice() {
+        let storage: &dyn Storage = &MemoryStore::new();
+
+        storage
+            .run(|store| {
+                let report = import(EXPORT.as_bytes(), store)?;
+
+                assert_eq!((1, 5, 0), (report.threads, report.comments, report.existing));
+                assert_eq!(vec!["4".to_owned()], report.orphans);
+                assert_eq!(
+                    vec![
+                        ("comment 3".to_owned(), "spam".to_owned()),
+                        ("comment 7".to_owned(), "trash".to_owned())
+                    ],
+                    report.skipped
+                );
+
+                // Items without comments are not imported
+                assert!(store.thread_by_uri("/about/")?.is_none());
+                let thread = store.thread_by_uri("/2018/01/hello-world/")?.unwrap();
+                assert_eq!("Hello world!", thread.title);
+
+                let (comments, _) = store.list(None, None, None, None, None, 10, 0)?;
+                let comment = |text: &str| {
+                    comments
+                        .iter()
+                        .map(|(c, _)| c)
+                        .find(|c| c.text == text)
+                        .unwrap()
+                        .clone()
+                };
+
+                let first = comment("First");
+                assert_eq!(CommentMode::Pending as i32, first.mode);
+                assert_eq!(1_514_797_200.0, first.created.to_f64());
+
+                let reply = comment("Nested reply");
+                assert_eq!(Some(first.id), reply.parent);
+                assert_eq!(Some("https://jane.example.com".to_owned()), reply.website);
+                assert_eq!("10.0.0.2", reply.remote_addr);
+                assert_eq!(1_514_800_800.0, reply.created.to_f64());
+
+                let orphan = comment("Orphan");
+                assert_eq!((CommentMode::Valid as i32, None), (orphan.mode, orphan.parent));
+
+                // Trashed comments with replies are kept without their text, author and website
+                let trashed = store.comment(comment("Answer").parent.unwrap())?.unwrap();
+                assert_eq!(CommentMode::SoftDeleted as i32, trashed.mode);
+                assert_eq!(
+                    ("", None, None),
+                    (trashed.text.as_str(), trashed.author, trashed.website)
+                );
+
+                // Nothing is imported twice
+                let report = import(EXPORT.as_bytes(), store)?;
+                assert_eq!((0, 0, 5), (report.threads, report.comments, report.existing));
+
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn trashed_comments_are_not_exposed() {
+        let (builder, ctx) = memory_api(RissoConfig::default());
+
+        builder.storage.run(|store| import(EXPORT.as_bytes(), store)).unwrap();
+
+        let json = crate::import::fetch_json(&ctx, "/2018/01/hello-world/");
+        assert!(json.contains("Answer"));
+        for hidden in &["Trashed secret", "Ghost", "ghost.example.com", "Removed"] {
+            assert!(!json.contains(hidden), "{} is exposed", hidden);
+        }
+    }
+}
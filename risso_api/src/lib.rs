@@ -17,30 +17,39 @@ use serde_derive::{Deserialize, Serialize};
 
 use chrono::prelude::*;
 
+use std::collections::HashMap;
+
 use futures::future::Future;
 
+use crate::config::{AvatarMode, ModerationConfig, RissoConfig};
 use crate::context::ApiContext;
+use crate::errors::ApiError;
+use crate::logs::macros::*;
+use crate::store::CommentStore;
 
+use percent_encoding::{percent_decode, utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
 use validator::Validate;
 
-mod config;
+pub mod admin;
+mod audit;
+mod avatar;
+pub mod backup;
+mod bloom;
+pub mod config;
 pub mod context;
 pub mod dieselext;
+mod email;
+pub mod errors;
+mod feed;
+mod guard;
+pub mod import;
 pub mod logs;
+pub mod markup;
+pub mod migrations;
 pub mod models;
 pub mod schema;
-
-lazy_static! {
-    /// Global configuration object. Each module can pick its own section in the configuration.
-    pub static ref CONFIG: ::config::Config = crate::config::load().unwrap();
-
-    /// General configurations (private to this crate)
-    static ref GENERAL_CONFIG: GeneralConfig = CONFIG.get("general").unwrap();
-
-    /// General configurations (private to this crate)
-    static ref SMTP_CONFIG: SmtpConfig = CONFIG.get("smtp").unwrap();
-
-}
+pub mod store;
+mod tokens;
 
 // newtype: use defer to pull wrapped type's methods
 // https://doc.rust-lang.org/book/second-edition/ch19-03-advanced-traits.html#using-the-newtype-pattern-to-implement-external-traits-on-external-types
@@ -48,21 +57,6 @@ lazy_static! {
 pub type ThreadId = i32;
 pub type CommentId = i32;
 
-#[derive(Deserialize)]
-struct GeneralConfig {
-    gravatar_url: String,
-}
-
-#[derive(Deserialize)]
-struct SmtpConfig {
-    username: Option<String>,
-    password: Option<String>,
-    host: String,
-    port: i32,
-    to: String,
-    from: String,
-}
-
 /// A boxed future returning a generic result and a `failure::Error`. Shortcut to simplify return statements.
 /// Boxed futures allow returning various Future implementations from a function.
 type BoxFuture<T> = Box<Future<Item = T, Error = failure::Error>>;
@@ -74,7 +68,7 @@ type BoxFuture<T> = Box<Future<Item = T, Error = failure::Error>>;
 ///
 pub fn validate<T: validator::Validate, U: 'static + Send>(v: &T) -> Option<BoxFuture<U>> {
     if let Err(e) = v.validate() {
-        Some(futures::failed(e.into()).boxed())
+        Some(futures::failed(ApiError::from(e).into()).boxed())
     } else {
         None
     }
@@ -107,95 +101,533 @@ pub struct CommentResponse {
     likes: i32,
     dislikes: i32,
     hash: String,
-    gravatar_image: String,
+    // Avatar url, absent if avatars are disabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gravatar_image: Option<String>,
+
+    // Reply information, only present on top-level comments returned by `fetch`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_replies: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hidden_replies: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replies: Option<Vec<CommentResponse>>,
+}
+
+impl CommentResponse {
+    pub fn id(&self) -> CommentId {
+        self.id
+    }
+
+    /// Is this comment waiting for approval by a moderator?
+    pub fn is_pending(&self) -> bool {
+        self.mode == models::CommentMode::Pending as i32
+    }
 }
 
 //--------------------------------------------------------------------------------------------------
 // New comment
 
+// Validation rules are those of Isso's `API.verify`
 #[derive(Clone, Deserialize, Validate)]
 pub struct NewComment {
+    #[validate(length(max = "1024"))]
     author: Option<String>,
+    #[validate(email, length(max = "254"))]
     email: Option<String>,
+    #[validate(length(min = "3", max = "65535"))]
     text: String,
-    parent: Option<i32>,
+    parent: Option<CommentId>,
+    #[validate(url, length(max = "254"))]
     website: Option<String>,
+    /// Title of the page, used if this comment creates a new thread.
+    #[validate(length(max = "256"))]
+    title: Option<String>,
+    /// `1` if the author wants to be notified of replies by email (as sent by Isso's client).
+    notification: Option<i32>,
 }
 
-pub fn new_comment(_ctx: &ApiContext, uri: String, req: NewComment) -> BoxFuture<CommentResponse> {
-    validate!(&req);
-    drop(uri);
-    drop(req);
+impl NewComment {
+    /// Comment forms send empty strings for fields that were left blank: consider them as missing
+    /// so that they don't fail validation and aren't stored.
+    fn normalize(mut self) -> Self {
+        fn non_empty(s: Option<String>) -> Option<String> {
+            s.map(|s| s.trim().to_owned()).filter(|s| !s.is_empty())
+        }
 
-    unimplemented!()
+        self.author = non_empty(self.author);
+        self.email = non_empty(self.email);
+        self.website = non_empty(self.website);
+        self.title = non_empty(self.title);
+        self
+    }
 }
 
-/// Sanitize html
+/// A newly created comment, along with the token that allows its author to edit or delete it
+/// during `general.max_age` seconds.
+pub struct CreatedComment {
+    pub comment: CommentResponse,
+    pub token: String,
+}
+
+/// Post a new comment on the thread for `uri`, creating the thread if needed.
 ///
-/// ```rust
-/// use risso_api::sanitize_html;
+/// `remote_addr` is the poster's address. It is also added to the comment's voters so that the
+/// poster cannot vote on their own comment. Posting is limited by the `[guard]` configuration.
 ///
-/// assert_eq!(sanitize_html("foo"), "foo".to_owned());
-/// ```
+pub fn new_comment(ctx: &ApiContext, uri: String, remote_addr: String, req: NewComment) -> BoxFuture<CreatedComment> {
+    let req = req.normalize();
+    validate!(&req);
+
+    let session_key = ctx.session_key().to_vec();
+    let notify_ctx = ctx.clone();
+
+    ctx.spawn_store(move |store| {
+        // Held until the transaction is committed, so that the guard counts concurrent comments
+        let posting = notify_ctx.posting_locks().lock(&remote_addr);
+
+        let (created, notifications) = store.atomically(|| {
+            let thread = match store.thread_by_uri(&uri)? {
+                Some(thread) => thread,
+                None => {
+                    let row = models::NewThreadRow {
+                        uri: &uri,
+                        title: req.title.as_ref().map_or("", String::as_str),
+                    };
+                    store.insert_thread(&row)?
+                }
+            };
+
+            let parent_comment = match req.parent {
+                None => None,
+                Some(parent_id) => match store.comment(parent_id)? {
+                    Some(parent) if parent.thread_id == thread.id => Some(parent),
+                    _ => {
+                        return Err(ApiError::BadRequest(format!(
+                            "Parent comment {} not found on this thread",
+                            parent_id
+                        ))
+                        .into());
+                    }
+                },
+            };
+
+            let created = dieselext::FloatDateTime(Utc::now()).to_f64();
+            guard::check_comment(
+                notify_ctx.config(),
+                store,
+                &remote_addr,
+                thread.id,
+                parent_comment.as_ref(),
+                created,
+            )?;
+
+            // Replies to a reply are attached to the top-level comment, as Isso does
+            let parent = parent_comment.map(|parent| parent.parent.unwrap_or(parent.id));
+
+            let mut voters = bloom::Bloomfilter::new();
+            voters.add(&remote_addr);
+
+            let mode = if needs_moderation(&notify_ctx.config().moderation, store, req.email.as_ref())? {
+                models::CommentMode::Pending
+            } else {
+                models::CommentMode::Valid
+            };
+
+            let row = models::NewCommentRow {
+                thread_id: thread.id,
+                parent,
+                created,
+                mode: mode as i32,
+                remote_addr: &remote_addr,
+                text: &req.text,
+                author: req.author.as_ref().map(String::as_str),
+                email: req.email.as_ref().map(String::as_str),
+                website: req.website.as_ref().map(String::as_str),
+                notification: req.notification == Some(1) && req.email.is_some(),
+                voters: voters.as_bytes(),
+            };
+
+            let mut comment = store.insert_comment(&row)?;
+            markup::refresh(&notify_ctx.config().markup, store, std::slice::from_mut(&mut comment))?;
+
+            info!("New comment {} on thread {}", comment.id, thread.uri);
+
+            let mut notifications = admin_notification(&notify_ctx, &thread, &comment);
+            notifications.extend(reply_notifications(&notify_ctx, store, thread, &comment)?);
+
+            let created = CreatedComment {
+                comment: comment_response(notify_ctx.config(), &comment, false),
+                token: tokens::sign(&session_key, tokens::AUTHOR, &comment.id.to_string()),
+            };
+            Ok((created, notifications))
+        })?;
+        drop(posting);
+
+        send_notifications(&notify_ctx, notifications);
+        Ok(created)
+    })
+    .boxed()
+}
+
+/// Should a new comment from `email` be held for moderation?
+fn needs_moderation(
+    config: &ModerationConfig,
+    store: &dyn CommentStore,
+    email: Option<&String>,
+) -> Result<bool, failure::Error> {
+    if !config.enabled {
+        return Ok(false);
+    }
+
+    match email {
+        Some(email) if config.approve_if_email_previously_approved => Ok(!store.is_email_approved(email)?),
+        _ => Ok(true),
+    }
+}
+
+/// An email about a comment. Notifications are sent once the changes they are about have been
+/// committed: sending them from a transaction that is then rolled back would announce a comment
+/// that doesn't exist.
+enum Notification {
+    /// A new comment, sent to the admin with moderation links.
+    NewComment {
+        thread: models::Thread,
+        comment: models::Comment,
+        links: email::ModerationLinks,
+    },
+    /// A reply, sent to an author that subscribed to the conversation.
+    Reply {
+        thread: models::Thread,
+        comment: models::Comment,
+        recipient: String,
+        unsubscribe_link: String,
+    },
+}
+
+/// Send notifications in the background. Delivery failures are only logged.
+fn send_notifications(ctx: &ApiContext, notifications: Vec<Notification>) {
+    for notification in notifications {
+        let smtp = ctx.config().smtp.clone();
+
+        ctx.spawn_detached(move || match notification {
+            Notification::NewComment { thread, comment, links } => {
+                email::send_new_comment_email(&smtp, &thread, &comment, &links)
+            }
+            Notification::Reply {
+                thread,
+                comment,
+                recipient,
+                unsubscribe_link,
+            } => email::send_reply_notification(&smtp, &thread, &comment, &recipient, &unsubscribe_link),
+        });
+    }
+}
+
+/// The email to the admin with a new comment and moderation links, if admin notifications are enabled.
+fn admin_notification(ctx: &ApiContext, thread: &models::Thread, comment: &models::Comment) -> Vec<Notification> {
+    let config = ctx.config();
+    if !config.general.notify_admin {
+        return Vec::new();
+    }
+
+    let key = tokens::sign(ctx.session_key(), tokens::MODERATION, &comment.id.to_string());
+    let link = |action: &str| {
+        format!(
+            "{}/id/{}/{}/{}",
+            config.general.public_endpoint.trim_end_matches('/'),
+            comment.id,
+            action,
+            key
+        )
+    };
+
+    vec![Notification::NewComment {
+        thread: thread.clone(),
+        comment: comment.clone(),
+        links: email::ModerationLinks {
+            activate: link("activate"),
+            delete: link("delete"),
+        },
+    }]
+}
+
+/// The emails to the authors that subscribed to replies to the parent of a new comment.
+fn reply_notifications(
+    ctx: &ApiContext,
+    store: &dyn CommentStore,
+    thread: models::Thread,
+    comment: &models::Comment,
+) -> Result<Vec<Notification>, failure::Error> {
+    let config = ctx.config();
+    let parent = match comment.parent {
+        Some(parent) if config.general.reply_notifications && comment.mode == models::CommentMode::Valid as i32 => {
+            parent
+        }
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut recipients: Vec<String> = Vec::new();
+    for subscriber in store.reply_subscribers(parent)? {
+        if let Some(email) = subscriber.email {
+            if subscriber.id != comment.id && Some(&email) != comment.email.as_ref() && !recipients.contains(&email) {
+                recipients.push(email);
+            }
+        }
+    }
+
+    let notifications = recipients
+        .into_iter()
+        .map(|recipient| {
+            let subject = unsubscribe_subject(thread.id, &recipient);
+            let key = tokens::sign(ctx.session_key(), tokens::UNSUBSCRIBE, &subject);
+            let unsubscribe_link = format!(
+                "{}/id/{}/unsubscribe/{}/{}",
+                config.general.public_endpoint.trim_end_matches('/'),
+                parent,
+                utf8_percent_encode(&recipient, PATH_SEGMENT_ENCODE_SET),
+                key
+            );
+
+            Notification::Reply {
+                thread: thread.clone(),
+                comment: comment.clone(),
+                recipient,
+                unsubscribe_link,
+            }
+        })
+        .collect();
+
+    Ok(notifications)
+}
+
+//--------------------------------------------------------------------------------------------------
+// Edit, delete & activate
+
+#[derive(Clone, Deserialize, Validate)]
+pub struct EditComment {
+    #[validate(length(max = "1024"))]
+    author: Option<String>,
+    #[validate(length(min = "3", max = "65535"))]
+    text: String,
+    #[validate(url, length(max = "254"))]
+    website: Option<String>,
+}
+
+impl EditComment {
+    /// Consider blank fields as missing, like `NewComment::normalize`.
+    fn normalize(self) -> Self {
+        EditComment {
+            author: self.author.filter(|s| !s.trim().is_empty()),
+            website: self.website.filter(|s| !s.trim().is_empty()),
+            ..self
+        }
+    }
+}
+
+/// Check that `key` allows modifying comment `id`: either a token given to its author that hasn't
+/// expired yet, or a moderation token.
+fn check_author_key(ctx: &ApiContext, id: CommentId, key: &str) -> Result<(), ApiError> {
+    let subject = id.to_string();
+    let max_age = Some(ctx.config().general.max_age);
+
+    if tokens::verify(ctx.session_key(), tokens::AUTHOR, &subject, key, max_age)
+        || tokens::verify(ctx.session_key(), tokens::MODERATION, &subject, key, None)
+    {
+        Ok(())
+    } else {
+        Err(ApiError::Forbidden(format!(
+            "Invalid or expired key for comment {}",
+            id
+        )))
+    }
+}
+
+/// Check that `key` is a moderation token for comment `id`.
+fn check_moderation_key(ctx: &ApiContext, id: CommentId, key: &str) -> Result<(), ApiError> {
+    if tokens::verify(ctx.session_key(), tokens::MODERATION, &id.to_string(), key, None) {
+        Ok(())
+    } else {
+        Err(ApiError::Forbidden(format!(
+            "Invalid moderation key for comment {}",
+            id
+        )))
+    }
+}
+
+fn not_found(id: CommentId) -> failure::Error {
+    ApiError::NotFound(format!("Comment {} not found", id)).into()
+}
+
+/// Edit the text, author and website of a comment.
+pub fn edit_comment(ctx: &ApiContext, id: CommentId, key: String, req: EditComment) -> BoxFuture<CommentResponse> {
+    if let Err(e) = check_author_key(ctx, id, &key) {
+        return futures::failed(e.into()).boxed();
+    }
+
+    let req = req.normalize();
+    validate!(&req);
+
+    let config = ctx.config().clone();
+
+    ctx.spawn_store(move |store| {
+        let author = req.author.as_ref().map(String::as_str);
+        let website = req.website.as_ref().map(String::as_str);
+
+        match store.update_comment(id, &req.text, author, website)? {
+            Some(mut comment) => {
+                markup::refresh(&config.markup, store, std::slice::from_mut(&mut comment))?;
+                Ok(comment_response(&config, &comment, false))
+            }
+            None => Err(not_found(id)),
+        }
+    })
+    .boxed()
+}
+
+/// Delete a comment. If it has replies, it is soft-deleted and returned with its cleared content,
+/// otherwise it is removed and `None` is returned.
+pub fn delete_comment(ctx: &ApiContext, id: CommentId, key: String) -> BoxFuture<Option<CommentResponse>> {
+    if let Err(e) = check_author_key(ctx, id, &key) {
+        return futures::failed(e.into()).boxed();
+    }
+
+    let config = ctx.config().clone();
+
+    ctx.spawn_store(move |store| {
+        store.atomically(|| {
+            if store.comment(id)?.is_none() {
+                return Err(not_found(id));
+            }
+
+            info!("Deleting comment {}", id);
+
+            let deleted = store.delete_comment(id)?;
+            Ok(deleted.map(|comment| comment_response(&config, &comment, false)))
+        })
+    })
+    .boxed()
+}
+
+/// Activate a pending comment. Requires a moderation key.
 ///
-pub fn sanitize_html(html: &str) -> String {
-    // See https://posativ.org/isso/docs/configuration/server/#markup
-
-    let mut sanitizer = ammonia::Builder::default();
-
-    sanitizer.add_tags(
-        vec![
-            "a",
-            "blockquote",
-            "br",
-            "code",
-            "del",
-            "em",
-            "h1",
-            "h2",
-            "h3",
-            "h4",
-            "h5",
-            "h6",
-            "hr",
-            "img",
-            "ins",
-            "li",
-            "ol",
-            "p",
-            "pre",
-            "strong",
-            "table",
-            "tbody",
-            "td",
-            "th",
-            "thead",
-            "ul",
-        ]
-        .into_iter(),
-    );
-
-    sanitizer.clean(html).to_string()
-}
-
-pub fn send_new_comment_email(title: &str, _comment: &NewComment) -> Result<(), failure::Error> {
-    use lettre::*;
-    use lettre_email::EmailBuilder;
-    use native_tls::TlsConnector;
-
-    let email = EmailBuilder::new()
-        .from(SMTP_CONFIG.from.clone())
-        .to(SMTP_CONFIG.to.clone())
-        .subject(format!("New comment on {}", title))
-        .text("foo")
-        .build()?;
-
-    let tls_parameters = ClientTlsParameters::new(String::from("foo"), TlsConnector::builder()?.build()?);
-    let mut mailer =
-        //SmtpTransport::builder_unencrypted_localhost()?.build();
-        SmtpTransport::builder("blah", ClientSecurity::Wrapper(tls_parameters))?.build();
-
-    mailer.send(&email).map(|_| ()).map_err(|err| err.into())
+/// Reply notifications are sent at this point, as pending comments are not visible to other users.
+pub fn activate_comment(ctx: &ApiContext, id: CommentId, key: String) -> BoxFuture<()> {
+    if let Err(e) = check_moderation_key(ctx, id, &key) {
+        return futures::failed(e.into()).boxed();
+    }
+
+    let notify_ctx = ctx.clone();
+
+    ctx.spawn_store(move |store| {
+        if store.comment(id)?.is_none() {
+            return Err(not_found(id));
+        }
+
+        let mut notifications = Vec::new();
+        activate(&notify_ctx, store, id, &mut notifications)?;
+        send_notifications(&notify_ctx, notifications);
+
+        Ok(())
+    })
+    .boxed()
+}
+
+/// Activate a pending comment, and add the emails to the subscribers of its parent to
+/// `notifications`. They must be sent once the activation is committed. Returns `false` if the
+/// comment doesn't exist or wasn't pending.
+fn activate(
+    ctx: &ApiContext,
+    store: &dyn CommentStore,
+    id: CommentId,
+    notifications: &mut Vec<Notification>,
+) -> Result<bool, failure::Error> {
+    if !store.activate(id)? {
+        return Ok(false);
+    }
+
+    info!("Activated comment {}", id);
+
+    if let Some(comment) = store.comment(id)? {
+        if let Some(thread) = store.thread(comment.thread_id)? {
+            notifications.extend(reply_notifications(ctx, store, thread, &comment)?);
+        }
+    }
+
+    Ok(true)
+}
+
+//--------------------------------------------------------------------------------------------------
+// Votes
+
+/// Isso stops recording votes past this number, as the voters bloom filter would then produce too
+/// many false positives.
+const MAX_VOTES: i32 = 142;
+
+/// Number of times a vote is tried when concurrent votes keep changing the voters of a comment.
+const VOTE_ATTEMPTS: usize = 5;
+
+#[derive(Serialize)]
+pub struct VoteResponse {
+    likes: i32,
+    dislikes: i32,
+}
+
+/// Like (`upvote == true`) or dislike a comment. Authors cannot vote on their own comments, and
+/// voters can vote only once on a given comment. The number of votes per minute of an address is
+/// limited by `guard.vote_ratelimit`.
+pub fn vote(ctx: &ApiContext, id: CommentId, remote_addr: String, upvote: bool) -> BoxFuture<VoteResponse> {
+    if let Err(e) = guard::check_vote(ctx.config(), ctx.vote_limiter(), &remote_addr) {
+        return futures::failed(e.into()).boxed();
+    }
+
+    ctx.spawn_store(move |store| {
+        // Each attempt is its own transaction, so that it reads the voters written by concurrent votes
+        for _ in 0..VOTE_ATTEMPTS {
+            if let Some(response) = store.atomically(|| try_vote(store, id, &remote_addr, upvote))? {
+                return Ok(response);
+            }
+        }
+
+        Err(failure::err_msg(format!("Too many concurrent votes on comment {}", id)))
+    })
+    .boxed()
+}
+
+/// Record a vote, unless another vote changed the voters of the comment since they were read, in
+/// which case `None` is returned.
+fn try_vote(
+    store: &dyn CommentStore,
+    id: CommentId,
+    remote_addr: &str,
+    upvote: bool,
+) -> Result<Option<VoteResponse>, failure::Error> {
+    let comment = store.comment(id)?.ok_or_else(|| not_found(id))?;
+
+    if comment.likes + comment.dislikes >= MAX_VOTES {
+        return Ok(Some(VoteResponse {
+            likes: comment.likes,
+            dislikes: comment.dislikes,
+        }));
+    }
+
+    let mut voters = bloom::Bloomfilter::from_bytes(&comment.voters);
+
+    if comment.remote_addr == remote_addr {
+        return Err(ApiError::Forbidden(String::from("Cannot vote on your own comment")).into());
+    }
+
+    if voters.contains(remote_addr) {
+        return Err(ApiError::Forbidden(format!("Already voted on comment {}", id)).into());
+    }
+
+    voters.add(remote_addr);
+
+    let updated = store.vote(id, upvote, &comment.voters, voters.as_bytes())?;
+    Ok(updated.map(|comment| VoteResponse {
+        likes: comment.likes,
+        dislikes: comment.dislikes,
+    }))
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -220,91 +652,259 @@ impl FetchRequest {
     }
 }
 
+/// Comments of a thread, in the tree structure expected by Isso's client: the requested comments
+/// (top-level comments or replies to `id`), each top-level comment having its own list of replies.
 #[derive(Serialize)]
 pub struct FetchResponse {
-    id: i32,
-    total_replies: i32,
-    hidden_replies: i32,
+    id: Option<CommentId>,
+    total_replies: i64,
+    hidden_replies: i64,
     replies: Vec<CommentResponse>,
 }
 
-pub fn fetch(ctx: &ApiContext, req: FetchRequest) -> BoxFuture<Vec<CommentResponse>> {
+pub fn fetch(ctx: &ApiContext, req: FetchRequest) -> BoxFuture<FetchResponse> {
     validate!(&req);
 
-    let _root_id = req.parent;
+    let root_id = req.parent;
     let plain = req.is_plain();
 
     let after: f64 = req
         .after
         .map_or(0.0_f64, |date| dieselext::FloatDateTime(date).to_f64());
 
-    let req1 = req.clone();
-    let reply_counts = ctx.spawn_db(move |cnx| models::Comment::reply_count(cnx, req1.uri, None, after));
+    let config = ctx.config().clone();
+
+    ctx.spawn_store(move |store| {
+        let reply_counts: HashMap<Option<CommentId>, i64> =
+            store.reply_count(&req.uri, None, after)?.into_iter().collect();
 
-    // comments
-    let root_list =
-        ctx.spawn_db(move |cnx| models::Comment::fetch(cnx, req.uri, None, after, req.parent, None, true, req.limit));
+        let mut root_list = if req.limit == Some(0) {
+            Vec::new()
+        } else {
+            store.fetch(&req.uri, None, after, root_id, None, true, req.limit)?
+        };
+        markup::render_stale(&config.markup, &mut root_list);
+
+        let total_replies = reply_counts.get(&root_id).cloned().unwrap_or(0);
+        let mut replies = process_fetched_list(&config, &root_list, plain);
+
+        // Only one level of nesting, as in Isso: replies to a reply are attached to the top-level comment.
+        if root_id.is_none() {
+            for comment in &mut replies {
+                let comment_total = reply_counts.get(&Some(comment.id)).cloned().unwrap_or(0);
 
-    reply_counts
-        .join(root_list)
-        .map(move |(_reply_counts, root_list)| process_fetched_list(&root_list, plain))
-        .boxed()
+                let mut nested_list = if comment_total == 0 || req.nested_limit == Some(0) {
+                    Vec::new()
+                } else {
+                    let limit = req.nested_limit.map(|l| l as i64);
+                    store.fetch(&req.uri, None, after, Some(comment.id), None, true, limit)?
+                };
+                markup::render_stale(&config.markup, &mut nested_list);
+
+                comment.total_replies = Some(comment_total);
+                comment.hidden_replies = Some(comment_total - nested_list.len() as i64);
+                comment.replies = Some(process_fetched_list(&config, &nested_list, plain));
+            }
+        }
+
+        Ok(FetchResponse {
+            id: root_id,
+            total_replies,
+            hidden_replies: total_replies - root_list.len() as i64,
+            replies,
+        })
+    })
+    .boxed()
 }
 
-fn process_fetched_list(list: &[models::Comment], plain: bool) -> Vec<CommentResponse> {
-    list.iter()
-        .map(|item| {
-            let mut digest = sha1::Sha1::new();
-            digest.update(item.email.as_ref().unwrap_or(&item.remote_addr).as_bytes());
+fn process_fetched_list(config: &RissoConfig, list: &[models::Comment], plain: bool) -> Vec<CommentResponse> {
+    list.iter().map(|item| comment_response(config, item, plain)).collect()
+}
 
+/// Build the response for a comment, using its cached html and author hash. Comments should be
+/// rendered with `markup::refresh` or `markup::render_stale` beforehand, otherwise those are
+/// computed on the fly.
+fn comment_response(config: &RissoConfig, item: &models::Comment, plain: bool) -> CommentResponse {
+    let hash = item.hash.clone().unwrap_or_else(|| markup::author_hash(item));
+
+    let gravatar_image = match config.general.avatar {
+        AvatarMode::Gravatar => {
             // Fallback on ip-address for the gravatar, for a somewhat stable image
             let email_md5 = format!("{:x}", md5::compute(item.email.as_ref().unwrap_or(&item.remote_addr)));
-            let gravatar_image = GENERAL_CONFIG.gravatar_url.replace("{}", &email_md5);
+            Some(config.general.gravatar_url.replace("{}", &email_md5))
+        }
+        AvatarMode::Local => Some(format!(
+            "{}/avatar/{}.svg",
+            config.general.public_endpoint.trim_end_matches('/'),
+            hash
+        )),
+        AvatarMode::None => None,
+    };
 
-            let text = if plain {
-                item.text.clone()
-            } else {
-                let md_parser = pulldown_cmark::Parser::new(&item.text);
-                let mut html = String::new();
-                pulldown_cmark::html::push_html(&mut html, md_parser);
-                html
-            };
+    let text = if plain {
+        item.text.clone()
+    } else {
+        item.html
+            .clone()
+            .unwrap_or_else(|| markup::render(&config.markup, &item.text))
+    };
 
-            CommentResponse {
-                id: item.id,
-                parent: item.parent,
-                text,
-                author: item.author.clone(),
-                website: item.website.clone(),
-                mode: item.mode,
-                created: item.created.0,
-                modified: item.modified.map(|d| d.0),
-                likes: item.likes,
-                dislikes: item.dislikes,
-
-                hash: digest.digest().to_string(),
-                gravatar_image,
-            }
-        })
-        .collect()
+    CommentResponse {
+        id: item.id,
+        parent: item.parent,
+        text,
+        author: item.author.clone(),
+        website: item.website.clone(),
+        mode: item.mode,
+        created: item.created.0,
+        modified: item.modified.map(|d| d.0),
+        likes: item.likes,
+        dislikes: item.dislikes,
+
+        hash,
+        gravatar_image,
+
+        total_replies: None,
+        hidden_replies: None,
+        replies: None,
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Counts
+
+/// Return the number of valid comments for each of `uris`, in the same order. Unknown uris have
+/// zero comments.
+pub fn counts(ctx: &ApiContext, uris: Vec<String>) -> BoxFuture<Vec<i64>> {
+    ctx.spawn_store(move |store| {
+        let counts: HashMap<String, i64> = store.count(&uris)?.into_iter().collect();
+
+        Ok(uris.iter().map(|uri| counts.get(uri).cloned().unwrap_or(0)).collect())
+    })
+    .boxed()
+}
+
+//--------------------------------------------------------------------------------------------------
+// Preview
+
+#[derive(Clone, Deserialize, Validate)]
+pub struct PreviewRequest {
+    // Same rules as `NewComment`
+    #[validate(length(min = "3", max = "65535"))]
+    text: String,
+}
+
+#[derive(Serialize)]
+pub struct PreviewResponse {
+    text: String,
+}
+
+/// Render a comment's text as it will be displayed once posted.
+pub fn preview(ctx: &ApiContext, req: PreviewRequest) -> BoxFuture<PreviewResponse> {
+    validate!(&req);
+
+    futures::finished(PreviewResponse {
+        text: markup::render(&ctx.config().markup, &req.text),
+    })
+    .boxed()
+}
+
+//--------------------------------------------------------------------------------------------------
+// Atom feed
+
+/// Atom feed of the latest comments on the thread for `uri`. Feeds are disabled if `rss.base`
+/// isn't set.
+pub fn feed(ctx: &ApiContext, uri: String) -> BoxFuture<String> {
+    let base = ctx.config().rss.base.clone();
+    if base.is_empty() {
+        return futures::failed(ApiError::NotFound(String::from("Feeds are disabled")).into()).boxed();
+    }
+
+    let limit = Some(ctx.config().rss.limit);
+    let markup_config = ctx.config().markup.clone();
+
+    ctx.spawn_store(move |store| {
+        // Only valid comments: soft-deleted ones would be empty entries
+        let valid = Some(models::CommentMode::Valid as i32);
+        let mut comments = store.fetch(&uri, valid, 0.0, Some(0), Some("id"), false, limit)?;
+        markup::render_stale(&markup_config, &mut comments);
+
+        Ok(feed::atom_feed(&base, &uri, &comments, |comment| {
+            comment.html.clone().unwrap_or_default()
+        }))
+    })
+    .boxed()
+}
+
+//--------------------------------------------------------------------------------------------------
+// Avatars
+
+/// An avatar image and its mime type.
+pub struct Avatar {
+    pub content_type: &'static str,
+    pub data: Vec<u8>,
+}
+
+/// Identicon for the comment `hash`, in the `svg` or `png` format. Only available if
+/// `general.avatar` is `local`.
+pub fn avatar(ctx: &ApiContext, hash: &str, format: &str) -> BoxFuture<Avatar> {
+    if ctx.config().general.avatar != AvatarMode::Local {
+        return futures::failed(ApiError::NotFound(String::from("Avatars are disabled")).into()).boxed();
+    }
+    if !avatar::is_valid_hash(hash) {
+        return futures::failed(ApiError::NotFound(format!("Invalid avatar hash {}", hash)).into()).boxed();
+    }
+
+    let result = match format {
+        "svg" => Ok(Avatar {
+            content_type: "image/svg+xml",
+            data: avatar::svg(hash).into_bytes(),
+        }),
+        "png" => avatar::png(hash).map(|data| Avatar {
+            content_type: "image/png",
+            data,
+        }),
+        _ => Err(ApiError::NotFound(format!("Unsupported avatar format {}", format)).into()),
+    };
+
+    futures::done(result).boxed()
 }
 
 //--------------------------------------------------------------------------------------------------
 // Unsubscribe
 
-pub fn unsubscribe2(_ctx: &ApiContext) -> BoxFuture<CommentResponse> {
-    futures::done(Err(failure::err_msg("Not implemented yet"))).boxed()
+/// Subject of unsubscribe tokens: the thread id and the subscriber's email.
+fn unsubscribe_subject(thread_id: ThreadId, email: &str) -> String {
+    format!("{}:{}", thread_id, email)
 }
 
-pub fn unsubscribe(_ctx: &ApiContext, id: String, email: String, key: String) -> BoxFuture<()> {
-    drop(id); // make clippy happy until we consume these
-    drop(email);
-    drop(key);
-    futures::done(Err(failure::err_msg("Not implemented yet"))).boxed()
+/// Stop sending reply notifications to `email` for the thread of comment `id`. The email and key
+/// come from the link in notification emails, where the email is percent-encoded: `email` is the
+/// path segment as is, and is decoded here.
+pub fn unsubscribe(ctx: &ApiContext, id: CommentId, email: String, key: String) -> BoxFuture<()> {
+    let email = percent_decode(email.as_bytes()).decode_utf8_lossy().into_owned();
+    let session_key = ctx.session_key().to_vec();
+
+    ctx.spawn_store(move |store| {
+        let comment = store.comment(id)?.ok_or_else(|| not_found(id))?;
+
+        let subject = unsubscribe_subject(comment.thread_id, &email);
+        if !tokens::verify(&session_key, tokens::UNSUBSCRIBE, &subject, &key, None) {
+            return Err(ApiError::Forbidden(format!("Invalid unsubscribe key for {}", email)).into());
+        }
+
+        let count = store.unsubscribe(comment.thread_id, &email)?;
+        info!("Unsubscribed {} from {} comments", email, count);
+
+        Ok(())
+    })
+    .boxed()
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::store::fixtures::memory_api;
     use futures::Future;
     use validator::Validate;
 
@@ -336,6 +936,27 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn new_comment_validation() {
+        let comment = |email: &str, text: &str| super::NewComment {
+            author: Some(String::from("John")),
+            email: Some(String::from(email)),
+            text: String::from(text),
+            parent: None,
+            website: Some(String::new()),
+            title: None,
+            notification: None,
+        };
+
+        // Blank fields are ignored
+        let c = comment(" ", "Hello world").normalize();
+        assert!(c.email.is_none() && c.website.is_none());
+        assert!(c.validate().is_ok());
+
+        assert!(comment("foo", "Hello world").normalize().validate().is_err());
+        assert!(comment("foo@bar.com", "Hi").normalize().validate().is_err());
+    }
+
     #[test]
     #[should_panic]
     fn demonstrate_should_panic() {
@@ -343,4 +964,285 @@ mod tests {
 
         x.unwrap();
     }
+
+    //----------------------------------------------------------------------------------------------
+    // API functions, on an in-memory store
+
+    fn post(ctx: &ApiContext, uri: &str, remote_addr: &str, parent: Option<CommentId>) -> CreatedComment {
+        let req = NewComment {
+            author: Some(String::from("Jane")),
+            email: None,
+            text: String::from("Hello *world*"),
+            parent,
+            website: None,
+            title: Some(String::from("Test page")),
+            notification: None,
+        };
+
+        new_comment(ctx, uri.to_owned(), remote_addr.to_owned(), req)
+            .wait()
+            .unwrap()
+    }
+
+    fn fetch_all(ctx: &ApiContext, uri: &str) -> FetchResponse {
+        let req = FetchRequest {
+            uri: uri.to_owned(),
+            parent: None,
+            limit: None,
+            nested_limit: None,
+            after: None,
+            plain: None,
+        };
+
+        fetch(ctx, req).wait().unwrap()
+    }
+
+    fn api_error<T>(result: Result<T, failure::Error>) -> ApiError {
+        match result {
+            Ok(_) => panic!("Expected an error"),
+            Err(err) => err.downcast::<ApiError>().expect("Expected an ApiError"),
+        }
+    }
+
+    #[test]
+    fn post_and_fetch_comments() {
+        let (_builder, ctx) = memory_api(RissoConfig::default());
+
+        let parent = post(&ctx, "/post", "10.0.0.1", None).comment;
+        let reply = post(&ctx, "/post", "10.0.0.2", Some(parent.id)).comment;
+        // Replies to a reply are attached to the top-level comment
+        let nested = post(&ctx, "/post", "10.0.0.3", Some(reply.id)).comment;
+        assert_eq!(Some(parent.id), nested.parent);
+
+        post(&ctx, "/other", "10.0.0.1", None);
+
+        let response = fetch_all(&ctx, "/post");
+        assert_eq!(1, response.total_replies);
+        assert_eq!(1, response.replies.len());
+
+        let top = &response.replies[0];
+        assert_eq!("<p>Hello <em>world</em></p>\n", top.text);
+        assert_eq!(Some(2), top.total_replies);
+        // Local avatars, on the server's public url
+        let avatar = format!("http://localhost:8080/avatar/{}.svg", top.hash);
+        assert_eq!(Some(&avatar), top.gravatar_image.as_ref());
+        assert_eq!(
+            vec![reply.id, nested.id],
+            top.replies.as_ref().unwrap().iter().map(|c| c.id).collect::<Vec<_>>()
+        );
+
+        let uris = vec![String::from("/post"), String::from("/other"), String::from("/none")];
+        assert_eq!(vec![3, 1, 0], counts(&ctx, uris).wait().unwrap());
+    }
+
+    #[test]
+    fn unknown_parent_is_rejected() {
+        let (_builder, ctx) = memory_api(RissoConfig::default());
+
+        let parent = post(&ctx, "/post", "10.0.0.1", None).comment;
+
+        let req = NewComment {
+            author: None,
+            email: None,
+            text: String::from("Hello"),
+            parent: Some(parent.id),
+            website: None,
+            title: None,
+            notification: None,
+        };
+
+        let result = new_comment(&ctx, String::from("/other"), String::from("10.0.0.2"), req).wait();
+        match api_error(result) {
+            ApiError::BadRequest(_) => {}
+            err => panic!("Unexpected error {}", err),
+        }
+
+        // The thread created for the comment was rolled back
+        assert_eq!(vec![0], counts(&ctx, vec![String::from("/other")]).wait().unwrap());
+        assert_eq!(1, admin::threads(&ctx).wait().unwrap().len());
+    }
+
+    #[test]
+    fn votes() {
+        let (builder, ctx) = memory_api(RissoConfig::default());
+
+        let id = post(&ctx, "/post", "10.0.0.1", None).comment.id;
+
+        match api_error(vote(&ctx, id, String::from("10.0.0.1"), true).wait()) {
+            ApiError::Forbidden(_) => {}
+            err => panic!("Unexpected error {}", err),
+        }
+
+        let response = vote(&ctx, id, String::from("10.0.0.2"), true).wait().unwrap();
+        assert_eq!((1, 0), (response.likes, response.dislikes));
+
+        let response = vote(&ctx, id, String::from("10.0.0.3"), false).wait().unwrap();
+        assert_eq!((1, 1), (response.likes, response.dislikes));
+
+        assert!(vote(&ctx, id, String::from("10.0.0.2"), false).wait().is_err());
+        assert!(vote(&ctx, id + 1, String::from("10.0.0.2"), false).wait().is_err());
+
+        // A vote based on voters that changed in the meantime isn't recorded
+        let stale = builder.storage.run(|store| store.vote(id, true, &[], &[1])).unwrap();
+        assert!(stale.is_none());
+    }
+
+    #[test]
+    fn rate_limits() {
+        let (_builder, ctx) = memory_api(RissoConfig::default());
+
+        post(&ctx, "/a", "10.0.0.1", None);
+        post(&ctx, "/b", "10.0.0.1", None);
+
+        let req = NewComment {
+            author: None,
+            email: None,
+            text: String::from("Hello again"),
+            parent: None,
+            website: None,
+            title: None,
+            notification: None,
+        };
+
+        match api_error(new_comment(&ctx, String::from("/c"), String::from("10.0.0.1"), req).wait()) {
+            ApiError::TooManyRequests { retry_after, .. } => assert!(retry_after > 0 && retry_after <= 60),
+            err => panic!("Unexpected error {}", err),
+        }
+    }
+
+    #[test]
+    fn feed_has_valid_comments_only() {
+        let mut config = RissoConfig::default();
+        config.rss.base = String::from("https://example.com");
+        let (_builder, ctx) = memory_api(config);
+
+        let parent = post(&ctx, "/post", "10.0.0.1", None);
+        let reply = post(&ctx, "/post", "10.0.0.2", Some(parent.comment.id)).comment;
+        // Soft-deleted, as it has a reply
+        delete_comment(&ctx, parent.comment.id, parent.token).wait().unwrap();
+
+        let xml = feed(&ctx, String::from("/post")).wait().unwrap();
+        assert_eq!(1, xml.matches("<entry>").count());
+        assert!(xml.contains(&format!("Comment #{}", reply.id)));
+    }
+
+    #[test]
+    fn reads_dont_store_renderings() {
+        let (builder, ctx) = memory_api(RissoConfig::default());
+
+        let id = post(&ctx, "/post", "10.0.0.1", None).comment.id;
+        // Edited behind the API's back, which clears the cache
+        builder
+            .storage
+            .run(|store| store.update_comment(id, "Hello *again*", Some("Jane"), None))
+            .unwrap();
+
+        let fetched = fetch_all(&ctx, "/post");
+        assert_eq!("<p>Hello <em>again</em></p>\n", fetched.replies[0].text);
+
+        let comment = builder.storage.run(|store| store.comment(id)).unwrap().unwrap();
+        assert_eq!(None, comment.html);
+    }
+
+    #[test]
+    fn unsubscribe_links() {
+        let (_builder, ctx) = memory_api(RissoConfig::default());
+
+        let first = post(&ctx, "/a", "10.0.0.1", None).comment.id;
+        let other = post(&ctx, "/b", "10.0.0.2", None).comment.id;
+        let thread_id = admin::threads(&ctx).wait().unwrap()[0].id;
+
+        let email = "jane+risso%41@example.com";
+        let segment = utf8_percent_encode(email, PATH_SEGMENT_ENCODE_SET).to_string();
+        let key = tokens::sign(
+            ctx.session_key(),
+            tokens::UNSUBSCRIBE,
+            &unsubscribe_subject(thread_id, email),
+        );
+
+        assert!(unsubscribe(&ctx, first, segment.clone(), key.clone()).wait().is_ok());
+
+        // The key only applies to the thread it was sent for
+        match api_error(unsubscribe(&ctx, other, segment, key).wait()) {
+            ApiError::Forbidden(_) => {}
+            err => panic!("Unexpected error {}", err),
+        }
+    }
+
+    #[test]
+    fn reply_notifications_to_subscribers() {
+        let mut config = RissoConfig::default();
+        config.general.reply_notifications = true;
+        let (builder, ctx) = memory_api(config);
+
+        let req = NewComment {
+            author: None,
+            email: Some(String::from("jane@example.com")),
+            text: String::from("Hello"),
+            parent: None,
+            website: None,
+            title: None,
+            notification: Some(1),
+        };
+        let parent = new_comment(&ctx, String::from("/post"), String::from("10.0.0.1"), req)
+            .wait()
+            .unwrap()
+            .comment;
+        let reply = post(&ctx, "/post", "10.0.0.2", Some(parent.id)).comment;
+
+        let notifications = builder
+            .storage
+            .run(|store| {
+                let comment = store.comment(reply.id)?.unwrap();
+                let thread = store.thread(comment.thread_id)?.unwrap();
+                reply_notifications(&ctx, store, thread, &comment)
+            })
+            .unwrap();
+
+        match notifications.as_slice() {
+            [Notification::Reply {
+                recipient,
+                unsubscribe_link,
+                ..
+            }] => {
+                assert_eq!("jane@example.com", recipient.as_str());
+                let prefix = format!("http://localhost:8080/id/{}/unsubscribe/jane@example.com/", parent.id);
+                assert!(unsubscribe_link.starts_with(&prefix));
+            }
+            _ => panic!("Expected a reply notification"),
+        }
+    }
+
+    #[test]
+    fn edit_and_delete() {
+        let (_builder, ctx) = memory_api(RissoConfig::default());
+
+        let parent = post(&ctx, "/post", "10.0.0.1", None);
+        let reply = post(&ctx, "/post", "10.0.0.2", Some(parent.comment.id));
+
+        let edit = EditComment {
+            author: None,
+            text: String::from("Edited"),
+            website: None,
+        };
+
+        assert!(edit_comment(&ctx, parent.comment.id, reply.token.clone(), edit.clone())
+            .wait()
+            .is_err());
+
+        let edited = edit_comment(&ctx, parent.comment.id, parent.token.clone(), edit)
+            .wait()
+            .unwrap();
+        assert_eq!("<p>Edited</p>\n", edited.text);
+        assert!(edited.modified.is_some());
+
+        // The parent has a reply: it is soft-deleted, and removed along with its last reply
+        let deleted = delete_comment(&ctx, parent.comment.id, parent.token).wait().unwrap();
+        assert_eq!(Some(String::new()), deleted.map(|c| c.text));
+
+        let deleted = delete_comment(&ctx, reply.comment.id, reply.token).wait().unwrap();
+        assert!(deleted.is_none());
+
+        assert!(fetch_all(&ctx, "/post").replies.is_empty());
+    }
 }
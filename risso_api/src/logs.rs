@@ -12,27 +12,39 @@ pub mod macros {
     pub use slog::{slog_crit, slog_debug, slog_error, slog_info, slog_log, slog_o, slog_trace, slog_warn};
 }
 
-/// Setup the slog logging framework and the log->slog bridge for crates that use log.
+/// Format of log records.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// One JSON object per line, for log processing tools.
+    Json,
+    /// Human readable text, for development.
+    Text,
+}
+
+/// Setup the slog logging framework and the log->slog bridge for crates that use log. Only records
+/// at `level` or above are output, on stderr.
 ///
 /// The result contains the root logger and a slog-scope global logger guard for this root logger.
 /// The global logger is unset once the guard is dropped.
 ///
-pub fn setup_slog() -> (slog_scope::GlobalLoggerGuard, slog::Logger) {
+pub fn setup_slog(level: slog::Level, format: LogFormat) -> (slog_scope::GlobalLoggerGuard, slog::Logger) {
     use slog::*;
 
-    let decorator = slog_term::TermDecorator::new().force_color().build();
-    let _term_drain = slog_term::FullFormat::new(decorator).build();
-
-    let json_drain = slog_json::Json::default(std::io::stderr());
-
-    // Pick your format
-    //let drain = term_drain;
-    let drain = json_drain;
-
-    // Display only info+
-    let drain = drain.filter_level(Level::Info);
-
-    let drain = slog_async::Async::new(drain.fuse()).build().fuse();
+    let drain = match format {
+        LogFormat::Json => {
+            let json_drain = slog_json::Json::default(std::io::stderr());
+            slog_async::Async::new(json_drain.filter_level(level).fuse())
+                .build()
+                .fuse()
+        }
+        LogFormat::Text => {
+            let decorator = slog_term::TermDecorator::new().stderr().build();
+            let term_drain = slog_term::FullFormat::new(decorator).build();
+            slog_async::Async::new(term_drain.filter_level(level).fuse())
+                .build()
+                .fuse()
+        }
+    };
 
     let log = Logger::root(
         drain,
@@ -0,0 +1,505 @@
+//! Conversion of comments from Markdown to sanitized html, according to the `[markup]` section of
+//! the configuration. All rendered text, be it in API responses, previews or feeds, goes through
+//! `render`.
+//!
+//! The html of comments is cached in the database along with the hash of their author, so that it
+//! is computed only once. The cache is stored when a comment is created or edited, and by
+//! `rerender_all`, which is run at startup when the markup configuration changed or comments were
+//! imported. Read paths don't write to the database: comments without a cache, or with one rendered
+//! for another markup configuration as detected by its fingerprint, are rendered in memory.
+
+use std::mem;
+
+use pulldown_cmark::{html, Event, Options, Parser, Tag};
+
+use crate::config::{MarkdownOption, MarkupConfig};
+use crate::models::{Comment, Preference};
+use crate::store::CommentStore;
+
+/// Version of the rendering code. Incrementing it renders all comments again, e.g. after a change
+/// in the Markdown extensions.
+const RENDERER_VERSION: u32 = 1;
+
+/// Number of comments read at once when rendering all comments.
+const PAGE_SIZE: i64 = 500;
+
+/// Elements produced by Markdown, allowed in addition to the defaults of `ammonia`.
+/// See https://posativ.org/isso/docs/configuration/server/#markup
+const TAGS: &[&str] = &[
+    "a",
+    "blockquote",
+    "br",
+    "code",
+    "del",
+    "em",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "img",
+    "ins",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "strong",
+    "sup",
+    "table",
+    "tbody",
+    "td",
+    "th",
+    "thead",
+    "ul",
+];
+
+/// Forced on all links, so that comments don't give any ranking to the sites they link to, nor
+/// access to the page that opened them.
+const LINK_REL: &str = "nofollow noopener";
+
+/// Convert the Markdown `text` of a comment to html, keeping only the allowed elements and attributes.
+pub fn render(config: &MarkupConfig, text: &str) -> String {
+    let enabled = |option: MarkdownOption| config.options.contains(&option);
+
+    let mut options = Options::empty();
+    if enabled(MarkdownOption::Tables) {
+        options.insert(Options::ENABLE_TABLES);
+    }
+    if enabled(MarkdownOption::Footnotes) {
+        options.insert(Options::ENABLE_FOOTNOTES);
+    }
+
+    let events = Parser::new_ext(text, options).collect();
+    let events = extend(
+        events,
+        enabled(MarkdownOption::Strikethrough),
+        enabled(MarkdownOption::Autolink),
+    );
+
+    let mut html = String::new();
+    html::push_html(&mut html, events.into_iter());
+
+    sanitize_html(config, &html)
+}
+
+/// Identifies the rendering produced by `config`: cached html with another fingerprint is stale.
+pub fn fingerprint(config: &MarkupConfig) -> String {
+    let mut options = config
+        .options
+        .iter()
+        .map(|option| format!("{:?}", option))
+        .collect::<Vec<_>>();
+    options.sort();
+
+    let mut elements = config.allowed_elements.clone();
+    elements.sort();
+    let mut attributes = config.allowed_attributes.clone();
+    attributes.sort();
+
+    let mut digest = sha1::Sha1::new();
+    digest.update(
+        format!(
+            "{};{};{};{}",
+            RENDERER_VERSION,
+            options.join(","),
+            elements.join(","),
+            attributes.join(",")
+        )
+        .as_bytes(),
+    );
+    digest.digest().to_string()
+}
+
+/// Hash identifying the author of a comment, computed from their email or, if they gave none,
+/// from their address.
+pub fn author_hash(comment: &Comment) -> String {
+    let mut digest = sha1::Sha1::new();
+    digest.update(comment.email.as_ref().unwrap_or(&comment.remote_addr).as_bytes());
+    digest.digest().to_string()
+}
+
+/// Fill the cache of the `comments` that have none or were rendered with another configuration,
+/// and store it. Returns the number of comments that were rendered.
+pub fn refresh(
+    config: &MarkupConfig,
+    store: &dyn CommentStore,
+    comments: &mut [Comment],
+) -> Result<usize, failure::Error> {
+    let fingerprint = fingerprint(config);
+    let mut count = 0;
+
+    for comment in comments.iter_mut().filter(|comment| is_stale(comment, &fingerprint)) {
+        cache(config, store, comment, &fingerprint)?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Like `refresh`, but without storing the cache, for read paths. Returns the number of comments
+/// that were rendered.
+pub fn render_stale(config: &MarkupConfig, comments: &mut [Comment]) -> usize {
+    let fingerprint = fingerprint(config);
+    let mut count = 0;
+
+    for comment in comments.iter_mut().filter(|comment| is_stale(comment, &fingerprint)) {
+        fill(config, comment, &fingerprint);
+        count += 1;
+    }
+
+    count
+}
+
+fn is_stale(comment: &Comment, fingerprint: &str) -> bool {
+    let cached = comment.hash.is_some() && comment.html.is_some();
+    !cached || comment.html_fingerprint.as_ref().map(String::as_str) != Some(fingerprint)
+}
+
+/// Render all comments again, whatever the state of their cache, and record the fingerprint of
+/// `config` as the one of the whole cache. Returns the number of comments.
+pub fn rerender_all(config: &MarkupConfig, store: &dyn CommentStore) -> Result<usize, failure::Error> {
+    let fingerprint = fingerprint(config);
+    let mut count = 0;
+    let mut before_id = None;
+
+    loop {
+        let page = store.comments_before(before_id, PAGE_SIZE)?;
+        before_id = match page.last() {
+            Some(last) => Some(last.id),
+            None => break,
+        };
+
+        for mut comment in page {
+            cache(config, store, &mut comment, &fingerprint)?;
+            count += 1;
+        }
+    }
+
+    match store.preference(Preference::MARKUP_FINGERPRINT)? {
+        Some(_) => store.update_preference(Preference::MARKUP_FINGERPRINT, &fingerprint)?,
+        None => store.insert_preference(Preference::MARKUP_FINGERPRINT, &fingerprint)?,
+    }
+
+    Ok(count)
+}
+
+/// Render all comments again if the cache wasn't produced by `config`, e.g. after a change in the
+/// markup configuration or an import. Returns the number of comments, if they were rendered.
+pub fn rerender_if_changed(config: &MarkupConfig, store: &dyn CommentStore) -> Result<Option<usize>, failure::Error> {
+    let cached = store.preference(Preference::MARKUP_FINGERPRINT)?;
+    if cached == Some(fingerprint(config)) {
+        return Ok(None);
+    }
+
+    rerender_all(config, store).map(Some)
+}
+
+/// Record that the cache is incomplete, after comments were added without rendering them, so that
+/// `rerender_if_changed` renders them all again.
+pub fn invalidate(store: &dyn CommentStore) -> Result<(), failure::Error> {
+    if store.preference(Preference::MARKUP_FINGERPRINT)?.is_some() {
+        store.update_preference(Preference::MARKUP_FINGERPRINT, "")?;
+    }
+
+    Ok(())
+}
+
+fn cache(
+    config: &MarkupConfig,
+    store: &dyn CommentStore,
+    comment: &mut Comment,
+    fingerprint: &str,
+) -> Result<(), failure::Error> {
+    let hash = author_hash(comment);
+    let html = render(config, &comment.text);
+    store.set_rendering(comment.id, &hash, &html, fingerprint)?;
+
+    comment.hash = Some(hash);
+    comment.html = Some(html);
+    comment.html_fingerprint = Some(fingerprint.to_owned());
+    Ok(())
+}
+
+fn fill(config: &MarkupConfig, comment: &mut Comment, fingerprint: &str) {
+    comment.hash = Some(author_hash(comment));
+    comment.html = Some(render(config, &comment.text));
+    comment.html_fingerprint = Some(fingerprint.to_owned());
+}
+
+/// Remove the elements and attributes of `html` that aren't allowed.
+pub fn sanitize_html(config: &MarkupConfig, html: &str) -> String {
+    let mut sanitizer = ammonia::Builder::default();
+
+    sanitizer
+        .add_tags(TAGS.iter().cloned())
+        .add_tags(config.allowed_elements.iter().map(String::as_str))
+        .add_generic_attributes(config.allowed_attributes.iter().map(String::as_str))
+        .link_rel(Some(LINK_REL));
+
+    sanitizer.clean(html).to_string()
+}
+
+/// Apply the extensions that pulldown-cmark doesn't provide: strikethrough and autolinks. They
+/// only apply to text outside of links, images and code, and `~~` pairs must be in the same run of
+/// text, i.e. `~~a *b*~~` isn't struck through.
+fn extend(events: Vec<Event>, strikethrough: bool, autolink: bool) -> Vec<Event> {
+    let mut result = Vec::with_capacity(events.len());
+    // The parser splits text on special characters: join consecutive chunks
+    let mut text = String::new();
+    // Nesting depth of links, images and code, whose text is kept as is
+    let mut verbatim = 0;
+
+    for event in events {
+        if let Event::Text(ref chunk) = event {
+            text.push_str(chunk);
+            continue;
+        }
+
+        let plain = verbatim == 0;
+        push_text(
+            mem::replace(&mut text, String::new()),
+            plain && strikethrough,
+            plain && autolink,
+            &mut result,
+        );
+
+        match event {
+            Event::Start(Tag::Link(..))
+            | Event::Start(Tag::Image(..))
+            | Event::Start(Tag::Code)
+            | Event::Start(Tag::CodeBlock(_)) => verbatim += 1,
+            Event::End(Tag::Link(..))
+            | Event::End(Tag::Image(..))
+            | Event::End(Tag::Code)
+            | Event::End(Tag::CodeBlock(_)) => verbatim -= 1,
+            _ => {}
+        }
+        result.push(event);
+    }
+
+    push_text(text, strikethrough, autolink, &mut result);
+    result
+}
+
+fn push_text(text: String, strikethrough: bool, autolink: bool, events: &mut Vec<Event>) {
+    if !strikethrough {
+        push_links(&text, autolink, events);
+        return;
+    }
+
+    let parts: Vec<&str> = text.split("~~").collect();
+    for (idx, part) in parts.iter().enumerate() {
+        if idx % 2 == 0 {
+            push_links(part, autolink, events);
+        } else if idx + 1 < parts.len() {
+            events.push(Event::Html("<del>".into()));
+            push_links(part, autolink, events);
+            events.push(Event::Html("</del>".into()));
+        } else {
+            // Delimiter without a closing one
+            events.push(Event::Text("~~".into()));
+            push_links(part, autolink, events);
+        }
+    }
+}
+
+fn push_links(mut text: &str, autolink: bool, events: &mut Vec<Event>) {
+    while let Some((start, end)) = if autolink { find_url(text) } else { None } {
+        if start > 0 {
+            events.push(Event::Text(text[..start].to_owned().into()));
+        }
+
+        let url = text[start..end].to_owned();
+        events.push(Event::Start(Tag::Link(url.clone().into(), "".into())));
+        events.push(Event::Text(url.clone().into()));
+        events.push(Event::End(Tag::Link(url.into(), "".into())));
+
+        text = &text[end..];
+    }
+
+    if !text.is_empty() {
+        events.push(Event::Text(text.to_owned().into()));
+    }
+}
+
+/// Start and end of the first http or https url in `text`. Trailing punctuation isn't considered
+/// as part of the url.
+fn find_url(text: &str) -> Option<(usize, usize)> {
+    let mut offset = 0;
+
+    while let Some(pos) = text[offset..].find("http") {
+        let start = offset + pos;
+        let candidate = &text[start..];
+
+        let scheme_len = if candidate.starts_with("https://") {
+            8
+        } else if candidate.starts_with("http://") {
+            7
+        } else {
+            0
+        };
+        let at_boundary = text[..start].chars().next_back().map_or(true, |c| !c.is_alphanumeric());
+
+        if scheme_len > 0 && at_boundary {
+            let len = candidate
+                .find(|c: char| c.is_whitespace() || c == '<' || c == '>' || c == '"')
+                .unwrap_or(candidate.len());
+            let url = candidate[..len].trim_end_matches(|c: char| ".,:;!?')".contains(c));
+
+            if url.len() > scheme_len {
+                return Some((start, start + url.len()));
+            }
+        }
+
+        offset = start + 4;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{NewCommentRow, NewThreadRow};
+    use crate::store::fixtures::comment_row;
+    use crate::store::{MemoryStore, Storage};
+
+    fn config(options: Vec<MarkdownOption>) -> MarkupConfig {
+        MarkupConfig {
+            options,
+            allowed_elements: Vec::new(),
+            allowed_attributes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn extensions() {
+        let all = config(vec![
+            MarkdownOption::Tables,
+            MarkdownOption::Strikethrough,
+            MarkdownOption::Footnotes,
+            MarkdownOption::Autolink,
+        ]);
+        let none = config(Vec::new());
+
+        let text = "a ~~b c~~ d";
+        assert_eq!("<p>a <del>b c</del> d</p>\n", render(&all, text));
+        assert_eq!("<p>a ~~b c~~ d</p>\n", render(&none, text));
+        assert_eq!("<p>a ~~b</p>\n", render(&all, "a ~~b"));
+
+        let text = "see https://example.com/a_b?c=1, or `http://example.org`";
+        assert_eq!(
+            "<p>see <a href=\"https://example.com/a_b?c=1\" rel=\"nofollow noopener\">https://example.com/a_b?c=1</a>, \
+             or <code>http://example.org</code></p>\n",
+            render(&all, text)
+        );
+        assert_eq!(
+            "<p>see https://example.com/a_b?c=1, or <code>http://example.org</code></p>\n",
+            render(&none, text)
+        );
+
+        let table = "| a | b |\n|---|---|\n| 1 | 2 |\n";
+        assert!(render(&all, table).starts_with("<table>"));
+        assert!(!render(&none, table).contains("<table>"));
+    }
+
+    #[test]
+    fn sanitization() {
+        let mut config = config(Vec::new());
+
+        assert_eq!(
+            "<p><a href=\"https://example.com\" rel=\"nofollow noopener\">link</a></p>\n",
+            render(&config, "[link](https://example.com)")
+        );
+        assert!(!render(&config, "<script>alert(1)</script>").contains("<script"));
+        assert_eq!("<p>a</p>\n", render(&config, "<p class=\"x\">a</p>"));
+
+        config.allowed_attributes.push("class".to_owned());
+        assert_eq!("<p class=\"x\">a</p>\n", render(&config, "<p class=\"x\">a</p>"));
+    }
+
+    #[test]
+    fn urls() {
+        assert_eq!(Some((4, 22)), find_url("see http://example.com."));
+        assert_eq!(Some((1, 20)), find_url("(https://example.com)"));
+        assert_eq!(None, find_url("xhttp://example.com"));
+        assert_eq!(None, find_url("http:// or https://"));
+    }
+
+    #[test]
+    fn cache() {
+        let storage: &dyn Storage = &MemoryStore::new();
+
+        storage
+            .run(|store| {
+                let thread = store.insert_thread(&NewThreadRow { uri: "/", title: "" })?;
+                store.insert_comment(&NewCommentRow {
+                    email: Some("jane@example.com"),
+                    ..comment_row(thread.id, "~~a~~")
+                })?;
+
+                let mut config = config(Vec::new());
+                let mut comments = store.fetch("/", None, 0.0, None, None, true, None)?;
+                assert_eq!(1, refresh(&config, store, &mut comments)?);
+                assert_eq!(Some("<p>~~a~~</p>\n"), comments[0].html.as_ref().map(String::as_str));
+
+                // Stored, and only rendered again if the configuration changes
+                let mut comments = store.fetch("/", None, 0.0, None, None, true, None)?;
+                assert_eq!(0, refresh(&config, store, &mut comments)?);
+                assert_eq!(Some(author_hash(&comments[0])), comments[0].hash);
+
+                config.options.push(MarkdownOption::Strikethrough);
+                assert_eq!(1, refresh(&config, store, &mut comments)?);
+                assert_eq!(
+                    Some("<p><del>a</del></p>\n"),
+                    comments[0].html.as_ref().map(String::as_str)
+                );
+
+                // Cleared on edits
+                let edited = store.update_comment(comments[0].id, "b", None, None)?.unwrap();
+                assert_eq!(None, edited.html);
+                assert_eq!(1, refresh(&config, store, &mut [edited])?);
+
+                // Only rendered in memory by read paths
+                config.options.clear();
+                let mut comments = store.fetch("/", None, 0.0, None, None, true, None)?;
+                assert_eq!(1, render_stale(&config, &mut comments));
+                assert_eq!(Some("<p>~~b~~</p>\n"), comments[0].html.as_ref().map(String::as_str));
+                let mut comments = store.fetch("/", None, 0.0, None, None, true, None)?;
+                assert_eq!(1, render_stale(&config, &mut comments));
+
+                assert_eq!(1, rerender_all(&config, store)?);
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn rerender_on_changes() {
+        let storage: &dyn Storage = &MemoryStore::new();
+
+        storage
+            .run(|store| {
+                let thread = store.insert_thread(&NewThreadRow { uri: "/", title: "" })?;
+                for _ in 0..PAGE_SIZE + 1 {
+                    store.insert_comment(&comment_row(thread.id, "~~a~~"))?;
+                }
+
+                let mut config = config(Vec::new());
+                assert_eq!(Some(PAGE_SIZE as usize + 1), rerender_if_changed(&config, store)?);
+                assert_eq!(None, rerender_if_changed(&config, store)?);
+
+                config.options.push(MarkdownOption::Strikethrough);
+                assert_eq!(Some(PAGE_SIZE as usize + 1), rerender_if_changed(&config, store)?);
+
+                // After an import
+                invalidate(store)?;
+                assert_eq!(Some(PAGE_SIZE as usize + 1), rerender_if_changed(&config, store)?);
+                assert_eq!(None, rerender_if_changed(&config, store)?);
+                Ok(())
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,248 @@
+//! Database migrations, embedded in the binary so that a fresh database can be created without the
+//! Diesel CLI.
+//!
+//! Applied migrations are recorded in Diesel's `__diesel_schema_migrations` table, so that the CLI
+//! and this module can be used interchangeably.
+//!
+//! Databases created by Isso (or by hand) already have the tables, but no bookkeeping table. When
+//! the bookkeeping table doesn't exist, migrations whose changes are already present in the database
+//! are recorded as applied without being run.
+
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::QueryResult;
+
+use crate::context::Connection;
+use crate::logs::macros::*;
+
+struct Migration {
+    /// Version, as computed by Diesel from the migration's directory name.
+    version: &'static str,
+    name: &'static str,
+    up: &'static str,
+    /// A query that succeeds only if the migration's changes are present in the database.
+    probe: &'static str,
+}
+
+macro_rules! migration {
+    ($backend:expr, $dir:expr, $version:expr, $name:expr, $probe:expr) => {
+        Migration {
+            version: $version,
+            name: $name,
+            up: include_str!(concat!("../migrations/", $backend, "/", $dir, "/up.sql")),
+            probe: $probe,
+        }
+    };
+}
+
+macro_rules! migrations {
+    ($backend:expr) => {
+        &[
+            migration!(
+                $backend,
+                "2018-10-06-170256_create-db",
+                "20181006170256",
+                "create-db",
+                "SELECT tid, voters FROM comments WHERE 1 = 0"
+            ),
+            migration!(
+                $backend,
+                "2018-10-14-150134_add-notifications",
+                "20181014150134",
+                "add-notifications",
+                "SELECT notification FROM comments WHERE 1 = 0"
+            ),
+            migration!(
+                $backend,
+                "2018-12-02-094512_add-rendering-cache",
+                "20181202094512",
+                "add-rendering-cache",
+                "SELECT hash, html, html_fingerprint FROM comments WHERE 1 = 0"
+            ),
+        ]
+    };
+}
+
+#[cfg(feature = "sqlite")]
+const MIGRATIONS: &[Migration] = migrations!("sqlite");
+
+#[cfg(feature = "postgres")]
+const MIGRATIONS: &[Migration] = migrations!("postgres");
+
+#[cfg(feature = "mysql")]
+const MIGRATIONS: &[Migration] = migrations!("mysql");
+
+table! {
+    __diesel_schema_migrations (version) {
+        version -> VarChar,
+    }
+}
+
+use self::__diesel_schema_migrations as bookkeeping;
+
+/// State of a migration in the database.
+#[derive(Debug)]
+pub struct MigrationStatus {
+    pub version: &'static str,
+    pub name: &'static str,
+    pub applied: bool,
+}
+
+/// List all migrations, and whether they have been applied to the database.
+pub fn status(cnx: &Connection) -> QueryResult<Vec<MigrationStatus>> {
+    let applied = applied_versions(cnx)?.unwrap_or_else(Vec::new);
+
+    Ok(MIGRATIONS
+        .iter()
+        .map(|m| MigrationStatus {
+            version: m.version,
+            name: m.name,
+            applied: applied.iter().any(|v| v == m.version),
+        })
+        .collect())
+}
+
+/// Run the migrations that haven't been applied yet, and return them.
+pub fn run_pending(cnx: &Connection) -> QueryResult<Vec<MigrationStatus>> {
+    let applied = match applied_versions(cnx)? {
+        Some(applied) => applied,
+        None => {
+            cnx.batch_execute(
+                "CREATE TABLE IF NOT EXISTS __diesel_schema_migrations (\
+                 version VARCHAR(50) PRIMARY KEY NOT NULL, \
+                 run_on TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP)",
+            )?;
+            adopt_existing_schema(cnx)?
+        }
+    };
+
+    let mut result = Vec::new();
+
+    for migration in MIGRATIONS.iter().filter(|m| !applied.iter().any(|v| v == m.version)) {
+        info!("Running migration {}_{}", migration.version, migration.name);
+
+        cnx.transaction(|| {
+            cnx.batch_execute(migration.up)?;
+            record(cnx, migration)
+        })?;
+
+        result.push(MigrationStatus {
+            version: migration.version,
+            name: migration.name,
+            applied: true,
+        });
+    }
+
+    Ok(result)
+}
+
+/// Versions of applied migrations, or `None` if there is no bookkeeping table.
+fn applied_versions(cnx: &Connection) -> QueryResult<Option<Vec<String>>> {
+    // Probe the table outside of a transaction, as a failed statement aborts PostgreSQL transactions
+    if cnx
+        .batch_execute("SELECT version FROM __diesel_schema_migrations WHERE 1 = 0")
+        .is_err()
+    {
+        return Ok(None);
+    }
+
+    bookkeeping::table.select(bookkeeping::version).load(cnx).map(Some)
+}
+
+/// Record as applied the migrations whose changes are already in the database, stopping at the
+/// first one that is missing. Returns their versions.
+fn adopt_existing_schema(cnx: &Connection) -> QueryResult<Vec<String>> {
+    let mut adopted = Vec::new();
+
+    for migration in MIGRATIONS {
+        if cnx.batch_execute(migration.probe).is_err() {
+            break;
+        }
+
+        info!(
+            "Found existing schema for migration {}_{}, marking it as applied",
+            migration.version, migration.name
+        );
+        record(cnx, migration)?;
+        adopted.push(migration.version.to_owned());
+    }
+
+    Ok(adopted)
+}
+
+fn record(cnx: &Connection, migration: &Migration) -> QueryResult<()> {
+    diesel::insert_into(bookkeeping::table)
+        .values(bookkeeping::version.eq(migration.version))
+        .execute(cnx)
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    fn pending(cnx: &Connection) -> Vec<&'static str> {
+        status(cnx)
+            .unwrap()
+            .into_iter()
+            .filter(|m| !m.applied)
+            .map(|m| m.name)
+            .collect()
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn fresh_database() {
+        let cnx = Connection::establish(":memory:").unwrap();
+        assert_eq!(
+            vec!["create-db", "add-notifications", "add-rendering-cache"],
+            pending(&cnx)
+        );
+
+        assert_eq!(3, run_pending(&cnx).unwrap().len());
+        assert!(pending(&cnx).is_empty());
+
+        // Running again is a no-op
+        assert!(run_pending(&cnx).unwrap().is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn existing_isso_database() {
+        // An old Isso database, without the notification column
+        let cnx = Connection::establish(":memory:").unwrap();
+        cnx.batch_execute(MIGRATIONS[0].up).unwrap();
+
+        let applied = run_pending(&cnx).unwrap();
+        assert_eq!(
+            vec!["add-notifications", "add-rendering-cache"],
+            applied.iter().map(|m| m.name).collect::<Vec<_>>()
+        );
+        assert!(pending(&cnx).is_empty());
+    }
+
+    #[test]
+    fn all_directories_are_listed() {
+        // The list is the same for all backends, only the sql files differ
+        let listed: Vec<String> = MIGRATIONS.iter().map(|m| format!("{}_{}", m.version, m.name)).collect();
+
+        for backend in &["sqlite", "postgres", "mysql"] {
+            let dir = format!("{}/migrations/{}", env!("CARGO_MANIFEST_DIR"), backend);
+
+            let mut found: Vec<String> = fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                // Diesel's version is the directory name up to the first '_', without the dashes
+                .map(|name| match name.find('_') {
+                    Some(pos) => format!("{}{}", name[..pos].replace('-', ""), &name[pos..]),
+                    None => name,
+                })
+                .collect();
+            found.sort();
+
+            assert_eq!(listed, found, "Migrations in {}", dir);
+        }
+    }
+}
@@ -6,16 +6,16 @@ use crate::dieselext::*;
 use crate::logs::macros::*;
 use crate::schema::*;
 
+use diesel::dsl::EqAny;
 use diesel::prelude::*;
 use diesel::result::QueryResult;
-use diesel::sql_types::Bool;
 
 use serde_derive::{Deserialize, Serialize};
 
 use prometheus::Counter;
 
 /// isso [handles comment mode values in a weird way][1]: each possible mode is a bit, and selection
-/// happens by testing against a bitmask rather than using SQL 'IN'.
+/// happens by testing against a bitmask.
 ///
 /// Value 5 is used by default, selecting valid and soft-deleted comments.
 ///
@@ -28,18 +28,78 @@ pub enum CommentMode {
 }
 
 impl CommentMode {
-    pub fn mask(opt_mode: Option<i32>) -> diesel::expression::SqlLiteral<Bool> {
-        let mode = opt_mode.unwrap_or(5);
-        diesel::dsl::sql::<diesel::sql_types::Bool>(&format!("({} | comments.mode) = {}", mode, mode))
+    /// Select comments whose mode is in the `opt_mode` bitmask. The mask is expanded to the list of
+    /// modes it contains and tested with SQL 'IN', which works the same on all database backends.
+    pub fn mask(opt_mode: Option<i32>) -> EqAny<comments::mode, Vec<i32>> {
+        let mask = opt_mode.unwrap_or(5);
+
+        let modes = [
+            CommentMode::Valid as i32,
+            CommentMode::Pending as i32,
+            CommentMode::SoftDeleted as i32,
+        ]
+        .iter()
+        .cloned()
+        .filter(|mode| mask & mode != 0)
+        .collect::<Vec<_>>();
+
+        comments::mode.eq_any(modes)
+    }
+
+    /// Is `mode` selected by the `opt_mode` bitmask? Same as `mask`, for comments in memory.
+    pub fn in_mask(opt_mode: Option<i32>, mode: i32) -> bool {
+        opt_mode.unwrap_or(5) & mode != 0
     }
 }
 
-#[derive(Queryable, Debug)]
+#[derive(Queryable, Insertable, Debug)]
+#[table_name = "preferences"]
 pub struct Preference {
     pub key: String,
     pub value: String,
 }
 
+impl Preference {
+    /// Key of the secret used to sign tokens. Same name as in Isso.
+    pub const SESSION_KEY: &'static str = "session-key";
+
+    /// Key of the markup fingerprint that all cached renderings were produced with, if any.
+    pub const MARKUP_FINGERPRINT: &'static str = "markup-fingerprint";
+
+    /// Return the value of a preference, if it exists.
+    pub fn get(cnx: &context::Connection, key: &str) -> QueryResult<Option<String>> {
+        preferences::table
+            .find(key)
+            .select(preferences::value)
+            .first(cnx)
+            .optional()
+    }
+
+    /// Return all preferences, sorted by key.
+    pub fn list(cnx: &context::Connection) -> QueryResult<Vec<(String, String)>> {
+        preferences::table
+            .select((preferences::key, preferences::value))
+            .order(preferences::key.asc())
+            .load(cnx)
+    }
+
+    /// Store a new preference.
+    pub fn insert(cnx: &context::Connection, key: &str, value: &str) -> QueryResult<()> {
+        diesel::insert_into(preferences::table)
+            .values((preferences::key.eq(key), preferences::value.eq(value)))
+            .execute(cnx)
+            .map(|_| ())
+    }
+
+    /// Change the value of an existing preference.
+    pub fn update(cnx: &context::Connection, key: &str, value: &str) -> QueryResult<()> {
+        diesel::update(preferences::table.find(key))
+            .set(preferences::value.eq(value))
+            .execute(cnx)
+            .map(|_| ())
+    }
+}
+
 #[derive(Clone, Queryable, Debug, Serialize, Deserialize)]
 pub struct Thread {
     pub id: i32,
@@ -47,7 +107,14 @@ pub struct Thread {
     pub title: String,
 }
 
-#[derive(Queryable, Debug, Serialize)]
+#[derive(Insertable, Debug)]
+#[table_name = "threads"]
+pub struct NewThreadRow<'a> {
+    pub uri: &'a str,
+    pub title: &'a str,
+}
+
+#[derive(Clone, Queryable, Debug, Serialize, Deserialize)]
 pub struct Comment {
     pub thread_id: i32,
     pub id: i32,
@@ -64,6 +131,33 @@ pub struct Comment {
     pub dislikes: i32,
     pub notification: bool,
     pub voters: Vec<u8>,
+    /// Hash identifying the author, computed from their email or address. Like `html`, it is a
+    /// cache that isn't exported, and is filled when the comment is first read.
+    #[serde(skip)]
+    pub hash: Option<String>,
+    /// Html rendering of `text`, cleared when the text changes.
+    #[serde(skip)]
+    pub html: Option<String>,
+    /// Fingerprint of the markup configuration `html` was rendered with.
+    #[serde(skip)]
+    pub html_fingerprint: Option<String>,
+}
+
+/// A comment to be inserted. Dates are provided as floats (see `FloatDateTime`).
+#[derive(Insertable, Debug)]
+#[table_name = "comments"]
+pub struct NewCommentRow<'a> {
+    pub thread_id: i32,
+    pub parent: Option<i32>,
+    pub created: f64,
+    pub mode: i32,
+    pub remote_addr: &'a str,
+    pub text: &'a str,
+    pub author: Option<&'a str>,
+    pub email: Option<&'a str>,
+    pub website: Option<&'a str>,
+    pub notification: bool,
+    pub voters: &'a [u8],
 }
 
 lazy_static! {
@@ -75,7 +169,330 @@ lazy_static! {
     .unwrap();
 }
 
+impl Thread {
+    /// Return the thread with a given id, if any.
+    pub fn get(cnx: &context::Connection, id: i32) -> QueryResult<Option<Self>> {
+        threads::table.find(id).first(cnx).optional()
+    }
+
+    /// Return all threads, sorted by uri.
+    pub fn list(cnx: &context::Connection) -> QueryResult<Vec<Self>> {
+        threads::table.order(threads::uri.asc()).load(cnx)
+    }
+
+    /// Return the thread for `uri`, if any.
+    pub fn get_by_uri(cnx: &context::Connection, uri: &str) -> QueryResult<Option<Self>> {
+        threads::table.filter(threads::uri.eq(uri)).first(cnx).optional()
+    }
+
+    /// Change the uri and title of a thread and return it.
+    pub fn update(cnx: &context::Connection, id: i32, uri: &str, title: &str) -> QueryResult<Self> {
+        diesel::update(threads::table.find(id))
+            .set((threads::uri.eq(uri), threads::title.eq(title)))
+            .execute(cnx)?;
+
+        threads::table.find(id).first(cnx)
+    }
+
+    /// Create a new thread and return it.
+    #[cfg(feature = "postgres")]
+    pub fn insert(cnx: &context::Connection, row: &NewThreadRow) -> QueryResult<Self> {
+        diesel::insert_into(threads::table).values(row).get_result(cnx)
+    }
+
+    /// Create a new thread and return it.
+    #[cfg(not(feature = "postgres"))]
+    pub fn insert(cnx: &context::Connection, row: &NewThreadRow) -> QueryResult<Self> {
+        diesel::insert_into(threads::table).values(row).execute(cnx)?;
+        let id = dieselext::inserted_id(cnx)?;
+
+        threads::table.find(id).first(cnx)
+    }
+
+    /// Insert a thread as is, including its id. Used by imports.
+    pub fn restore(cnx: &context::Connection, thread: &Thread) -> QueryResult<()> {
+        diesel::insert_into(threads::table)
+            .values((
+                threads::id.eq(thread.id),
+                threads::uri.eq(&thread.uri),
+                threads::title.eq(&thread.title),
+            ))
+            .execute(cnx)?;
+
+        dieselext::sync_sequence(cnx, "threads")
+    }
+
+    /// Highest thread id, if there are any threads.
+    pub fn max_id(cnx: &context::Connection) -> QueryResult<Option<i32>> {
+        threads::table.select(diesel::dsl::max(threads::id)).first(cnx)
+    }
+}
+
 impl Comment {
+    /// Return the comment with a given id, if any.
+    pub fn get(cnx: &context::Connection, id: i32) -> QueryResult<Option<Self>> {
+        comments::table.find(id).first(cnx).optional()
+    }
+
+    /// Create a new comment and return it.
+    #[cfg(feature = "postgres")]
+    pub fn insert(cnx: &context::Connection, row: &NewCommentRow) -> QueryResult<Self> {
+        diesel::insert_into(comments::table).values(row).get_result(cnx)
+    }
+
+    /// Create a new comment and return it.
+    #[cfg(not(feature = "postgres"))]
+    pub fn insert(cnx: &context::Connection, row: &NewCommentRow) -> QueryResult<Self> {
+        diesel::insert_into(comments::table).values(row).execute(cnx)?;
+        let id = dieselext::inserted_id(cnx)?;
+
+        comments::table.find(id).first(cnx)
+    }
+
+    /// Insert a comment as is, including its id, dates and votes. Used by imports. The rendering
+    /// cache is left empty.
+    pub fn restore(cnx: &context::Connection, comment: &Comment) -> QueryResult<()> {
+        diesel::insert_into(comments::table)
+            .values((
+                comments::thread_id.eq(comment.thread_id),
+                comments::id.eq(comment.id),
+                comments::parent.eq(comment.parent),
+                comments::created.eq(comment.created.to_f64()),
+                comments::modified.eq(comment.modified.map(|date| date.to_f64())),
+                comments::mode.eq(comment.mode),
+                comments::remote_addr.eq(&comment.remote_addr),
+                comments::text.eq(&comment.text),
+                comments::author.eq(&comment.author),
+                comments::email.eq(&comment.email),
+                comments::website.eq(&comment.website),
+                comments::likes.eq(comment.likes),
+                comments::dislikes.eq(comment.dislikes),
+                comments::notification.eq(comment.notification),
+                comments::voters.eq(&comment.voters),
+            ))
+            .execute(cnx)?;
+
+        dieselext::sync_sequence(cnx, "comments")
+    }
+
+    /// Highest comment id, if there are any comments.
+    pub fn max_id(cnx: &context::Connection) -> QueryResult<Option<i32>> {
+        comments::table.select(diesel::dsl::max(comments::id)).first(cnx)
+    }
+
+    /// Update the text, author and website of a comment and return it. Its html rendering is
+    /// cleared.
+    pub fn update(
+        cnx: &context::Connection,
+        id: i32,
+        text: &str,
+        author: Option<&str>,
+        website: Option<&str>,
+    ) -> QueryResult<Option<Self>> {
+        let modified = FloatDateTime(chrono::Utc::now()).to_f64();
+
+        diesel::update(comments::table.find(id))
+            .set((
+                comments::text.eq(text),
+                comments::author.eq(author),
+                comments::website.eq(website),
+                comments::modified.eq(Some(modified)),
+                comments::html.eq(None::<String>),
+                comments::html_fingerprint.eq(None::<String>),
+            ))
+            .execute(cnx)?;
+
+        Self::get(cnx, id)
+    }
+
+    /// Store the author hash and html rendering of a comment.
+    pub fn set_rendering(
+        cnx: &context::Connection,
+        id: i32,
+        hash: &str,
+        html: &str,
+        fingerprint: &str,
+    ) -> QueryResult<()> {
+        diesel::update(comments::table.find(id))
+            .set((
+                comments::hash.eq(hash),
+                comments::html.eq(html),
+                comments::html_fingerprint.eq(fingerprint),
+            ))
+            .execute(cnx)?;
+
+        Ok(())
+    }
+
+    /// Record a vote on a comment and replace its voters bloom filter, if the filter is still
+    /// `old_voters`. Returns the updated comment, or `None` if it doesn't exist or its voters
+    /// changed since they were read, i.e. another vote was recorded in between.
+    ///
+    /// Checking the previous filter in the `UPDATE` rather than locking the row works the same on
+    /// all backends: of two concurrent votes, only the first one matches a row.
+    pub fn vote(
+        cnx: &context::Connection,
+        id: i32,
+        upvote: bool,
+        old_voters: &[u8],
+        voters: &[u8],
+    ) -> QueryResult<Option<Self>> {
+        let target = comments::table.find(id).filter(comments::voters.eq(old_voters));
+
+        let count = if upvote {
+            diesel::update(target)
+                .set((comments::likes.eq(comments::likes + 1), comments::voters.eq(voters)))
+                .execute(cnx)?
+        } else {
+            diesel::update(target)
+                .set((
+                    comments::dislikes.eq(comments::dislikes + 1),
+                    comments::voters.eq(voters),
+                ))
+                .execute(cnx)?
+        };
+
+        if count == 0 {
+            Ok(None)
+        } else {
+            Self::get(cnx, id)
+        }
+    }
+
+    /// Comments posted from `remote_addr` after `after`, only on a thread if `thread_id` is given,
+    /// oldest first.
+    pub fn by_addr(
+        cnx: &context::Connection,
+        remote_addr: &str,
+        after: f64,
+        thread_id: Option<i32>,
+    ) -> QueryResult<Vec<Self>> {
+        let mut q = comments::table
+            .filter(comments::remote_addr.eq(remote_addr))
+            .filter(comments::created.gt(after))
+            .order(comments::created.asc())
+            .into_boxed();
+
+        if let Some(thread_id) = thread_id {
+            q = q.filter(comments::thread_id.eq(thread_id));
+        }
+
+        q.load(cnx)
+    }
+
+    /// Has a comment posted with `email` already been approved?
+    pub fn is_email_approved(cnx: &context::Connection, email: &str) -> QueryResult<bool> {
+        comments::table
+            .filter(comments::email.eq(email))
+            .filter(CommentMode::mask(Some(CommentMode::Valid as i32)))
+            .select(comments::id)
+            .first::<i32>(cnx)
+            .optional()
+            .map(|id| id.is_some())
+    }
+
+    /// Comments whose authors asked to be notified of replies to `parent`: the parent itself and
+    /// the other replies to it.
+    pub fn reply_subscribers(cnx: &context::Connection, parent: i32) -> QueryResult<Vec<Self>> {
+        comments::table
+            .filter(
+                comments::id.eq(parent).or(comments::parent
+                    .eq(parent)
+                    .and(CommentMode::mask(Some(CommentMode::Valid as i32)))),
+            )
+            .filter(comments::notification.eq(true).and(comments::email.is_not_null()))
+            .order(comments::id.asc())
+            .load(cnx)
+    }
+
+    /// Disable reply notifications for comments posted with `email` on a thread. Returns the number
+    /// of updated comments.
+    pub fn unsubscribe(cnx: &context::Connection, thread_id: i32, email: &str) -> QueryResult<usize> {
+        diesel::update(
+            comments::table
+                .filter(comments::thread_id.eq(thread_id))
+                .filter(comments::email.eq(email)),
+        )
+        .set(comments::notification.eq(false))
+        .execute(cnx)
+    }
+
+    /// Activate a pending comment. Returns `false` if the comment doesn't exist or wasn't pending.
+    pub fn activate(cnx: &context::Connection, id: i32) -> QueryResult<bool> {
+        diesel::update(
+            comments::table
+                .find(id)
+                .filter(comments::mode.eq(CommentMode::Pending as i32)),
+        )
+        .set(comments::mode.eq(CommentMode::Valid as i32))
+        .execute(cnx)
+        .map(|count| count > 0)
+    }
+
+    /// Put a valid comment back in the moderation queue. Returns `false` if the comment doesn't
+    /// exist or wasn't valid.
+    pub fn deactivate(cnx: &context::Connection, id: i32) -> QueryResult<bool> {
+        diesel::update(
+            comments::table
+                .find(id)
+                .filter(comments::mode.eq(CommentMode::Valid as i32)),
+        )
+        .set(comments::mode.eq(CommentMode::Pending as i32))
+        .execute(cnx)
+        .map(|count| count > 0)
+    }
+
+    /// Delete a comment. A comment that has replies cannot be removed without breaking the thread,
+    /// so it is soft-deleted: its content is cleared and it is returned with its new state.
+    ///
+    /// Soft-deleted comments whose replies have all been deleted are removed in the process.
+    pub fn delete(cnx: &context::Connection, id: i32) -> QueryResult<Option<Self>> {
+        let has_replies = comments::table
+            .filter(comments::parent.eq(id))
+            .select(comments::id)
+            .first::<i32>(cnx)
+            .optional()?
+            .is_some();
+
+        let result = if has_replies {
+            diesel::update(comments::table.find(id))
+                .set((
+                    comments::text.eq(""),
+                    comments::author.eq(None::<String>),
+                    comments::website.eq(None::<String>),
+                    comments::mode.eq(CommentMode::SoftDeleted as i32),
+                    comments::html.eq(None::<String>),
+                    comments::html_fingerprint.eq(None::<String>),
+                ))
+                .execute(cnx)?;
+            Self::get(cnx, id)?
+        } else {
+            diesel::delete(comments::table.find(id)).execute(cnx)?;
+            None
+        };
+
+        Self::remove_stale(cnx)?;
+
+        Ok(result)
+    }
+
+    /// Remove soft-deleted comments that have no replies anymore. Repeated until there are no
+    /// more of them, as each pass can orphan soft-deleted parents.
+    ///
+    /// The subquery is wrapped in a derived table, as MySQL doesn't allow selecting from the table
+    /// rows are deleted from.
+    fn remove_stale(cnx: &context::Connection) -> QueryResult<()> {
+        let stmt = diesel::sql_query(format!(
+            "DELETE FROM comments WHERE mode = {} AND id NOT IN \
+             (SELECT parent FROM (SELECT parent FROM comments WHERE parent IS NOT NULL) AS replies)",
+            CommentMode::SoftDeleted as i32
+        ));
+
+        while stmt.clone().execute(cnx)? > 0 {}
+
+        Ok(())
+    }
+
     /// Return comments for `uri` with `mode`.
     #[allow(clippy::too_many_arguments)]
     pub fn fetch(
@@ -138,6 +555,71 @@ impl Comment {
         q.load(cnx)
     }
 
+    /// Return comments across all threads along with their thread, most recent first, and the total
+    /// number of comments matching the filters. `search` is looked up in the text, author and email,
+    /// and `after` and `before` are bounds of the creation date.
+    #[allow(clippy::too_many_arguments)]
+    pub fn list(
+        cnx: &context::Connection,
+        mode: Option<i32>,
+        uri: Option<&str>,
+        search: Option<&str>,
+        after: Option<f64>,
+        before: Option<f64>,
+        limit: i64,
+        offset: i64,
+    ) -> QueryResult<(Vec<(Self, Thread)>, i64)> {
+        let pattern = search.map(|s| format!("%{}%", s));
+
+        let filtered = || {
+            let mut q = comments::table.inner_join(threads::table).into_boxed();
+
+            if mode.is_some() {
+                q = q.filter(CommentMode::mask(mode));
+            }
+            if let Some(uri) = uri {
+                q = q.filter(threads::uri.eq(uri));
+            }
+            if let Some(ref pattern) = pattern {
+                let pattern = pattern.as_str();
+                q = q.filter(
+                    comments::text
+                        .like(pattern)
+                        .or(comments::author.like(pattern))
+                        .or(comments::email.like(pattern)),
+                );
+            }
+            if let Some(after) = after {
+                q = q.filter(comments::created.ge(after));
+            }
+            if let Some(before) = before {
+                q = q.filter(comments::created.lt(before));
+            }
+
+            q
+        };
+
+        let total = filtered().count().get_result(cnx)?;
+
+        let stmt = filtered().order(comments::id.desc()).limit(limit).offset(offset);
+
+        trace!("{:?}", diesel::debug_query::<context::DB, _>(&stmt));
+
+        Ok((stmt.load(cnx)?, total))
+    }
+
+    /// Up to `limit` comments with an id lower than `before_id`, or the latest ones if it's `None`,
+    /// by descending id.
+    pub fn before(cnx: &context::Connection, before_id: Option<i32>, limit: i64) -> QueryResult<Vec<Self>> {
+        let mut q = comments::table.into_boxed();
+
+        if let Some(before_id) = before_id {
+            q = q.filter(comments::id.lt(before_id));
+        }
+
+        q.order(comments::id.desc()).limit(limit).load(cnx)
+    }
+
     /// Return comment count for main thread and all reply threads for one url.
     pub fn reply_count(
         cnx: &context::Connection,
@@ -160,4 +642,90 @@ impl Comment {
 
         stmt.load(cnx)
     }
+
+    /// Return the number of valid comments for each of `uris`. Threads without comments aren't
+    /// part of the result.
+    pub fn count(cnx: &context::Connection, uris: Vec<String>) -> QueryResult<Vec<(String, i64)>> {
+        let stmt = comments::table
+            .inner_join(threads::table)
+            .select((threads::uri, dieselext::count_star()))
+            .filter(
+                threads::uri
+                    .eq_any(uris)
+                    .and(CommentMode::mask(Some(CommentMode::Valid as i32))),
+            )
+            .group_by(threads::uri);
+
+        trace!("{:?}", diesel::debug_query::<context::DB, _>(&stmt));
+
+        stmt.load(cnx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Tests of the query layer against a real database, to check that it works the same on all
+    //! backends. The database url is given by `RISSO_TEST_DATABASE_URL`, and its schema must have been
+    //! created with the migrations of the selected backend (see `make test-postgres`).
+
+    use super::*;
+    use diesel::Connection;
+
+    #[test]
+    #[ignore]
+    fn query_layer() {
+        let url = std::env::var("RISSO_TEST_DATABASE_URL").expect("RISSO_TEST_DATABASE_URL is not set");
+        let cnx = context::Connection::establish(&url).unwrap();
+        let uri = "/test/query-layer";
+
+        cnx.test_transaction::<_, diesel::result::Error, _>(|| {
+            let thread = Thread::insert(&cnx, &NewThreadRow { uri, title: "Test" })?;
+
+            let row = |parent: Option<i32>, mode: CommentMode| NewCommentRow {
+                thread_id: thread.id,
+                parent,
+                created: FloatDateTime(chrono::Utc::now()).to_f64(),
+                mode: mode as i32,
+                remote_addr: "127.0.0.1",
+                text: "Hello",
+                author: Some("Jane"),
+                email: Some("jane@example.com"),
+                website: None,
+                notification: true,
+                voters: &[0; 256],
+            };
+
+            let parent = Comment::insert(&cnx, &row(None, CommentMode::Valid))?;
+            let reply = Comment::insert(&cnx, &row(Some(parent.id), CommentMode::Valid))?;
+            Comment::insert(&cnx, &row(None, CommentMode::Pending))?;
+
+            assert_eq!(thread.id, Thread::get_by_uri(&cnx, uri)?.unwrap().id);
+            assert_eq!(vec![(uri.to_owned(), 2)], Comment::count(&cnx, vec![uri.to_owned()])?);
+
+            let top_level = Comment::fetch(&cnx, uri.to_owned(), None, 0.0, None, None, true, None)?;
+            assert_eq!(vec![parent.id], top_level.iter().map(|c| c.id).collect::<Vec<_>>());
+
+            let mut counts = Comment::reply_count(&cnx, uri.to_owned(), None, 0.0)?;
+            counts.sort();
+            assert_eq!(vec![(None, 1), (Some(parent.id), 1)], counts);
+
+            let voted = Comment::vote(&cnx, reply.id, true, &reply.voters, &[1; 256])?;
+            assert_eq!(Some(1), voted.map(|c| c.likes));
+            // The voters changed since `reply` was read
+            assert!(Comment::vote(&cnx, reply.id, true, &reply.voters, &[2; 256])?.is_none());
+
+            assert!(Comment::is_email_approved(&cnx, "jane@example.com")?);
+            assert_eq!(2, Comment::reply_subscribers(&cnx, parent.id)?.len());
+
+            let (pending, total) = Comment::list(&cnx, Some(2), Some(uri), Some("ell"), None, None, 10, 0)?;
+            assert_eq!((1, 1), (pending.len(), total));
+
+            // The parent is soft-deleted as it has a reply, and removed with its last reply
+            assert!(Comment::delete(&cnx, parent.id)?.is_some());
+            assert!(Comment::delete(&cnx, reply.id)?.is_none());
+            assert!(Comment::get(&cnx, parent.id)?.is_none());
+
+            Ok(())
+        });
+    }
 }
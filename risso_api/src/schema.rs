@@ -36,6 +36,9 @@ table! {
         dislikes -> Integer,
         notification -> Bool,
         voters -> Binary, // bloom_filter(remote_addr), initialized with poster's address so he can't vote on himself
+        hash -> Nullable<Text>, // Rendering cache, not in Isso's schema
+        html -> Nullable<Text>,
+        html_fingerprint -> Nullable<Text>,
     }
 }
 
@@ -0,0 +1,194 @@
+//! Storage in the database selected by the cargo features, using the queries in `models`.
+
+use diesel::r2d2::{ConnectionManager, Pool};
+
+use super::{CommentStore, Storage};
+use crate::context::Connection;
+use crate::models::{Comment, NewCommentRow, NewThreadRow, Preference, Thread};
+
+/// A pool of database connections.
+#[derive(Clone)]
+pub struct DieselStore {
+    pool: Pool<ConnectionManager<Connection>>,
+}
+
+impl DieselStore {
+    pub fn new(pool: Pool<ConnectionManager<Connection>>) -> Self {
+        DieselStore { pool }
+    }
+
+    pub fn pool(&self) -> &Pool<ConnectionManager<Connection>> {
+        &self.pool
+    }
+}
+
+impl Storage for DieselStore {
+    fn with_store(
+        &self,
+        f: &mut dyn FnMut(&dyn CommentStore) -> Result<(), failure::Error>,
+    ) -> Result<(), failure::Error> {
+        let cnx = self.pool.get()?;
+        f(&*cnx)
+    }
+}
+
+impl CommentStore for Connection {
+    fn preference(&self, key: &str) -> Result<Option<String>, failure::Error> {
+        Ok(Preference::get(self, key)?)
+    }
+
+    fn preferences(&self) -> Result<Vec<(String, String)>, failure::Error> {
+        Ok(Preference::list(self)?)
+    }
+
+    fn insert_preference(&self, key: &str, value: &str) -> Result<(), failure::Error> {
+        Ok(Preference::insert(self, key, value)?)
+    }
+
+    fn update_preference(&self, key: &str, value: &str) -> Result<(), failure::Error> {
+        Ok(Preference::update(self, key, value)?)
+    }
+
+    fn thread(&self, id: i32) -> Result<Option<Thread>, failure::Error> {
+        Ok(Thread::get(self, id)?)
+    }
+
+    fn thread_by_uri(&self, uri: &str) -> Result<Option<Thread>, failure::Error> {
+        Ok(Thread::get_by_uri(self, uri)?)
+    }
+
+    fn threads(&self) -> Result<Vec<Thread>, failure::Error> {
+        Ok(Thread::list(self)?)
+    }
+
+    fn insert_thread(&self, row: &NewThreadRow) -> Result<Thread, failure::Error> {
+        Ok(Thread::insert(self, row)?)
+    }
+
+    fn update_thread(&self, id: i32, uri: &str, title: &str) -> Result<Thread, failure::Error> {
+        Ok(Thread::update(self, id, uri, title)?)
+    }
+
+    fn comment(&self, id: i32) -> Result<Option<Comment>, failure::Error> {
+        Ok(Comment::get(self, id)?)
+    }
+
+    fn insert_comment(&self, row: &NewCommentRow) -> Result<Comment, failure::Error> {
+        Ok(Comment::insert(self, row)?)
+    }
+
+    fn update_comment(
+        &self,
+        id: i32,
+        text: &str,
+        author: Option<&str>,
+        website: Option<&str>,
+    ) -> Result<Option<Comment>, failure::Error> {
+        Ok(Comment::update(self, id, text, author, website)?)
+    }
+
+    fn set_rendering(&self, id: i32, hash: &str, html: &str, fingerprint: &str) -> Result<(), failure::Error> {
+        Ok(Comment::set_rendering(self, id, hash, html, fingerprint)?)
+    }
+
+    fn vote(&self, id: i32, upvote: bool, old_voters: &[u8], voters: &[u8]) -> Result<Option<Comment>, failure::Error> {
+        Ok(Comment::vote(self, id, upvote, old_voters, voters)?)
+    }
+
+    fn comments_by_addr(
+        &self,
+        remote_addr: &str,
+        after: f64,
+        thread_id: Option<i32>,
+    ) -> Result<Vec<Comment>, failure::Error> {
+        Ok(Comment::by_addr(self, remote_addr, after, thread_id)?)
+    }
+
+    fn is_email_approved(&self, email: &str) -> Result<bool, failure::Error> {
+        Ok(Comment::is_email_approved(self, email)?)
+    }
+
+    fn reply_subscribers(&self, parent: i32) -> Result<Vec<Comment>, failure::Error> {
+        Ok(Comment::reply_subscribers(self, parent)?)
+    }
+
+    fn unsubscribe(&self, thread_id: i32, email: &str) -> Result<usize, failure::Error> {
+        Ok(Comment::unsubscribe(self, thread_id, email)?)
+    }
+
+    fn activate(&self, id: i32) -> Result<bool, failure::Error> {
+        Ok(Comment::activate(self, id)?)
+    }
+
+    fn deactivate(&self, id: i32) -> Result<bool, failure::Error> {
+        Ok(Comment::deactivate(self, id)?)
+    }
+
+    fn delete_comment(&self, id: i32) -> Result<Option<Comment>, failure::Error> {
+        Ok(Comment::delete(self, id)?)
+    }
+
+    fn fetch(
+        &self,
+        uri: &str,
+        mode: Option<i32>,
+        after: f64,
+        parent: Option<i32>,
+        order_by: Option<&str>,
+        asc: bool,
+        limit: Option<i64>,
+    ) -> Result<Vec<Comment>, failure::Error> {
+        let order_by = order_by.map(String::from);
+        Ok(Comment::fetch(
+            self,
+            uri.to_owned(),
+            mode,
+            after,
+            parent,
+            order_by,
+            asc,
+            limit,
+        )?)
+    }
+
+    fn reply_count(&self, uri: &str, mode: Option<i32>, after: f64) -> Result<Vec<(Option<i32>, i64)>, failure::Error> {
+        Ok(Comment::reply_count(self, uri.to_owned(), mode, after)?)
+    }
+
+    fn count(&self, uris: &[String]) -> Result<Vec<(String, i64)>, failure::Error> {
+        Ok(Comment::count(self, uris.to_vec())?)
+    }
+
+    fn list(
+        &self,
+        mode: Option<i32>,
+        uri: Option<&str>,
+        search: Option<&str>,
+        after: Option<f64>,
+        before: Option<f64>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<(Comment, Thread)>, i64), failure::Error> {
+        Ok(Comment::list(self, mode, uri, search, after, before, limit, offset)?)
+    }
+
+    fn comments_before(&self, before_id: Option<i32>, limit: i64) -> Result<Vec<Comment>, failure::Error> {
+        Ok(Comment::before(self, before_id, limit)?)
+    }
+
+    fn restore_thread(&self, thread: &Thread) -> Result<(), failure::Error> {
+        Ok(Thread::restore(self, thread)?)
+    }
+
+    fn restore_comment(&self, comment: &Comment) -> Result<(), failure::Error> {
+        Ok(Comment::restore(self, comment)?)
+    }
+
+    fn max_ids(&self) -> Result<(Option<i32>, Option<i32>), failure::Error> {
+        Ok((Thread::max_id(self)?, Comment::max_id(self)?))
+    }
+
+    fn transaction(&self, f: &mut dyn FnMut() -> Result<(), failure::Error>) -> Result<(), failure::Error> {
+        diesel::Connection::transaction::<_, failure::Error, _>(self, || f())
+    }
+}
@@ -0,0 +1,574 @@
+//! Storage in memory, lost when the process stops. Useful for tests, and for embedders that want
+//! to try Risso without a database.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::Mutex;
+
+use chrono::Utc;
+
+use super::{CommentStore, Storage};
+use crate::dieselext::FloatDateTime;
+use crate::models::{Comment, CommentMode, NewCommentRow, NewThreadRow, Thread};
+
+#[derive(Clone, Default)]
+struct Data {
+    preferences: HashMap<String, String>,
+    threads: BTreeMap<i32, Thread>,
+    comments: BTreeMap<i32, Comment>,
+}
+
+/// An in-memory store. Operations are serialized: a store is given to one caller at a time.
+#[derive(Default)]
+pub struct MemoryStore {
+    data: Mutex<Data>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStore {
+    fn with_store(
+        &self,
+        f: &mut dyn FnMut(&dyn CommentStore) -> Result<(), failure::Error>,
+    ) -> Result<(), failure::Error> {
+        let mut data = self
+            .data
+            .lock()
+            .map_err(|_| failure::err_msg("Memory store lock is poisoned"))?;
+
+        f(&MemorySession {
+            data: RefCell::new(&mut *data),
+        })
+    }
+}
+
+/// Exclusive access to the data of a `MemoryStore`.
+struct MemorySession<'a> {
+    data: RefCell<&'a mut Data>,
+}
+
+fn not_found(what: &str, id: i32) -> failure::Error {
+    failure::err_msg(format!("{} {} not found", what, id))
+}
+
+fn next_id<T>(map: &BTreeMap<i32, T>) -> i32 {
+    map.keys().next_back().map_or(1, |id| id + 1)
+}
+
+impl<'a> MemorySession<'a> {
+    fn uri_comments(&self, uri: &str, mode: Option<i32>, after: f64) -> Vec<Comment> {
+        let data = self.data.borrow();
+
+        let thread_id = match data.threads.values().find(|t| t.uri == uri) {
+            Some(thread) => thread.id,
+            None => return Vec::new(),
+        };
+
+        data.comments
+            .values()
+            .filter(|c| c.thread_id == thread_id && CommentMode::in_mask(mode, c.mode) && c.created.to_f64() > after)
+            .cloned()
+            .collect()
+    }
+
+    fn update<F: FnOnce(&mut Comment) -> bool>(&self, id: i32, f: F) -> bool {
+        self.data.borrow_mut().comments.get_mut(&id).map_or(false, f)
+    }
+}
+
+impl<'a> CommentStore for MemorySession<'a> {
+    fn preference(&self, key: &str) -> Result<Option<String>, failure::Error> {
+        Ok(self.data.borrow().preferences.get(key).cloned())
+    }
+
+    fn preferences(&self) -> Result<Vec<(String, String)>, failure::Error> {
+        let mut preferences = self
+            .data
+            .borrow()
+            .preferences
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        preferences.sort();
+        Ok(preferences)
+    }
+
+    fn insert_preference(&self, key: &str, value: &str) -> Result<(), failure::Error> {
+        let mut data = self.data.borrow_mut();
+        if data.preferences.contains_key(key) {
+            return Err(failure::err_msg(format!("Preference {} already exists", key)));
+        }
+
+        data.preferences.insert(key.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    fn update_preference(&self, key: &str, value: &str) -> Result<(), failure::Error> {
+        if let Some(existing) = self.data.borrow_mut().preferences.get_mut(key) {
+            *existing = value.to_owned();
+        }
+        Ok(())
+    }
+
+    fn thread(&self, id: i32) -> Result<Option<Thread>, failure::Error> {
+        Ok(self.data.borrow().threads.get(&id).cloned())
+    }
+
+    fn thread_by_uri(&self, uri: &str) -> Result<Option<Thread>, failure::Error> {
+        Ok(self.data.borrow().threads.values().find(|t| t.uri == uri).cloned())
+    }
+
+    fn threads(&self) -> Result<Vec<Thread>, failure::Error> {
+        let mut threads = self.data.borrow().threads.values().cloned().collect::<Vec<_>>();
+        threads.sort_by(|a, b| a.uri.cmp(&b.uri));
+        Ok(threads)
+    }
+
+    fn insert_thread(&self, row: &NewThreadRow) -> Result<Thread, failure::Error> {
+        if self.thread_by_uri(row.uri)?.is_some() {
+            return Err(failure::err_msg(format!("Thread {} already exists", row.uri)));
+        }
+
+        let mut data = self.data.borrow_mut();
+        let thread = Thread {
+            id: next_id(&data.threads),
+            uri: row.uri.to_owned(),
+            title: row.title.to_owned(),
+        };
+
+        data.threads.insert(thread.id, thread.clone());
+        Ok(thread)
+    }
+
+    fn update_thread(&self, id: i32, uri: &str, title: &str) -> Result<Thread, failure::Error> {
+        if self.thread_by_uri(uri)?.map_or(false, |other| other.id != id) {
+            return Err(failure::err_msg(format!("Thread {} already exists", uri)));
+        }
+
+        let mut data = self.data.borrow_mut();
+        let thread = data.threads.get_mut(&id).ok_or_else(|| not_found("Thread", id))?;
+
+        thread.uri = uri.to_owned();
+        thread.title = title.to_owned();
+        Ok(thread.clone())
+    }
+
+    fn comment(&self, id: i32) -> Result<Option<Comment>, failure::Error> {
+        Ok(self.data.borrow().comments.get(&id).cloned())
+    }
+
+    fn insert_comment(&self, row: &NewCommentRow) -> Result<Comment, failure::Error> {
+        let mut data = self.data.borrow_mut();
+
+        if !data.threads.contains_key(&row.thread_id) {
+            return Err(not_found("Thread", row.thread_id));
+        }
+
+        let comment = Comment {
+            thread_id: row.thread_id,
+            id: next_id(&data.comments),
+            parent: row.parent,
+            created: FloatDateTime::from_f64(row.created).ok_or_else(|| failure::err_msg("Invalid date"))?,
+            modified: None,
+            mode: row.mode,
+            remote_addr: row.remote_addr.to_owned(),
+            text: row.text.to_owned(),
+            author: row.author.map(String::from),
+            email: row.email.map(String::from),
+            website: row.website.map(String::from),
+            likes: 0,
+            dislikes: 0,
+            notification: row.notification,
+            voters: row.voters.to_vec(),
+            hash: None,
+            html: None,
+            html_fingerprint: None,
+        };
+
+        data.comments.insert(comment.id, comment.clone());
+        Ok(comment)
+    }
+
+    fn update_comment(
+        &self,
+        id: i32,
+        text: &str,
+        author: Option<&str>,
+        website: Option<&str>,
+    ) -> Result<Option<Comment>, failure::Error> {
+        self.update(id, |comment| {
+            comment.text = text.to_owned();
+            comment.author = author.map(String::from);
+            comment.website = website.map(String::from);
+            comment.modified = Some(FloatDateTime(Utc::now()));
+            comment.html = None;
+            comment.html_fingerprint = None;
+            true
+        });
+
+        self.comment(id)
+    }
+
+    fn set_rendering(&self, id: i32, hash: &str, html: &str, fingerprint: &str) -> Result<(), failure::Error> {
+        self.update(id, |comment| {
+            comment.hash = Some(hash.to_owned());
+            comment.html = Some(html.to_owned());
+            comment.html_fingerprint = Some(fingerprint.to_owned());
+            true
+        });
+
+        Ok(())
+    }
+
+    fn vote(&self, id: i32, upvote: bool, old_voters: &[u8], voters: &[u8]) -> Result<Option<Comment>, failure::Error> {
+        let updated = self.update(id, |comment| {
+            if comment.voters != old_voters {
+                return false;
+            }
+            if upvote {
+                comment.likes += 1;
+            } else {
+                comment.dislikes += 1;
+            }
+            comment.voters = voters.to_vec();
+            true
+        });
+
+        if updated {
+            self.comment(id)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn comments_by_addr(
+        &self,
+        remote_addr: &str,
+        after: f64,
+        thread_id: Option<i32>,
+    ) -> Result<Vec<Comment>, failure::Error> {
+        let mut comments = self
+            .data
+            .borrow()
+            .comments
+            .values()
+            .filter(|c| c.remote_addr == remote_addr && c.created.to_f64() > after)
+            .filter(|c| thread_id.map_or(true, |id| c.thread_id == id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        comments.sort_by_key(|c| c.created);
+        Ok(comments)
+    }
+
+    fn is_email_approved(&self, email: &str) -> Result<bool, failure::Error> {
+        Ok(self
+            .data
+            .borrow()
+            .comments
+            .values()
+            .any(|c| c.email.as_ref().map(String::as_str) == Some(email) && c.mode == CommentMode::Valid as i32))
+    }
+
+    fn reply_subscribers(&self, parent: i32) -> Result<Vec<Comment>, failure::Error> {
+        Ok(self
+            .data
+            .borrow()
+            .comments
+            .values()
+            .filter(|c| c.id == parent || (c.parent == Some(parent) && c.mode == CommentMode::Valid as i32))
+            .filter(|c| c.notification && c.email.is_some())
+            .cloned()
+            .collect())
+    }
+
+    fn unsubscribe(&self, thread_id: i32, email: &str) -> Result<usize, failure::Error> {
+        let mut data = self.data.borrow_mut();
+        let mut count = 0;
+
+        for comment in data.comments.values_mut() {
+            if comment.thread_id == thread_id && comment.email.as_ref().map(String::as_str) == Some(email) {
+                comment.notification = false;
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn activate(&self, id: i32) -> Result<bool, failure::Error> {
+        Ok(self.update(id, |comment| {
+            let pending = comment.mode == CommentMode::Pending as i32;
+            if pending {
+                comment.mode = CommentMode::Valid as i32;
+            }
+            pending
+        }))
+    }
+
+    fn deactivate(&self, id: i32) -> Result<bool, failure::Error> {
+        Ok(self.update(id, |comment| {
+            let valid = comment.mode == CommentMode::Valid as i32;
+            if valid {
+                comment.mode = CommentMode::Pending as i32;
+            }
+            valid
+        }))
+    }
+
+    fn delete_comment(&self, id: i32) -> Result<Option<Comment>, failure::Error> {
+        let mut data = self.data.borrow_mut();
+
+        let has_replies = data.comments.values().any(|c| c.parent == Some(id));
+
+        let result = if has_replies {
+            data.comments.get_mut(&id).map(|comment| {
+                comment.text = String::new();
+                comment.author = None;
+                comment.website = None;
+                comment.mode = CommentMode::SoftDeleted as i32;
+                comment.html = None;
+                comment.html_fingerprint = None;
+                comment.clone()
+            })
+        } else {
+            data.comments.remove(&id);
+            None
+        };
+
+        // Remove soft-deleted comments that have no replies anymore
+        loop {
+            let stale = data
+                .comments
+                .values()
+                .filter(|c| c.mode == CommentMode::SoftDeleted as i32)
+                .filter(|c| !data.comments.values().any(|reply| reply.parent == Some(c.id)))
+                .map(|c| c.id)
+                .collect::<Vec<_>>();
+
+            if stale.is_empty() {
+                break;
+            }
+
+            for id in stale {
+                data.comments.remove(&id);
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn fetch(
+        &self,
+        uri: &str,
+        mode: Option<i32>,
+        after: f64,
+        parent: Option<i32>,
+        order_by: Option<&str>,
+        asc: bool,
+        limit: Option<i64>,
+    ) -> Result<Vec<Comment>, failure::Error> {
+        let mut comments = self
+            .uri_comments(uri, mode, after)
+            .into_iter()
+            .filter(|c| match parent {
+                None => c.parent.is_none(),
+                Some(0) => true, // 'any' in the python version
+                Some(id) => c.parent == Some(id),
+            })
+            .collect::<Vec<_>>();
+
+        match order_by.unwrap_or("id") {
+            "created" => comments.sort_by_key(|c| c.created),
+            "modified" => comments.sort_by_key(|c| c.modified),
+            "likes" => comments.sort_by_key(|c| c.likes),
+            "dislikes" => comments.sort_by_key(|c| c.dislikes),
+            _ => comments.sort_by_key(|c| c.id),
+        }
+
+        if !asc {
+            comments.reverse();
+        }
+
+        if let Some(limit) = limit {
+            comments.truncate(limit.max(0) as usize);
+        }
+
+        Ok(comments)
+    }
+
+    fn reply_count(&self, uri: &str, mode: Option<i32>, after: f64) -> Result<Vec<(Option<i32>, i64)>, failure::Error> {
+        let mut counts = BTreeMap::new();
+        for comment in self.uri_comments(uri, mode, after) {
+            *counts.entry(comment.parent).or_insert(0) += 1;
+        }
+
+        Ok(counts.into_iter().collect())
+    }
+
+    fn count(&self, uris: &[String]) -> Result<Vec<(String, i64)>, failure::Error> {
+        let valid = Some(CommentMode::Valid as i32);
+
+        Ok(uris
+            .iter()
+            .map(|uri| {
+                (
+                    uri.clone(),
+                    self.uri_comments(uri, valid, std::f64::NEG_INFINITY).len() as i64,
+                )
+            })
+            .filter(|&(_, count)| count > 0)
+            .collect())
+    }
+
+    fn list(
+        &self,
+        mode: Option<i32>,
+        uri: Option<&str>,
+        search: Option<&str>,
+        after: Option<f64>,
+        before: Option<f64>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<(Comment, Thread)>, i64), failure::Error> {
+        let data = self.data.borrow();
+
+        // Like SQL's LIKE on SQLite, case-insensitive
+        let search = search.map(str::to_lowercase);
+        let contains =
+            |field: &Option<String>, search: &str| field.as_ref().map_or(false, |f| f.to_lowercase().contains(search));
+
+        let comments = data
+            .comments
+            .values()
+            .rev()
+            .filter_map(|c| data.threads.get(&c.thread_id).map(|t| (c, t)))
+            .filter(|(c, _)| mode.is_none() || CommentMode::in_mask(mode, c.mode))
+            .filter(|(_, t)| uri.map_or(true, |uri| t.uri == uri))
+            .filter(|(c, _)| {
+                search.as_ref().map_or(true, |search| {
+                    c.text.to_lowercase().contains(search.as_str())
+                        || contains(&c.author, search)
+                        || contains(&c.email, search)
+                })
+            })
+            .filter(|(c, _)| after.map_or(true, |after| c.created.to_f64() >= after))
+            .filter(|(c, _)| before.map_or(true, |before| c.created.to_f64() < before))
+            .collect::<Vec<_>>();
+
+        let total = comments.len() as i64;
+
+        let page = comments
+            .into_iter()
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .map(|(c, t)| (c.clone(), t.clone()))
+            .collect();
+
+        Ok((page, total))
+    }
+
+    fn comments_before(&self, before_id: Option<i32>, limit: i64) -> Result<Vec<Comment>, failure::Error> {
+        let data = self.data.borrow();
+        let upper = before_id.map_or(Bound::Unbounded, Bound::Excluded);
+
+        Ok(data
+            .comments
+            .range((Bound::Unbounded, upper))
+            .rev()
+            .take(limit.max(0) as usize)
+            .map(|(_, c)| c.clone())
+            .collect())
+    }
+
+    fn restore_thread(&self, thread: &Thread) -> Result<(), failure::Error> {
+        if self.thread_by_uri(&thread.uri)?.is_some() {
+            return Err(failure::err_msg(format!("Thread {} already exists", thread.uri)));
+        }
+
+        let mut data = self.data.borrow_mut();
+        if data.threads.contains_key(&thread.id) {
+            return Err(failure::err_msg(format!("Thread id {} already exists", thread.id)));
+        }
+
+        data.threads.insert(thread.id, thread.clone());
+        Ok(())
+    }
+
+    fn restore_comment(&self, comment: &Comment) -> Result<(), failure::Error> {
+        let mut data = self.data.borrow_mut();
+
+        if !data.threads.contains_key(&comment.thread_id) {
+            return Err(not_found("Thread", comment.thread_id));
+        }
+        if data.comments.contains_key(&comment.id) {
+            return Err(failure::err_msg(format!("Comment id {} already exists", comment.id)));
+        }
+
+        let mut comment = comment.clone();
+        comment.hash = None;
+        comment.html = None;
+        comment.html_fingerprint = None;
+
+        data.comments.insert(comment.id, comment);
+        Ok(())
+    }
+
+    fn max_ids(&self) -> Result<(Option<i32>, Option<i32>), failure::Error> {
+        let data = self.data.borrow();
+        Ok((
+            data.threads.keys().next_back().cloned(),
+            data.comments.keys().next_back().cloned(),
+        ))
+    }
+
+    fn transaction(&self, f: &mut dyn FnMut() -> Result<(), failure::Error>) -> Result<(), failure::Error> {
+        let snapshot = (**self.data.borrow()).clone();
+
+        let result = f();
+        if result.is_err() {
+            **self.data.borrow_mut() = snapshot;
+        }
+
+        result
+    }
+}
+
+/// Fixtures for tests that run on an in-memory store.
+#[cfg(test)]
+pub mod fixtures {
+    use super::MemoryStore;
+    use crate::config::RissoConfig;
+    use crate::context::{ApiBuilder, ApiContext};
+    use crate::models::NewCommentRow;
+    use std::sync::Arc;
+
+    /// The builder must be kept alive as long as the context is used, as it owns the thread pool.
+    pub fn memory_api(config: RissoConfig) -> (ApiBuilder, ApiContext) {
+        let builder = ApiBuilder::with_storage(config, Arc::new(MemoryStore::new())).unwrap();
+        let ctx = builder.build();
+        (builder, ctx)
+    }
+
+    /// A valid, anonymous top-level comment. Tests override the fields they care about.
+    pub fn comment_row(thread_id: i32, text: &str) -> NewCommentRow {
+        NewCommentRow {
+            thread_id,
+            parent: None,
+            created: 1_500_000_000.0,
+            mode: 1,
+            remote_addr: "127.0.0.1",
+            text,
+            author: None,
+            email: None,
+            website: None,
+            notification: false,
+            voters: &[0],
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! Storage of threads and comments.
+//!
+//! API functions don't talk to the database directly, but to a `CommentStore` obtained from the
+//! context's `Storage`. Two implementations are provided:
+//! - `DieselStore`, a connection pool to the database selected by the cargo features,
+//! - `MemoryStore`, that keeps everything in memory and is mostly useful for tests.
+//!
+//! Embedders can provide their own implementation with `ApiBuilder::with_storage`.
+
+use crate::models::{Comment, NewCommentRow, NewThreadRow, Thread};
+
+mod db;
+mod memory;
+
+pub use self::db::DieselStore;
+pub use self::memory::MemoryStore;
+
+#[cfg(test)]
+pub use self::memory::fixtures;
+
+/// Data access operations. Their semantics are those of the diesel implementation in `models`, that
+/// other implementations must follow.
+pub trait CommentStore {
+    /// Return the value of a preference, if it exists.
+    fn preference(&self, key: &str) -> Result<Option<String>, failure::Error>;
+
+    /// Return all preferences, sorted by key.
+    fn preferences(&self) -> Result<Vec<(String, String)>, failure::Error>;
+
+    /// Store a new preference.
+    fn insert_preference(&self, key: &str, value: &str) -> Result<(), failure::Error>;
+
+    /// Change the value of an existing preference.
+    fn update_preference(&self, key: &str, value: &str) -> Result<(), failure::Error>;
+
+    /// Return the thread with a given id, if any.
+    fn thread(&self, id: i32) -> Result<Option<Thread>, failure::Error>;
+
+    /// Return the thread for `uri`, if any.
+    fn thread_by_uri(&self, uri: &str) -> Result<Option<Thread>, failure::Error>;
+
+    /// Return all threads, sorted by uri.
+    fn threads(&self) -> Result<Vec<Thread>, failure::Error>;
+
+    /// Create a new thread and return it.
+    fn insert_thread(&self, row: &NewThreadRow) -> Result<Thread, failure::Error>;
+
+    /// Change the uri and title of a thread and return it.
+    fn update_thread(&self, id: i32, uri: &str, title: &str) -> Result<Thread, failure::Error>;
+
+    /// Return the comment with a given id, if any.
+    fn comment(&self, id: i32) -> Result<Option<Comment>, failure::Error>;
+
+    /// Create a new comment and return it.
+    fn insert_comment(&self, row: &NewCommentRow) -> Result<Comment, failure::Error>;
+
+    /// Update the text, author and website of a comment and return it. Its html rendering is
+    /// cleared.
+    fn update_comment(
+        &self,
+        id: i32,
+        text: &str,
+        author: Option<&str>,
+        website: Option<&str>,
+    ) -> Result<Option<Comment>, failure::Error>;
+
+    /// Store the author hash and html rendering of a comment.
+    fn set_rendering(&self, id: i32, hash: &str, html: &str, fingerprint: &str) -> Result<(), failure::Error>;
+
+    /// Record a vote on a comment along with its updated voters bloom filter, if its voters are
+    /// still `old_voters`. Returns the updated comment, or `None` if it doesn't exist or another
+    /// vote changed its voters in between.
+    fn vote(&self, id: i32, upvote: bool, old_voters: &[u8], voters: &[u8]) -> Result<Option<Comment>, failure::Error>;
+
+    /// Comments posted from `remote_addr` after `after`, only on a thread if `thread_id` is given,
+    /// oldest first.
+    fn comments_by_addr(
+        &self,
+        remote_addr: &str,
+        after: f64,
+        thread_id: Option<i32>,
+    ) -> Result<Vec<Comment>, failure::Error>;
+
+    /// Has a comment posted with `email` already been approved?
+    fn is_email_approved(&self, email: &str) -> Result<bool, failure::Error>;
+
+    /// Comments whose authors asked to be notified of replies to `parent`.
+    fn reply_subscribers(&self, parent: i32) -> Result<Vec<Comment>, failure::Error>;
+
+    /// Disable reply notifications for comments posted with `email` on a thread.
+    fn unsubscribe(&self, thread_id: i32, email: &str) -> Result<usize, failure::Error>;
+
+    /// Activate a pending comment. Returns `false` if the comment doesn't exist or wasn't pending.
+    fn activate(&self, id: i32) -> Result<bool, failure::Error>;
+
+    /// Put a valid comment back in the moderation queue.
+    fn deactivate(&self, id: i32) -> Result<bool, failure::Error>;
+
+    /// Delete a comment, or soft-delete it if it has replies.
+    fn delete_comment(&self, id: i32) -> Result<Option<Comment>, failure::Error>;
+
+    /// Return comments for `uri` with `mode`.
+    #[allow(clippy::too_many_arguments)]
+    fn fetch(
+        &self,
+        uri: &str,
+        mode: Option<i32>,
+        after: f64,
+        parent: Option<i32>,
+        order_by: Option<&str>,
+        asc: bool,
+        limit: Option<i64>,
+    ) -> Result<Vec<Comment>, failure::Error>;
+
+    /// Return comment count for main thread and all reply threads for one url.
+    fn reply_count(&self, uri: &str, mode: Option<i32>, after: f64) -> Result<Vec<(Option<i32>, i64)>, failure::Error>;
+
+    /// Return the number of valid comments for each of `uris`.
+    fn count(&self, uris: &[String]) -> Result<Vec<(String, i64)>, failure::Error>;
+
+    /// Return comments across all threads along with their thread, and the total number of
+    /// matching comments.
+    #[allow(clippy::too_many_arguments)]
+    fn list(
+        &self,
+        mode: Option<i32>,
+        uri: Option<&str>,
+        search: Option<&str>,
+        after: Option<f64>,
+        before: Option<f64>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<(Comment, Thread)>, i64), failure::Error>;
+
+    /// Up to `limit` comments with an id lower than `before_id`, or the latest ones if it's `None`,
+    /// by descending id. Unlike `list`, pages don't shift when comments are added meanwhile.
+    fn comments_before(&self, before_id: Option<i32>, limit: i64) -> Result<Vec<Comment>, failure::Error>;
+
+    /// Insert a thread as is, including its id. Used by imports.
+    fn restore_thread(&self, thread: &Thread) -> Result<(), failure::Error>;
+
+    /// Insert a comment as is, including its id, dates and votes. Used by imports. The rendering
+    /// cache is left empty.
+    fn restore_comment(&self, comment: &Comment) -> Result<(), failure::Error>;
+
+    /// Highest thread and comment ids, to allocate ids when importing.
+    fn max_ids(&self) -> Result<(Option<i32>, Option<i32>), failure::Error>;
+
+    /// Run `f` in a transaction: changes are discarded if it fails. Use `atomically` rather than
+    /// calling this method directly.
+    fn transaction(&self, f: &mut dyn FnMut() -> Result<(), failure::Error>) -> Result<(), failure::Error>;
+}
+
+impl<'a> dyn CommentStore + 'a {
+    /// Run `f` in a transaction and return its result.
+    pub fn atomically<T, F>(&self, f: F) -> Result<T, failure::Error>
+    where
+        F: FnOnce() -> Result<T, failure::Error>,
+    {
+        // `transaction` takes an `FnMut` to be object-safe, but calls it only once
+        let mut f = Some(f);
+        let mut result = None;
+
+        self.transaction(&mut || {
+            let f = f.take().expect("transaction called twice");
+            result = Some(f()?);
+            Ok(())
+        })?;
+
+        Ok(result.expect("transaction not called"))
+    }
+}
+
+/// A source of `CommentStore`s, shared by all threads of the API's thread pool.
+pub trait Storage: Send + Sync {
+    /// Call `f` with a store, e.g. a connection taken from a pool.
+    fn with_store(
+        &self,
+        f: &mut dyn FnMut(&dyn CommentStore) -> Result<(), failure::Error>,
+    ) -> Result<(), failure::Error>;
+}
+
+impl dyn Storage {
+    /// Call `f` with a store and return its result.
+    pub fn run<T, F>(&self, f: F) -> Result<T, failure::Error>
+    where
+        F: FnOnce(&dyn CommentStore) -> Result<T, failure::Error>,
+    {
+        let mut f = Some(f);
+        let mut result = None;
+
+        self.with_store(&mut |store| {
+            let f = f.take().expect("store called twice");
+            result = Some(f(store)?);
+            Ok(())
+        })?;
+
+        Ok(result.expect("store not called"))
+    }
+}
@@ -0,0 +1,117 @@
+//! Signed tokens, used to authorize actions on comments without user accounts: authors get a
+//! token when they post a comment that allows them to edit or delete it for a limited time, and
+//! moderation links sent to the admin contain a token that allows activating or deleting a comment.
+//! Sessions of the admin interface are also tokens.
+//!
+//! A token is `<timestamp>.<signature>`, where the signature is a HMAC-SHA256 of the scope, subject
+//! and timestamp using the server's session key.
+
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Scope of tokens given to comment authors. The subject is the comment id.
+pub const AUTHOR: &str = "author";
+
+/// Scope of tokens in moderation links. The subject is the comment id.
+pub const MODERATION: &str = "moderation";
+
+/// Scope of tokens in unsubscribe links. The subject is the thread id and the subscriber's email,
+/// so that a link only applies to the thread it was sent for.
+pub const UNSUBSCRIBE: &str = "unsubscribe";
+
+/// Scope of admin session tokens. The subject is always `admin`, as there is a single admin account.
+pub const ADMIN: &str = "admin";
+
+/// Create a token for `subject` in a given `scope`.
+pub fn sign(key: &[u8], scope: &str, subject: &str) -> String {
+    sign_at(key, scope, subject, Utc::now().timestamp())
+}
+
+/// Verify a token for `subject` in a given `scope`. If `max_age` is set, tokens that were created
+/// more than `max_age` seconds ago are rejected.
+pub fn verify(key: &[u8], scope: &str, subject: &str, token: &str, max_age: Option<i64>) -> bool {
+    let mut parts = token.splitn(2, '.');
+
+    let (timestamp, signature) = match (parts.next().map(str::parse::<i64>), parts.next().map(hex::decode)) {
+        (Some(Ok(timestamp)), Some(Ok(signature))) => (timestamp, signature),
+        _ => return false,
+    };
+
+    if let Some(max_age) = max_age {
+        if Utc::now().timestamp() - timestamp > max_age {
+            return false;
+        }
+    }
+
+    mac(key, scope, subject, timestamp).verify(&signature).is_ok()
+}
+
+/// Compare a secret given by a client, such as a password, with the `expected` one. Their HMACs
+/// are compared in constant time, so that response times tell nothing about the secret, not even
+/// its length.
+pub fn secret_matches(key: &[u8], expected: &str, given: &str) -> bool {
+    let code = |secret: &str| {
+        let mut mac = HmacSha256::new_varkey(key).unwrap();
+        mac.input(secret.as_bytes());
+        mac
+    };
+
+    code(expected).verify(&code(given).result().code()).is_ok()
+}
+
+fn sign_at(key: &[u8], scope: &str, subject: &str, timestamp: i64) -> String {
+    let signature = mac(key, scope, subject, timestamp).result().code();
+    format!("{}.{}", timestamp, hex::encode(signature))
+}
+
+fn mac(key: &[u8], scope: &str, subject: &str, timestamp: i64) -> HmacSha256 {
+    // HMAC accepts keys of any size
+    let mut mac = HmacSha256::new_varkey(key).unwrap();
+
+    // Separate fields with a NUL byte so that they can't be shifted into each other
+    mac.input(scope.as_bytes());
+    mac.input(b"\0");
+    mac.input(subject.as_bytes());
+    mac.input(b"\0");
+    mac.input(timestamp.to_string().as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"secret";
+
+    #[test]
+    fn sign_and_verify() {
+        let token = sign(KEY, AUTHOR, "42");
+
+        assert!(verify(KEY, AUTHOR, "42", &token, Some(60)));
+        assert!(verify(KEY, AUTHOR, "42", &token, None));
+
+        assert!(!verify(b"other secret", AUTHOR, "42", &token, None));
+        assert!(!verify(KEY, MODERATION, "42", &token, None));
+        assert!(!verify(KEY, AUTHOR, "43", &token, None));
+        assert!(!verify(KEY, AUTHOR, "42", "garbage", None));
+    }
+
+    #[test]
+    fn secrets() {
+        assert!(secret_matches(KEY, "password", "password"));
+        assert!(!secret_matches(KEY, "password", "passwore"));
+        assert!(!secret_matches(KEY, "password", "pass"));
+        assert!(!secret_matches(KEY, "password", ""));
+    }
+
+    #[test]
+    fn expired_token() {
+        let token = sign_at(KEY, AUTHOR, "42", Utc::now().timestamp() - 120);
+
+        assert!(!verify(KEY, AUTHOR, "42", &token, Some(60)));
+        assert!(verify(KEY, AUTHOR, "42", &token, Some(180)));
+    }
+}